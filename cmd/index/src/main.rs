@@ -1,27 +1,44 @@
-use std::{env, net::IpAddr};
+use std::{env, net::IpAddr, path::PathBuf, time::Duration};
 
 use core::index_node::index_node::IndexNode;
 
 use ronion_index::key::{gen_keys, read_keypair};
 
+static STATE_ENV: &str = "RO_STATE";
+static STATE_DEFAULT: &str = "index.state.rsf";
+static NO_CELLS_ENV: &str = "RO_NO_CELLS";
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.iter().any(|x| x == "gen-keys") {
         gen_keys().expect("unable to generate keys");
         return;
     }
-    
 
     let keypair = read_keypair();
-    let (ip, port) = parse_arguments(args);
+    let (ip, port, relay_ttl) = parse_arguments(args);
     let node = IndexNode::new(ip, port, keypair);
+    if let Some(relay_ttl) = relay_ttl {
+        node.set_relay_ttl(relay_ttl);
+    }
+    if env::var_os(NO_CELLS_ENV).is_some() {
+        node.set_cells(false);
+    }
+    let state_file =
+        env::var(STATE_ENV).map_or_else(|_| PathBuf::from(STATE_DEFAULT), PathBuf::from);
+    node.set_state_file(state_file)
+        .expect("unable to read state file");
 
     node.start();
 }
 
-fn parse_arguments(args: Vec<String>) -> (IpAddr, u16) {
+// The optional third argument is how many seconds a relay stays listed after its last ping
+fn parse_arguments(args: Vec<String>) -> (IpAddr, u16, Option<Duration>) {
     let addr: IpAddr = args[1].parse().unwrap();
     let port: u16 = args[2].parse().unwrap();
+    let relay_ttl = args
+        .get(3)
+        .map(|secs| Duration::from_secs(secs.parse().unwrap()));
 
-    (addr, port)
+    (addr, port, relay_ttl)
 }
@@ -1,25 +1,44 @@
 use shadowsocks::relay::socks5::{
-    self, Command, HandshakeResponse, TcpRequestHeader, TcpResponseHeader, SOCKS5_AUTH_METHOD_NONE,
+    self, Command, HandshakeResponse, Reply, TcpRequestHeader, TcpResponseHeader,
+    SOCKS5_AUTH_METHOD_NONE,
 };
 use shadowsocks::relay::Address;
 use shadowsocks::{
     self, context::Context, relay::tcprelay::proxy_listener::ProxyListener, ServerConfig,
 };
-use std::io;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{mpsc, oneshot};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
 };
 
-use core::consumer_node::consumer::Consumer;
+use core::consumer_node::consumer::{Consumer, StreamEvent};
+use core::protocol::onion::{RefusalReason, Target};
 
 use ronion_index::key;
 
+/// The commands waiting for the consumer before the connections wait for it.
+const COMMAND_QUEUE_LEN: usize = 64;
+
+/// What the connections of the proxy ask of the task that owns the consumer.
+enum StreamCommand {
+    /// Opens a stream to the target, answering with its ID and handing its events to the sender.
+    Begin(
+        Target,
+        mpsc::UnboundedSender<StreamEvent>,
+        oneshot::Sender<io::Result<u32>>,
+    ),
+    Data(u32, Vec<u8>),
+    End(u32),
+}
+
 pub struct Proxy {
-    consumer: Arc<Mutex<Consumer>>,
+    commands: mpsc::Sender<StreamCommand>,
 }
 
 impl Proxy {
@@ -27,8 +46,12 @@ impl Proxy {
         let index_key = key::read_public();
         println!("Index key read");
 
-        let consumer = Arc::new(Mutex::new(Consumer::new(index_addr, index_key).await));
-        Proxy { consumer }
+        let consumer = Consumer::new(index_addr, index_key)
+            .await
+            .expect("unable to build a circuit");
+        let (commands, queue) = mpsc::channel(COMMAND_QUEUE_LEN);
+        tokio::spawn(Proxy::run_consumer(consumer, queue));
+        Proxy { commands }
     }
 
     pub async fn serve_consumers(&mut self, context: Arc<Context>, svr_cfg: &ServerConfig) -> () {
@@ -38,22 +61,152 @@ impl Proxy {
             let (stream, target_addr) = listener.accept().await.unwrap();
             println!("-------------NEW CONNECTION");
             let inner = stream.into_inner();
-            self.handle_connection(inner, target_addr).await;
+            let commands = self.commands.clone();
+            tokio::spawn(async move {
+                if let Err(err) = Proxy::handle_connection(commands, inner, target_addr).await {
+                    println!("connection from {} failed: {}", target_addr, err);
+                }
+            });
         }
     }
 
-    async fn handle_connection(&mut self, mut stream: TcpStream, target_addr: SocketAddr) -> () {
-        let consumer_stream = Proxy::handshake(stream, target_addr).await.unwrap();
-        let (mut reader, mut writer) = consumer_stream.into_split();
-        self.send_consumer(&mut reader, target_addr); //Seperate sending task
-        self.recv_consumer(&mut writer); //Seperate recieving task
+    // Owns the consumer, running the commands of the connections and handing every event of
+    // a stream to the connection of that stream. Ends once the circuit fails, which ends the
+    // events of every stream.
+    // param consumer: The consumer whose circuit carries the streams
+    // param commands: The commands of the connections
+    async fn run_consumer(mut consumer: Consumer, mut commands: mpsc::Receiver<StreamCommand>) {
+        let mut streams: HashMap<u32, mpsc::UnboundedSender<StreamEvent>> = HashMap::new();
+        loop {
+            tokio::select! {
+                command = commands.recv() => {
+                    let command = match command {
+                        Some(command) => command,
+                        None => return,
+                    };
+                    if let Err(err) = Proxy::run_command(&mut consumer, &mut streams, command).await {
+                        println!("failed to send on the circuit: {}", err);
+                        return;
+                    }
+                }
+                // Only waiting for the circuit is raced, so no onion is read halfway.
+                readable = consumer.readable() => {
+                    let event = match readable {
+                        Ok(()) => consumer.recv_message().await,
+                        Err(err) => Err(err.into()),
+                    };
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(err) => {
+                            println!("failed to receive message: {}", err);
+                            return;
+                        }
+                    };
+                    let stream_id = event.stream_id();
+                    let ended = matches!(event, StreamEvent::End(_) | StreamEvent::Refused(..));
+                    if let Some(events) = streams.get(&stream_id) {
+                        // The connection may be gone already, its stream ends with it.
+                        let _ = events.send(event);
+                    }
+                    if ended {
+                        streams.remove(&stream_id);
+                    }
+                }
+            }
+        }
     }
 
-    async fn handshake(mut stream: TcpStream, target_addr: SocketAddr) -> io::Result<TcpStream> {
-        println!("-------------NEW STREAM");
-        let handshake_req = socks5::HandshakeRequest::read_from(&mut stream)
+    // Runs a command of a connection on the consumer. Failing to send on a stream that
+    // ended meanwhile is no error, anything else means the circuit failed.
+    // param consumer: The consumer whose circuit carries the streams
+    // param streams: Where the events of every open stream go
+    // param command: The command to run
+    async fn run_command(
+        consumer: &mut Consumer,
+        streams: &mut HashMap<u32, mpsc::UnboundedSender<StreamEvent>>,
+        command: StreamCommand,
+    ) -> io::Result<()> {
+        let sent = match command {
+            StreamCommand::Begin(target, events, opened) => {
+                let begun = consumer.begin_stream(target).await;
+                let result = match &begun {
+                    Ok(stream_id) => {
+                        streams.insert(*stream_id, events);
+                        Ok(())
+                    }
+                    Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+                };
+                let _ = opened.send(begun);
+                result
+            }
+            StreamCommand::Data(stream_id, data) => consumer.send_data(stream_id, data).await,
+            StreamCommand::End(stream_id) => {
+                streams.remove(&stream_id);
+                consumer.end_stream(stream_id).await
+            }
+        };
+
+        match sent {
+            Err(err) if err.kind() == ErrorKind::NotConnected => Ok(()),
+            sent => sent,
+        }
+    }
+
+    // Opens a stream for a SOCKS5 client and pipes its connection through the circuit. The
+    // client only gets its reply once the exit relay opened the stream or refused it.
+    // param commands: Reaches the task that owns the consumer
+    // param stream: The connection of the client
+    // param target_addr: The address of the client
+    async fn handle_connection(
+        commands: mpsc::Sender<StreamCommand>,
+        mut stream: TcpStream,
+        target_addr: SocketAddr,
+    ) -> io::Result<()> {
+        let target = Proxy::handshake(&mut stream, target_addr).await?;
+        let consumer_gone = || io::Error::new(ErrorKind::NotConnected, "the circuit is closed");
+
+        let (events_sender, mut events) = mpsc::unbounded_channel();
+        let (opened_sender, opened) = oneshot::channel();
+        commands
+            .send(StreamCommand::Begin(target, events_sender, opened_sender))
             .await
-            .unwrap();
+            .map_err(|_| consumer_gone())?;
+        let stream_id = opened.await.map_err(|_| consumer_gone())??;
+
+        let reply = match events.recv().await {
+            Some(StreamEvent::Opened(_)) => Reply::Succeeded,
+            Some(StreamEvent::Refused(_, reason)) => {
+                println!("exit relay refused the stream: {:?}", reason);
+                match reason {
+                    RefusalReason::ExitPolicy => Reply::ConnectionNotAllowed,
+                    RefusalReason::ResolveFailed => Reply::HostUnreachable,
+                    RefusalReason::ConnectFailed => Reply::ConnectionRefused,
+                }
+            }
+            _ => Reply::GeneralFailure,
+        };
+        let opened = matches!(reply, Reply::Succeeded);
+        TcpResponseHeader::new(reply, Address::SocketAddress(target_addr))
+            .write_to(&mut stream)
+            .await?;
+        if !opened {
+            return Ok(());
+        }
+
+        let (reader, writer) = stream.into_split();
+        tokio::join!(
+            Proxy::send_consumer(reader, commands, stream_id),
+            Proxy::recv_consumer(writer, events),
+        );
+        Ok(())
+    }
+
+    // Answers the SOCKS5 handshake of a client up to its request, returning the address it
+    // asked for. Domain names are passed on unresolved for the exit relay. The request is
+    // answered once the exit relay opened the stream or refused it.
+    async fn handshake(stream: &mut TcpStream, target_addr: SocketAddr) -> io::Result<Target> {
+        println!("-------------NEW STREAM");
+        let handshake_req = socks5::HandshakeRequest::read_from(stream).await.unwrap();
 
         println!("Req: {:?}", handshake_req.methods);
 
@@ -62,7 +215,7 @@ impl Proxy {
                 socks5::SOCKS5_AUTH_METHOD_NONE => {
                     println!("MONKE");
                     let handshake_resp = HandshakeResponse::new(SOCKS5_AUTH_METHOD_NONE);
-                    handshake_resp.write_to(&mut stream).await.unwrap();
+                    handshake_resp.write_to(stream).await.unwrap();
                     break;
                 }
                 _ => {
@@ -71,7 +224,7 @@ impl Proxy {
             }
         }
 
-        let header = match TcpRequestHeader::read_from(&mut stream).await {
+        let header = match TcpRequestHeader::read_from(stream).await {
             Ok(h) => h,
             Err(err) => {
                 println!(
@@ -80,7 +233,7 @@ impl Proxy {
                 );
                 let rh =
                     TcpResponseHeader::new(err.as_reply(), Address::SocketAddress(target_addr));
-                rh.write_to(&mut stream).await.unwrap();
+                rh.write_to(stream).await.unwrap();
                 return Err(err.into());
             }
         };
@@ -90,42 +243,61 @@ impl Proxy {
         match header.command {
             Command::TcpConnect => {
                 println!("CONNECT {}", target_addr);
-
-                let header = TcpResponseHeader::new(
-                    socks5::Reply::Succeeded,
-                    Address::SocketAddress(target_addr),
-                );
-                header.write_to(&mut stream).await.unwrap();
             }
             _ => {
                 panic!("got unexpected command {:?}", header.command);
             }
         }
 
-        return Ok(stream);
+        let target = match header.address {
+            Address::SocketAddress(addr) => Target::IP(addr),
+            Address::DomainNameAddress(name, port) => Target::Domain(name, port),
+        };
+
+        return Ok(target);
     }
 
-    async fn send_consumer(&mut self, stream: &mut OwnedReadHalf, target_addr: SocketAddr) -> () {
+    // Sends what the client writes on its stream, ending the stream once the client is done
+    // param stream: The read half of the client's connection
+    // param commands: Reaches the task that owns the consumer
+    // param stream_id: The stream of the client
+    async fn send_consumer(
+        mut stream: OwnedReadHalf,
+        commands: mpsc::Sender<StreamCommand>,
+        stream_id: u32,
+    ) -> () {
         loop {
             let mut payload = [0u8; 1024];
 
-            stream.read(&mut payload).await.unwrap();
-
-            let mut guard = self.consumer.lock().unwrap();
-            let consumer_locked = &mut *guard;
-            consumer_locked
-                .send_message(payload.to_vec(), target_addr)
-                .await;
+            let read = stream.read(&mut payload).await.unwrap_or(0);
+            let command = match read {
+                0 => StreamCommand::End(stream_id),
+                read => StreamCommand::Data(stream_id, payload[..read].to_vec()),
+            };
+            if commands.send(command).await.is_err() || read == 0 {
+                return;
+            }
         }
     }
 
-    async fn recv_consumer(&mut self, stream: &mut OwnedWriteHalf) -> () {
-        loop {
-            let mut guard = self.consumer.lock().unwrap();
-            let consumer_locked = &mut *guard;
-            let payload = consumer_locked.recv_message().await;
-            drop(guard);
-            stream.write(&payload).await.unwrap();
+    // Writes what the exit relay sends on the stream to the client until the stream ends
+    // param stream: The write half of the client's connection
+    // param events: The events of the stream
+    async fn recv_consumer(
+        mut stream: OwnedWriteHalf,
+        mut events: mpsc::UnboundedReceiver<StreamEvent>,
+    ) -> () {
+        while let Some(event) = events.recv().await {
+            match event {
+                StreamEvent::Data(_, payload) => {
+                    if stream.write_all(&payload).await.is_err() {
+                        return;
+                    }
+                }
+                StreamEvent::End(_) | StreamEvent::Refused(..) => break,
+                StreamEvent::Opened(_) => {}
+            }
         }
+        let _ = stream.shutdown().await;
     }
 }
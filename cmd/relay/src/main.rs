@@ -3,22 +3,33 @@ use std::{
     net::{IpAddr, SocketAddr},
 };
 
-use core::relay_node::relay_node::RelayNode;
+use core::relay_node::{exit_policy::ExitPolicy, relay_node::RelayNode};
+
+static NO_CELLS_ENV: &str = "RO_NO_CELLS";
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    let (ip, port, index_addr) = parse_arguments(args);
+    let (ip, port, index_addr, exit_policy) = parse_arguments(args);
     let node = RelayNode::new(ip, port);
+    node.set_exit_policy(exit_policy);
+    if env::var_os(NO_CELLS_ENV).is_some() {
+        node.set_cells(false);
+    }
 
     node.register(index_addr, ronion_index::key::read_public());
 
     node.start();
 }
 
-fn parse_arguments(args: Vec<String>) -> (IpAddr, u16, SocketAddr) {
+// The optional fourth argument is an exit policy like "reject 10.0.0.0/8:*, accept *:443"
+fn parse_arguments(args: Vec<String>) -> (IpAddr, u16, SocketAddr, ExitPolicy) {
     let addr: IpAddr = args[1].parse().unwrap();
     let port: u16 = args[2].parse().unwrap();
     let index_addr = args[3].parse().unwrap();
-    (addr, port, index_addr)
+    let exit_policy = match args.get(4) {
+        Some(policy) => policy.parse().unwrap(),
+        None => ExitPolicy::default(),
+    };
+    (addr, port, index_addr, exit_policy)
 }
@@ -1,27 +1,116 @@
 use crate::{
-    crypto::{Aes256, ClientCrypto, ClientSecret},
+    crypto::{ClientCrypto, Negotiation, SuiteCipher},
+    flow_control::{RecvWindow, SendWindow},
     protocol::{
+        directory::unix_time,
+        error::ProtocolError,
         io::{OnionReader, OnionWriter, RawOnionReader, RawOnionWriter},
-        onion::{ClientType, HelloRequest, Message, Onion, Relay, Target},
+        onion::{
+            ClientType, HelloRequest, HelloResponse, Message, Onion, RefusalReason, Relay,
+            StreamData, StreamRefusal, Target, DIRECTORY_VERSION, STREAM_OPENED_VERSION,
+        },
     },
+    runtime,
+    transport::{TcpTransport, Transport},
+};
+use futures::io::{AsyncReadExt, ReadHalf, WriteHalf};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Error, ErrorKind, Result},
+    net::{SocketAddr, ToSocketAddrs},
 };
-use async_std::net::{SocketAddr, TcpStream};
 
 use super::onionizer::Onionizer;
 
-pub struct Consumer {
-    entry_reader: OnionReader<TcpStream, Aes256>,
-    entry_writer: OnionWriter<TcpStream, Aes256>,
+/// The largest frame a consumer accepts unless created with another limit.
+pub const CONSUMER_MAX_FRAME_LEN: u32 = 1 << 20;
+
+/// Something that happened on one of the streams of a consumer's circuit.
+#[derive(Debug, PartialEq)]
+pub enum StreamEvent {
+    /// The exit relay connected the stream to its target.
+    Opened(u32),
+    /// The exit relay sent bytes from the endpoint of the stream.
+    Data(u32, Vec<u8>),
+    /// The stream was closed by the exit relay.
+    End(u32),
+    /// The exit relay did not open the stream, for the given reason.
+    Refused(u32, RefusalReason),
+}
+
+impl StreamEvent {
+    /// Gets the ID of the stream the event happened on.
+    pub fn stream_id(&self) -> u32 {
+        match self {
+            StreamEvent::Opened(stream_id)
+            | StreamEvent::Data(stream_id, _)
+            | StreamEvent::End(stream_id)
+            | StreamEvent::Refused(stream_id, _) => *stream_id,
+        }
+    }
+}
+
+// The flow control windows of one open stream.
+struct StreamWindows {
+    send: SendWindow,
+    recv: RecvWindow,
+}
+
+type EntryReader<T> = OnionReader<ReadHalf<<T as Transport>::Stream>, SuiteCipher>;
+type EntryWriter<T> = OnionWriter<WriteHalf<<T as Transport>::Stream>, SuiteCipher>;
+
+pub struct Consumer<T: Transport = TcpTransport> {
+    entry_reader: EntryReader<T>,
+    entry_writer: EntryWriter<T>,
     onionizer: Onionizer,
+    streams: HashMap<u32, StreamWindows>,
+    next_stream_id: u32,
+    send_window: SendWindow,
+    recv_window: RecvWindow,
+    // Events read while waiting for credit, not yet returned by recv_message.
+    pending: VecDeque<StreamEvent>,
 }
 
 impl Consumer {
     // Creates a new Consumer instance by dialing an index_addr with
     // an index key. After receiving relays it will attemtp to set up
     // its overral circuit in the network.
-    pub async fn new(index_addr: String, index_pub_key: [u8; 32]) -> Self {
+    pub async fn new(index_addr: String, index_pub_key: [u8; 32]) -> Result<Self> {
+        Consumer::with_max_frame_len(index_addr, index_pub_key, CONSUMER_MAX_FRAME_LEN).await
+    }
+
+    // Creates a new Consumer instance like Consumer::new, rejecting frames from the
+    // index and the circuit that are longer than the given limit.
+    // param max_frame_len: The maximum frame length in bytes
+    pub async fn with_max_frame_len(
+        index_addr: String,
+        index_pub_key: [u8; 32],
+        max_frame_len: u32,
+    ) -> Result<Self> {
+        // Looking the host up blocks, so it runs where it doesn't hold up the runtime.
+        let index_addr = runtime::spawn_blocking(move || index_addr.to_socket_addrs())
+            .await?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "address resolved to nothing"))?;
+        Consumer::with_transport(TcpTransport, index_addr, index_pub_key, max_frame_len, None).await
+    }
+}
+
+impl<T: Transport> Consumer<T> {
+    // Creates a new Consumer instance like Consumer::with_max_frame_len, reaching the
+    // index and the relays over the given transport.
+    // param transport: The transport to reach the other nodes over
+    // param exit_port: The port the streams go to, if known. The circuit then exits at a
+    // relay whose policy allows it, and creating it fails if no listed relay does.
+    pub async fn with_transport(
+        transport: T,
+        index_addr: SocketAddr,
+        index_pub_key: [u8; 32],
+        max_frame_len: u32,
+        exit_port: Option<u16>,
+    ) -> Result<Self> {
         let (mut index_reader, mut index_writer) =
-            Consumer::dial_with_key(index_addr, index_pub_key).await;
+            Self::dial_with_key(&transport, index_addr, index_pub_key, max_frame_len).await?;
 
         index_writer
             .write(Onion {
@@ -29,177 +118,377 @@ impl Consumer {
                 message: Message::GetRelaysRequest(),
                 target: Target::Current,
             })
-            .await
-            .expect("index writer failed");
+            .await?;
 
-        let index_onion = index_reader.read().await.expect("index reader failed");
+        let index_onion = index_reader.read().await?;
         let relays = match index_onion.message {
-            Message::GetRelaysResponse(relays) => relays,
-            _ => panic!("Got unexpected message"),
+            Message::Directory(directory) => directory
+                .verify(&index_pub_key, unix_time())
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?,
+            // Index nodes that predate directories send the relays unsigned.
+            Message::GetRelaysResponse(relays) if index_reader.version() < DIRECTORY_VERSION => {
+                relays
+            }
+            _ => return Err(ProtocolError::UnexpectedMessage("a Directory").into()),
         };
 
         println!("In consumer new before circuit creation");
 
-        let (entry_reader, entry_writer, target_ids, ciphers) =
-            Consumer::create_circuit(relays).await;
+        let (entry_reader, entry_writer, onionizer) =
+            Self::create_circuit(&transport, relays, max_frame_len, exit_port).await?;
 
         println!("In consumer new after circuit creation");
 
-        Consumer {
+        Ok(Consumer {
             entry_reader,
             entry_writer,
-            onionizer: Onionizer::new(target_ids, ciphers),
-        }
+            onionizer,
+            streams: HashMap::new(),
+            next_stream_id: 0,
+            send_window: SendWindow::circuit(),
+            recv_window: RecvWindow::circuit(),
+            pending: VecDeque::new(),
+        })
     }
 
-    // Sets upp a tcp connectioon to the given addr.
-    async fn dial(addr: String) -> TcpStream {
+    // Sets upp a connectioon to the given addr.
+    async fn dial(transport: &T, addr: SocketAddr) -> Result<T::Stream> {
         println!("{:?}: ", addr);
-        TcpStream::connect(addr).await.expect("unable to connect")
+        transport.connect(addr).await
     }
 
     // Dials, given a key. It uses said key to execute a handshake with the recieveing
     // node at the specified addr.
     async fn dial_with_key(
-        addr: String,
+        transport: &T,
+        addr: SocketAddr,
         peer_pub_key: [u8; 32],
-    ) -> (
-        OnionReader<TcpStream, Aes256>,
-        OnionWriter<TcpStream, Aes256>,
-    ) {
-        Consumer::handshake(&mut Consumer::dial(addr).await, peer_pub_key).await
+        max_frame_len: u32,
+    ) -> Result<(EntryReader<T>, EntryWriter<T>)> {
+        Self::handshake(
+            Self::dial(transport, addr).await?,
+            peer_pub_key,
+            max_frame_len,
+        )
+        .await
+    }
+
+    //Creates the network circuit before actually utelizing the network.
+    async fn create_circuit(
+        transport: &T,
+        mut relays: Vec<Relay>,
+        max_frame_len: u32,
+        exit_port: Option<u16>,
+    ) -> Result<(EntryReader<T>, EntryWriter<T>, Onionizer)> {
+        let mut onionizer = Onionizer::new(Vec::new(), Vec::new());
+
+        if relays.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "the index listed no relays",
+            ));
+        }
+        println!("Relays: {:?}", relays);
+        // Streams leave the circuit at its last relay, so make it one that exits to their
+        // port, or to some port if that isn't known.
+        let exit = relays.iter().rposition(|relay| match exit_port {
+            Some(port) => relay.exit_policy.allows_port(port),
+            None => !relay.exit_policy.ports.is_empty(),
+        });
+        if exit.is_none() && exit_port.is_some() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "the index listed no relay that exits to the port",
+            ));
+        }
+        let mut exit_node = exit.map(|exit| relays.remove(exit));
+        // A circuit through the exit alone enters at it.
+        let entry_node = match relays.pop() {
+            Some(entry_node) => entry_node,
+            None => exit_node.take().unwrap(),
+        };
+        relays.extend(exit_node);
+        let (mut entry_reader, mut entry_writer) = Self::dial_with_key(
+            transport,
+            entry_node.addr,
+            entry_node.pub_key,
+            max_frame_len,
+        )
+        .await?;
+
+        for relay in relays {
+            let crypto = ClientCrypto::new(&relay.pub_key).map_err(Self::invalid_key)?;
+            let secret = crypto.gen_secret();
+            let onion = onionizer
+                .grow_onion(Onion {
+                    circuit_id: None,
+                    message: Message::HelloRequest(HelloRequest::new(
+                        ClientType::Consumer,
+                        secret.public_key(),
+                    )),
+                    target: Target::Relay(relay.id),
+                })
+                .await;
+            entry_writer.write(onion).await?;
+            let onion = entry_reader.read().await?;
+            // The relays pass a Close on without layers.
+            if let Message::Close(reason) = onion.message {
+                return Err(ProtocolError::CircuitClosed(reason).into());
+            }
+            let onion = onionizer.peel_onion(onion).await?;
+            let hello_resp = Self::hello_response(onion.message)?;
+            let negotiation = Self::negotiation(&hello_resp)?;
+            let ciphers = secret
+                .symmetric_ciphers(hello_resp.signed_public_key, &negotiation)
+                .map_err(|_| Self::unsigned_handshake())?;
+            onionizer.push_layer(relay.id, ciphers, negotiation.version);
+        }
+
+        Ok((entry_reader, entry_writer, onionizer))
     }
 
     // Attempts to create a ronion handshake with the given stream. From the handshake
     // we will end up with a OnionReader and OnionWriter with the same cipher.
     async fn handshake(
-        stream: &mut TcpStream,
+        stream: T::Stream,
         peer_pub_key: [u8; 32],
-    ) -> (
-        OnionReader<TcpStream, Aes256>,
-        OnionWriter<TcpStream, Aes256>,
-    ) {
-        let client_crypto = match ClientCrypto::new(&peer_pub_key) {
-            Ok(v) => v,
-            Err(e) => panic!("could not create crypto client: {:?}", e),
-        };
+        max_frame_len: u32,
+    ) -> Result<(EntryReader<T>, EntryWriter<T>)> {
+        let client_crypto = ClientCrypto::new(&peer_pub_key).map_err(Self::invalid_key)?;
         let secret = client_crypto.gen_secret();
         let pub_key = secret.public_key();
 
-        let mut raw_writer = RawOnionWriter::new(stream.clone());
-        let mut raw_reader = RawOnionReader::new(stream.clone());
+        let (reader, writer) = stream.split();
+        let mut raw_writer = RawOnionWriter::new(writer);
+        let mut raw_reader = RawOnionReader::new(reader).with_max_frame_len(max_frame_len);
 
         raw_writer
             .write(Onion {
                 circuit_id: None,
-                message: Message::HelloRequest(HelloRequest {
-                    client_type: ClientType::Consumer,
-                    public_key: pub_key,
-                }),
+                message: Message::HelloRequest(HelloRequest::new(ClientType::Consumer, pub_key)),
                 target: Target::Current,
             })
-            .await
-            .expect("raw handshake writer failed");
-        let hello_resp = raw_reader.read().await.expect("raw reader failed");
+            .await?;
+        let hello_resp = raw_reader.read().await?;
 
-        let signed_public_key = match hello_resp.message {
-            Message::HelloResponse(signed_public_key) => signed_public_key,
-            _ => {
-                panic!("expected 'HelloResponse', got {:?}", hello_resp.message)
-            }
-        };
+        let hello_resp = Self::hello_response(hello_resp.message)?;
+        let negotiation = Self::negotiation(&hello_resp)?;
 
-        let cipher = secret
-            .symmetric_cipher(signed_public_key)
-            .expect("symmetric cipher gen failed");
-        (
-            raw_reader.with_cipher(cipher.clone()),
-            raw_writer.with_cipher(cipher.clone()),
+        let ciphers = secret
+            .symmetric_ciphers(hello_resp.signed_public_key, &negotiation)
+            .map_err(|_| Self::unsigned_handshake())?;
+        Ok((
+            raw_reader
+                .with_version(negotiation.version)
+                .with_cells(negotiation.cells)
+                .with_cipher(ciphers.recv),
+            raw_writer
+                .with_version(negotiation.version)
+                .with_cells(negotiation.cells)
+                .with_cipher(ciphers.send),
+        ))
+    }
+
+    // Unpacks the answer to a HelloRequest, failing with the peer's reason if it
+    // closed the handshake instead.
+    // param message: The message received after sending the HelloRequest
+    fn hello_response(message: Message) -> Result<HelloResponse> {
+        match message {
+            Message::HelloResponse(hello_resp) => Ok(hello_resp),
+            Message::Close(reason) => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("peer closed the handshake: {:?}", reason),
+            )),
+            _ => Err(ProtocolError::UnexpectedMessage("a HelloResponse").into()),
+        }
+    }
+
+    // Checks the version, cipher suite and cells picked by the peer against the ones we offered.
+    // Consumers always ask for cells.
+    // param hello_resp: The HelloResponse of the peer
+    fn negotiation(hello_resp: &HelloResponse) -> Result<Negotiation> {
+        Negotiation::confirm(hello_resp, true).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "peer picked a version, cipher suite or cells that were not offered",
+            )
+        })
+    }
+
+    // The error of a relay or index node whose signing key is not a valid key
+    fn invalid_key<E>(_: E) -> Error {
+        Error::new(ErrorKind::InvalidData, "invalid signing key")
+    }
+
+    // The error of a handshake that was not signed with the signing key of the peer
+    fn unsigned_handshake() -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            "handshake not signed with the peer's key",
         )
     }
 
+    // Opens a new stream over the consumer's circuit. The exit relay connects the
+    // stream to the given target, and every stream is closed independently.
+    // param target: The IP or domain the exit relay connects the stream to
+    pub async fn begin_stream(&mut self, target: Target) -> Result<u32> {
+        let mut stream_id = self.next_stream_id;
+        while self.streams.contains_key(&stream_id) {
+            stream_id = stream_id.wrapping_add(1);
+        }
+        self.next_stream_id = stream_id.wrapping_add(1);
+
+        self.send(Message::BeginStream(stream_id), target).await?;
+        self.streams.insert(
+            stream_id,
+            StreamWindows {
+                send: SendWindow::stream(),
+                recv: RecvWindow::stream(),
+            },
+        );
+        // Exit relays that predate StreamOpened only report streams they could not open.
+        if self.onionizer.exit_version() < Some(STREAM_OPENED_VERSION) {
+            self.pending.push_back(StreamEvent::Opened(stream_id));
+        }
+        Ok(stream_id)
+    }
+
     // Method to be called by other implementations utelising consumer. Sends
-    // the specified payload as an onion across the consumer's circuit.
-    pub async fn send_message(&mut self, payload: Vec<u8>, addr: SocketAddr) -> () {
-        let onion = self.onionizer.grow_onion_relay(payload, addr).await;
-        self.entry_writer.write(onion).await.unwrap();
+    // the specified payload as an onion on one of the consumer's streams. Waits for
+    // credit from the exit relay once the stream or circuit window is exhausted.
+    // param stream_id: The stream returned by begin_stream
+    pub async fn send_data(&mut self, stream_id: u32, data: Vec<u8>) -> Result<()> {
+        loop {
+            let stream = self.open_stream(stream_id)?;
+            if stream.send.available() > 0 && self.send_window.available() > 0 {
+                stream.send.try_reserve();
+                self.send_window.try_reserve();
+                break;
+            }
+            // Only the exit relay can give credit, so read until it does.
+            if let Some(event) = self.read_event().await? {
+                self.pending.push_back(event);
+            }
+        }
+
+        self.send(
+            Message::Data(StreamData { stream_id, data }),
+            Target::Current,
+        )
+        .await
+    }
+
+    // Closes one of the consumer's streams, leaving the others open.
+    // param stream_id: The stream returned by begin_stream
+    pub async fn end_stream(&mut self, stream_id: u32) -> Result<()> {
+        self.open_stream(stream_id)?;
+        self.streams.remove(&stream_id);
+        self.send(Message::EndStream(stream_id), Target::Current)
+            .await
     }
 
     // Method to be called by other implementations utelising consumer. Recieves
-    // a payload from an onion recieved over the consumer's circuit.
-    pub async fn recv_message(&mut self) -> Vec<u8> {
-        let onion = self.entry_reader.read().await.expect("entry reader failed");
-        let peeled_onion = self.onionizer.peel_onion_relay(onion).await;
+    // the next stream event from an onion recieved over the consumer's circuit. Credit
+    // for more data is sent to the exit relay as data is returned.
+    pub async fn recv_message(&mut self) -> std::result::Result<StreamEvent, ProtocolError> {
+        let event = loop {
+            if let Some(event) = self.pending.pop_front() {
+                break event;
+            }
+            if let Some(event) = self.read_event().await? {
+                break event;
+            }
+        };
+
+        if let StreamEvent::Data(stream_id, _) = event {
+            if self.recv_window.deliver() {
+                self.send(Message::Sendme(None), Target::Current).await?;
+            }
+            let stream_sendme = self
+                .streams
+                .get_mut(&stream_id)
+                .is_some_and(|stream| stream.recv.deliver());
+            if stream_sendme {
+                self.send(Message::Sendme(Some(stream_id)), Target::Current)
+                    .await?;
+            }
+        }
+
+        Ok(event)
+    }
+
+    // Reads the next onion from the circuit, taking the credit of Sendmes and returning
+    // the events of the streams that are still open.
+    async fn read_event(&mut self) -> std::result::Result<Option<StreamEvent>, ProtocolError> {
+        let onion = self.entry_reader.read().await?;
+        // The relays pass a Close on without layers.
+        if let Message::Close(reason) = onion.message {
+            return Err(ProtocolError::CircuitClosed(reason));
+        }
+        let peeled_onion = self.onionizer.peel_onion_relay(onion).await?;
         match peeled_onion.message {
-            Message::Payload(load) => load,
-            Message::Close(msg) => match msg {
-                Some(_v) => todo!(),
-                None => todo!(),
+            Message::Data(StreamData { stream_id, data }) => Ok(self
+                .streams
+                .contains_key(&stream_id)
+                .then_some(StreamEvent::Data(stream_id, data))),
+            Message::StreamOpened(stream_id) => Ok(self
+                .streams
+                .contains_key(&stream_id)
+                .then_some(StreamEvent::Opened(stream_id))),
+            Message::EndStream(stream_id) => Ok(self
+                .streams
+                .remove(&stream_id)
+                .map(|_| StreamEvent::End(stream_id))),
+            Message::StreamRefused(StreamRefusal { stream_id, reason }) => Ok(self
+                .streams
+                .remove(&stream_id)
+                .map(|_| StreamEvent::Refused(stream_id, reason))),
+            Message::Sendme(None) => self.send_window.credit().map(|_| None),
+            // Credit can still arrive for a stream we closed.
+            Message::Sendme(Some(stream_id)) => match self.streams.get(&stream_id) {
+                Some(stream) => stream.send.credit().map(|_| None),
+                None => Ok(None),
             },
-            _ => panic!("Got unexpected message"),
+            Message::Close(reason) => Err(ProtocolError::CircuitClosed(reason)),
+            _ => Err(ProtocolError::UnexpectedMessage("a stream message")),
         }
     }
 
-    //Creates the network circuit before actually utelizing the network.
-    async fn create_circuit(
-        mut relays: Vec<Relay>,
-    ) -> (
-        OnionReader<TcpStream, Aes256>,
-        OnionWriter<TcpStream, Aes256>,
-        Vec<u32>,
-        Vec<Aes256>,
-    ) {
-        let mut crypto: ClientCrypto;
-        let mut secret: ClientSecret;
-        let mut secret_public: [u8; 32];
-        let mut ciphers = Vec::<Aes256>::new();
-        let mut onion: Onion;
-
-        if relays.len() == 0 {
-            panic!("Relays cannot be zero in length")
+    // Waits until recv_message has an event to return or the entry relay sent something, so
+    // the wait can be raced against other work without losing part of an onion. recv_message
+    // may still wait for the rest of an onion that is on its way.
+    pub async fn readable(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            self.entry_reader.ready().await?;
         }
-        println!("Relays: {:?}", relays);
-        let entry_node = relays.remove(relays.len() - 1);
-        let (mut entry_reader, mut entry_writer) =
-            Consumer::dial_with_key(entry_node.addr.to_string(), entry_node.pub_key).await;
-
-        for i in 0..relays.len() {
-            crypto = ClientCrypto::new(&relays.clone()[relays.len() - 1].pub_key)
-                .expect("clientcrypto new failed");
-            secret = crypto.gen_secret();
-            secret_public = secret.public_key();
-            onion = Onionizer::grow_onion(
-                Onion {
-                    circuit_id: None,
-                    message: Message::HelloRequest(HelloRequest {
-                        client_type: ClientType::Consumer,
-                        public_key: secret_public,
-                    }),
-                    target: Target::Relay(relays.clone()[i].id),
-                },
-                relays.clone()[0..i]
-                    .into_iter()
-                    .map(|relay| relay.id)
-                    .collect(),
-                ciphers[0..i].to_vec(),
-            )
-            .await;
-            match entry_writer.write(onion).await {
-                Ok(v) => v,
-                Err(_e) => panic!("Write error"),
-            };
-            onion = entry_reader.read().await.expect("entry read failed");
-            onion = Onionizer::peel_onion(onion, ciphers.clone()).await;
-            ciphers.push(match onion.message {
-                Message::HelloResponse(signed_public_key) => secret
-                    .symmetric_cipher(signed_public_key)
-                    .expect("symmetric cipher gen failed"),
-                _ => panic!("Got unexpected Message type"),
+        Ok(())
+    }
+
+    // Closes the consumer's circuit, tearing it down at every relay along with its streams.
+    pub async fn close(&mut self) -> Result<()> {
+        self.streams.clear();
+        self.entry_writer
+            .write(Onion {
+                circuit_id: None,
+                message: Message::Close(None),
+                target: Target::Current,
             })
-        }
+            .await
+    }
 
-        let target_ids = relays.into_iter().map(|relay| relay.id).collect();
+    // Grows a message for the exit relay and sends it into the circuit.
+    async fn send(&mut self, message: Message, target: Target) -> Result<()> {
+        let onion = self.onionizer.grow_onion_relay(message, target).await;
+        self.entry_writer.write(onion).await
+    }
 
-        (entry_reader, entry_writer, target_ids, ciphers)
+    // Gets the windows of a stream that was opened by begin_stream and has not ended.
+    fn open_stream(&self, stream_id: u32) -> Result<&StreamWindows> {
+        self.streams.get(&stream_id).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotConnected,
+                format!("stream {} is not open", stream_id),
+            )
+        })
     }
 }
@@ -1,108 +1,274 @@
-use async_std::io::{Cursor, WriteExt};
-use async_std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use bytes::BytesMut;
+use rand_core::{OsRng, RngCore};
 
-use crate::crypto::Aes256;
+use crate::crypto::{CipherError, CountedCipher, LinkCiphers, SuiteCipher, TAG_LEN};
 use crate::protocol::{
-    io::{RawOnionReader, RawOnionWriter},
-    onion::{Message, Onion, Target},
+    codec::{encode_onion, payload_header_len, CELL_VERSION},
+    error::ProtocolError,
+    io::{open_layer, unpad_layer},
+    onion::{Message, Onion, Target, PROTOCOL_VERSIONS},
 };
 
+// The ciphers and protocol version shared with one relay of the circuit. Each
+// direction keeps its own frame counter, so the layer must live as long as the circuit.
+struct Layer {
+    send: CountedCipher<SuiteCipher>,
+    recv: CountedCipher<SuiteCipher>,
+    version: u8,
+}
+
+impl Layer {
+    fn new(ciphers: LinkCiphers, version: u8) -> Self {
+        Layer {
+            send: CountedCipher::new(ciphers.send),
+            recv: CountedCipher::new(ciphers.recv),
+            version,
+        }
+    }
+
+    // Whether the relay pads the layer it passes on, see pad_layer.
+    fn pads(&self) -> bool {
+        self.version >= CELL_VERSION
+    }
+}
+
+// The shape of a layer once it reached its relay.
+struct LayerShape {
+    // The length of the layer
+    len: usize,
+    // The length of the pad the relay inserts into the layer it passes on, 0 if it doesn't
+    pad_len: usize,
+    // The length of the junk at the end of the layer's plaintext, see Onionizer::junk
+    junk_len: usize,
+}
+
 pub struct Onionizer {
     target_ids: Vec<u32>,
-    ciphers: Vec<Aes256>,
+    layers: Vec<Layer>,
 }
 
 impl Onionizer {
-    pub fn new(target_ids: Vec<u32>, ciphers: Vec<Aes256>) -> Self {
+    // Creates an onionizer whose layers all speak the newest protocol version.
+    pub fn new(target_ids: Vec<u32>, ciphers: Vec<LinkCiphers>) -> Self {
+        let version = *PROTOCOL_VERSIONS.end();
         Onionizer {
             target_ids,
-            ciphers,
+            layers: ciphers
+                .into_iter()
+                .map(|ciphers| Layer::new(ciphers, version))
+                .collect(),
         }
     }
 
-    // Serializes the given onion to data.
-    async fn onionize(onion: Onion, cipher: Aes256) -> Vec<u8> {
-        let mut cursor = Cursor::new(Vec::<u8>::new());
-        let mut onion_writer = RawOnionWriter::new(cursor.get_mut()).with_cipher(cipher);
-        onion_writer
-            .write(onion)
-            .await
-            .expect("onionize write failed");
-        cursor.into_inner()
+    // Returns the protocol version negotiated with the last relay of the circuit, if any
+    pub fn exit_version(&self) -> Option<u8> {
+        self.layers.last().map(|layer| layer.version)
     }
 
-    // Deserializes the given data to an onion.
-    async fn deonionize(data: Vec<u8>, cipher: Aes256) -> Onion {
-        let mut cursor = Cursor::new(Vec::<u8>::new());
-        cursor.write(&data).await.unwrap();
+    // Extends the circuit by one relay, adding a layer to every onion grown from now on.
+    // param version: The protocol version negotiated with the relay
+    pub fn push_layer(&mut self, target_id: u32, ciphers: LinkCiphers, version: u8) {
+        self.target_ids.push(target_id);
+        self.layers.push(Layer::new(ciphers, version));
+    }
 
-        cursor.set_position(0);
-        let mut onion_reader = RawOnionReader::new(cursor).with_cipher(cipher);
+    // Adds layers to a message for the exit relay. Streams are opened towards an IP or
+    // a domain target, and their data and ends go to the exit itself (Target::Current).
+    pub async fn grow_onion_relay(&mut self, message: Message, target: Target) -> Onion {
+        self.grow_onion(Onion {
+            circuit_id: None,
+            message,
+            target,
+        })
+        .await
+    }
 
-        onion_reader.read().await.expect("onionize read failed")
+    // Removes layers from a specified onion and returns the onions core.
+    pub async fn peel_onion_relay(&mut self, onion: Onion) -> Result<Onion, ProtocolError> {
+        self.peel_onion(onion).await
     }
 
-    // Adds layers to a data load that goes to a specific addr (relay).
-    pub async fn grow_onion_relay(&self, payload: Vec<u8>, addr: SocketAddr) -> Onion {
-        Onionizer::grow_onion(
-            Onion {
+    // Adds layers (grows) the onion for each layer of the circuit. Every relay that pads
+    // passes on a layer of the length it received, see layer_shapes, so the innermost layer
+    // ends with the junk the pads turn into, see junk. The room before it is filled randomly.
+    pub async fn grow_onion(&mut self, onion: Onion) -> Onion {
+        let Some(exit) = self.layers.last() else {
+            return onion;
+        };
+        let mut plain_onion = BytesMut::new();
+        encode_onion(onion, exit.version, &mut plain_onion).expect("onionize write failed");
+
+        let shapes = self.layer_shapes(plain_onion.len());
+        let junk = self.junk(&shapes).expect("onionize write failed");
+        let exit_index = self.layers.len() - 1;
+        let core_len = plain_onion.len();
+        plain_onion.resize(shapes[exit_index].len - TAG_LEN - junk[exit_index].len(), 0);
+        OsRng {}.fill_bytes(&mut plain_onion[core_len..]);
+        plain_onion.extend_from_slice(&junk[exit_index]);
+        let mut layer = self.seal_layer(exit_index, &plain_onion);
+
+        for index in (0..exit_index).rev() {
+            // The pad the relay inserts is left out, it sends the tag in front of the rest.
+            let payload = match shapes[index].pad_len {
+                0 => layer,
+                pad_len => unpad_layer(layer, pad_len),
+            };
+            let onion = Onion {
                 circuit_id: None,
                 message: Message::Payload(payload),
-                target: Target::IP(addr),
-            },
-            self.target_ids.clone(),
-            self.ciphers.clone(),
-        )
-        .await
+                target: Target::Relay(self.target_ids[index + 1]),
+            };
+            plain_onion.clear();
+            encode_onion(onion, self.layers[index].version, &mut plain_onion)
+                .expect("onionize write failed");
+            // The Payload of a relay that pads ends with the junk already.
+            if !self.layers[index].pads() {
+                plain_onion.extend_from_slice(&junk[index]);
+            }
+            layer = self.seal_layer(index, &plain_onion);
+        }
+
+        Onion {
+            circuit_id: None,
+            message: Message::Payload(layer),
+            target: Target::Relay(self.target_ids[0]),
+        }
     }
 
-    // Removes layers from a specified onion and returns the onions core.
-    pub async fn peel_onion_relay(&self, onion: Onion) -> Onion {
-        Onionizer::peel_onion(onion, self.ciphers.clone()).await
+    // Seals the plaintext of a layer with the layer's cipher
+    // param index: The index of the layer
+    // param plain_layer: The plaintext of the layer
+    fn seal_layer(&mut self, index: usize, plain_layer: &[u8]) -> Vec<u8> {
+        self.layers[index]
+            .send
+            .seal(plain_layer)
+            .expect("onionize write failed")
     }
 
-    // Adds layers (grows) the onion for each target/cipher given.
-    pub async fn grow_onion(mut onion: Onion, target_ids: Vec<u32>, ciphers: Vec<Aes256>) -> Onion {
-        let mut onion_load: Vec<u8>;
-        for i in 0..target_ids.len() {
-            onion_load = Onionizer::onionize(onion, ciphers[ciphers.len() - 1 - i].clone()).await;
-            onion = Onion {
-                circuit_id: None,
-                message: Message::Payload(onion_load),
-                target: Target::Relay(target_ids[target_ids.len() - 1 - i].clone()),
+    // Works out the shape of every layer for an onion whose core encodes to core_len bytes.
+    // The outermost layer is made long enough for the core and the junk to fit into the
+    // innermost one.
+    // param core_len: The length of the encoded onion for the last relay
+    fn layer_shapes(&self, core_len: usize) -> Vec<LayerShape> {
+        let mut len = core_len + TAG_LEN;
+        loop {
+            let Some(shapes) = self.layer_shapes_from(len) else {
+                len += 1;
+                continue;
             };
+            let exit = &shapes[shapes.len() - 1];
+            let room = exit.len.saturating_sub(TAG_LEN + exit.junk_len);
+            if room >= core_len {
+                return shapes;
+            }
+            len += core_len - room;
         }
+    }
 
-        onion
+    // Works out the shapes of the layers, outside in, for an outermost layer of len bytes.
+    // A relay that pads peels a Payload that fills its layer and passes it on padded by the
+    // length of the Payload's header and tag, so the junk at the end of the layers grows by
+    // that much with every such relay. A relay that doesn't pad passes its Payload on as it
+    // is, which ends before the junk. Returns None if a Payload can't fill its room exactly.
+    // param len: The length of the outermost layer
+    fn layer_shapes_from(&self, len: usize) -> Option<Vec<LayerShape>> {
+        let mut shapes = Vec::new();
+        let (mut len, mut junk_len) = (len, 0);
+        for (layer, next_id) in self.layers.iter().zip(&self.target_ids[1..]) {
+            let room = match layer.pads() {
+                true => len.checked_sub(TAG_LEN)?,
+                false => len.checked_sub(TAG_LEN + junk_len)?,
+            };
+            let payload_len = payload_len_in(*next_id, room)?;
+            let (next_len, pad_len) = match layer.pads() {
+                true if payload_len >= TAG_LEN + junk_len => (len, len - payload_len),
+                true => return None,
+                false => (payload_len, 0),
+            };
+            shapes.push(LayerShape {
+                len,
+                pad_len,
+                junk_len,
+            });
+            junk_len = match layer.pads() {
+                true => junk_len + pad_len,
+                false => 0,
+            };
+            len = next_len;
+        }
+        shapes.push(LayerShape {
+            len,
+            pad_len: 0,
+            junk_len,
+        });
+        Some(shapes)
     }
 
-    // Removes layers (peels) the onion for each cipher given.
-    pub async fn peel_onion(onion: Onion, ciphers: Vec<Aes256>) -> Onion {
-        let mut out_onion: Onion;
-        let mut data = match onion.message {
-            Message::Payload(payload) => payload,
-            _ => panic!("Got unexpected message type"),
-        };
+    // Works out the junk at the end of the plaintext of every layer. The relay of a layer
+    // passes on the end of its plaintext, followed by its pad, which the next relay decrypts
+    // into the junk at the end of its own plaintext. Pads are keystream of the relay that
+    // inserts them, so junk looks like ciphertext to every relay.
+    // param shapes: The shapes of the layers
+    fn junk(&self, shapes: &[LayerShape]) -> Result<Vec<Vec<u8>>, CipherError> {
+        let mut junk = Vec::new();
+        let mut passed_on: Vec<u8> = Vec::new();
+        for (layer, shape) in self.layers.iter().zip(shapes) {
+            let plain_len = shape.len - TAG_LEN;
+            let keystream = layer.send.keystream(
+                layer.send.counter(),
+                plain_len - passed_on.len()..plain_len + shape.pad_len,
+            )?;
+            let (junk_stream, pad) = keystream.split_at(passed_on.len());
+            let layer_junk: Vec<u8> = passed_on
+                .iter()
+                .zip(junk_stream)
+                .map(|(byte, key)| byte ^ key)
+                .collect();
+            passed_on = match shape.pad_len {
+                0 => Vec::new(),
+                _ => [layer_junk.as_slice(), pad].concat(),
+            };
+            junk.push(layer_junk);
+        }
+        Ok(junk)
+    }
 
-        for i in 0..ciphers.len() - 1 {
-            out_onion = Onionizer::deonionize(data, ciphers[i].clone()).await;
-            data = match out_onion.message {
+    // Removes layers (peels) the onion for each layer of the circuit.
+    pub async fn peel_onion(&mut self, mut onion: Onion) -> Result<Onion, ProtocolError> {
+        for layer in self.layers.iter_mut() {
+            let data = match onion.message {
                 Message::Payload(payload) => payload,
-                _ => panic!("Got unexpected message type"),
+                _ => return Err(ProtocolError::UnexpectedMessage("a Payload")),
             };
+            onion = open_layer(&data, &mut layer.recv, layer.version).await?;
         }
 
-        Onionizer::deonionize(data, ciphers[ciphers.len() - 1].clone()).await
+        Ok(onion)
     }
 }
 
+// Gets the length of the Payload for a relay whose onion fills room bytes exactly, if any.
+// Headers grow with the length of their Payload, so some rooms can't be filled.
+// param relay_id: The relay the onion targets
+// param room: The length of the encoded onion
+fn payload_len_in(relay_id: u32, room: usize) -> Option<usize> {
+    let payload_len = room.checked_sub(payload_header_len(relay_id, room))?;
+    [payload_len, payload_len + 1]
+        .into_iter()
+        .find(|&len| len + payload_header_len(relay_id, len) == room)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::crypto::{ClientCrypto, ServerCrypto};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use crate::crypto::{ClientCrypto, Negotiation, ServerCrypto};
+    use crate::protocol::io::{pad_layer, seal_layer};
+    use crate::protocol::onion::{ClientType, HelloRequest};
 
     use super::*;
 
-    fn test_cipher() -> Aes256 {
+    fn test_cipher() -> SuiteCipher {
         let server_crypto = ServerCrypto::new();
         let server_sign_key = server_crypto.signing_public();
 
@@ -110,7 +276,81 @@ mod tests {
         let client_secret = client_crypto.gen_secret();
         let client_public = client_secret.public_key();
 
-        server_crypto.gen_secret().symmetric_cipher(client_public)
+        server_crypto
+            .gen_secret()
+            .symmetric_ciphers(
+                client_public,
+                &Negotiation::accept(
+                    &HelloRequest::new(ClientType::Consumer, client_public),
+                    true,
+                )
+                .unwrap(),
+            )
+            .send
+    }
+
+    // Link ciphers that decrypt what they encrypt, so onions can be peeled by their grower.
+    fn loopback_ciphers() -> LinkCiphers {
+        let cipher = test_cipher();
+        LinkCiphers {
+            send: cipher.clone(),
+            recv: cipher,
+        }
+    }
+
+    // Builds the onionizer of a circuit through relays of the given versions, returning it
+    // with the ciphers the relays peel with. Its layers peel what they grow as well.
+    fn circuit(versions: &[u8]) -> (Onionizer, Vec<SuiteCipher>) {
+        let ciphers: Vec<SuiteCipher> = versions.iter().map(|_| test_cipher()).collect();
+        let mut onionizer = Onionizer::new(Vec::new(), Vec::new());
+        for (id, (cipher, version)) in ciphers.iter().zip(versions).enumerate() {
+            let ciphers = LinkCiphers {
+                send: cipher.clone(),
+                recv: cipher.clone(),
+            };
+            onionizer.push_layer(id as u32, ciphers, *version);
+        }
+        (onionizer, ciphers)
+    }
+
+    // Passes a grown onion through the relays of a circuit the way they peel it and pad the
+    // layer they pass on, returning the layer every relay received with its plaintext, and
+    // the onion the last relay peeled.
+    async fn peel_at_relays(
+        mut onion: Onion,
+        ciphers: Vec<SuiteCipher>,
+        versions: &[u8],
+    ) -> (Vec<(Vec<u8>, Vec<u8>)>, Onion) {
+        let mut hops = Vec::new();
+        for (index, (cipher, version)) in ciphers.into_iter().zip(versions).enumerate() {
+            let Message::Payload(payload) = onion.message else {
+                panic!("expected payload");
+            };
+            let plain = CountedCipher::new(cipher.clone()).open(&payload).unwrap();
+            let mut cipher = CountedCipher::new(cipher);
+            onion = open_layer(&payload, &mut cipher, *version).await.unwrap();
+            if index + 1 < versions.len() && *version >= CELL_VERSION {
+                if let Message::Payload(layer) = onion.message {
+                    let padded = pad_layer(layer, payload.len(), &cipher, 0).unwrap();
+                    onion.message = Message::Payload(padded);
+                }
+            }
+            hops.push((payload, plain));
+        }
+
+        (hops, onion)
+    }
+
+    fn lens(hops: &[(Vec<u8>, Vec<u8>)]) -> Vec<usize> {
+        hops.iter().map(|(layer, _)| layer.len()).collect()
+    }
+
+    fn longest_zero_run(bytes: &[u8]) -> usize {
+        bytes
+            .split(|&byte| byte != 0)
+            .map(<[u8]>::len)
+            .max()
+            .unwrap_or(0)
     }
 
     #[async_std::test]
@@ -121,8 +361,13 @@ mod tests {
             target: Target::Relay(69),
         };
         let cipher = test_cipher();
-        let data = Onionizer::onionize(onion, cipher.clone()).await;
-        let actual_onion = Onionizer::deonionize(data, cipher).await;
+        let version = *PROTOCOL_VERSIONS.end();
+        let data = seal_layer(onion, &mut CountedCipher::new(cipher.clone()), version)
+            .await
+            .unwrap();
+        let actual_onion = open_layer(&data, &mut CountedCipher::new(cipher), version)
+            .await
+            .unwrap();
         assert_eq!(
             Onion {
                 circuit_id: Some(420),
@@ -140,10 +385,10 @@ mod tests {
             message: Message::Payload("Naice test guy".as_bytes().to_vec()),
             target: Target::Relay(69),
         };
-        let ciphers: Vec<Aes256> = (0..3).into_iter().map(|_| test_cipher()).collect();
-        let target_ids = (0..3).collect();
-        let grown_onion = Onionizer::grow_onion(onion, target_ids, ciphers.clone()).await;
-        let peeled_onion = Onionizer::peel_onion(grown_onion, ciphers).await;
+        let versions = [*PROTOCOL_VERSIONS.end(); 3];
+        let (mut onionizer, ciphers) = circuit(&versions);
+        let grown_onion = onionizer.grow_onion(onion).await;
+        let (_, peeled_onion) = peel_at_relays(grown_onion, ciphers, &versions).await;
 
         assert_eq!(
             Onion {
@@ -157,16 +402,18 @@ mod tests {
 
     #[async_std::test]
     async fn grown_onion_relay_can_be_peeled() {
-        let ciphers: Vec<Aes256> = (0..3).into_iter().map(|_| test_cipher()).collect();
-        let target_ids = (0..3).collect();
-        let onionizer = Onionizer::new(target_ids, ciphers);
+        let versions = [*PROTOCOL_VERSIONS.end(); 3];
+        let (mut onionizer, ciphers) = circuit(&versions);
         let grown_onion = onionizer
             .grow_onion_relay(
-                "Naice test guy".as_bytes().to_vec(),
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+                Message::Payload("Naice test guy".as_bytes().to_vec()),
+                Target::IP(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    8080,
+                )),
             )
             .await;
-        let peeled_onion = onionizer.peel_onion_relay(grown_onion).await;
+        let (_, peeled_onion) = peel_at_relays(grown_onion, ciphers, &versions).await;
 
         assert_eq!(
             Onion {
@@ -180,4 +427,133 @@ mod tests {
             peeled_onion
         )
     }
+
+    fn end_stream_onion() -> Onion {
+        Onion {
+            circuit_id: None,
+            message: Message::EndStream(7),
+            target: Target::Current,
+        }
+    }
+
+    #[async_std::test]
+    async fn every_relay_receives_a_layer_of_the_same_length() {
+        let versions = [*PROTOCOL_VERSIONS.end(); 4];
+
+        let (mut onionizer, ciphers) = circuit(&versions);
+
+        let grown_onion = onionizer.grow_onion(end_stream_onion()).await;
+        let (hops, exit_onion) = peel_at_relays(grown_onion, ciphers, &versions).await;
+
+        let lens = lens(&hops);
+        assert!(lens.iter().all(|&len| len == lens[0]), "{:?}", lens);
+        assert_eq!(exit_onion, end_stream_onion());
+    }
+
+    #[async_std::test]
+    async fn relays_see_the_same_bytes_at_every_position() {
+        let versions = [CELL_VERSION; 4];
+        let (mut onionizer, ciphers) = circuit(&versions);
+        let core = || Onion {
+            circuit_id: None,
+            message: Message::Payload(vec![7; 77]),
+            target: Target::Current,
+        };
+        let mut plain_core = BytesMut::new();
+        encode_onion(core(), CELL_VERSION, &mut plain_core).unwrap();
+
+        let grown_onion = onionizer.grow_onion(core()).await;
+        let (hops, _) = peel_at_relays(grown_onion, ciphers, &versions).await;
+
+        let (exit_hop, relay_hops) = hops.split_last().unwrap();
+        // Relays peel a Payload of the same length at every position, made of ciphertext and
+        // pads, and the exit's core is followed by random bytes and junk.
+        let mut seen: Vec<&[u8]> = hops.iter().map(|(layer, _)| layer.as_slice()).collect();
+        let mut payload_lens = Vec::new();
+        for (_, plain) in relay_hops {
+            let header_len = payload_header_len(0, plain.len());
+            payload_lens.push(plain.len() - header_len);
+            seen.push(&plain[header_len..]);
+        }
+        seen.push(&exit_hop.1[plain_core.len()..]);
+
+        assert!(payload_lens.iter().all(|&len| len == payload_lens[0]));
+        for bytes in seen {
+            assert!(longest_zero_run(bytes) < 4, "{:?}", bytes);
+        }
+    }
+
+    #[async_std::test]
+    async fn layers_peel_behind_relays_that_do_not_pad() {
+        let newest = *PROTOCOL_VERSIONS.end();
+        let versions = [newest, CELL_VERSION - 1, newest];
+
+        let (mut onionizer, ciphers) = circuit(&versions);
+
+        let grown_onion = onionizer.grow_onion(end_stream_onion()).await;
+        let (hops, exit_onion) = peel_at_relays(grown_onion, ciphers, &versions).await;
+
+        let lens = lens(&hops);
+
+        assert_eq!(lens[0], lens[1]);
+        assert!(lens[2] < lens[1]);
+        assert_eq!(exit_onion, end_stream_onion());
+    }
+
+    #[async_std::test]
+    async fn onion_without_layers_is_rejected() {
+        let ciphers: Vec<LinkCiphers> = (0..3).map(|_| loopback_ciphers()).collect();
+        let mut onionizer = Onionizer::new((0..3).collect(), ciphers);
+        let onion = Onion {
+            circuit_id: None,
+            message: Message::EndStream(0),
+            target: Target::Current,
+        };
+
+        let peeled = onionizer.peel_onion(onion).await;
+
+        assert!(matches!(peeled, Err(ProtocolError::UnexpectedMessage(_))));
+    }
+
+    #[async_std::test]
+    async fn grown_onion_relay_to_domain_can_be_peeled() {
+        let versions = [*PROTOCOL_VERSIONS.end(); 3];
+        let (mut onionizer, ciphers) = circuit(&versions);
+        let target = Target::Domain("example.com".to_string(), 80);
+        let grown_onion = onionizer
+            .grow_onion_relay(
+                Message::Payload("Naice test guy".as_bytes().to_vec()),
+                target.clone(),
+            )
+            .await;
+        let (_, peeled_onion) = peel_at_relays(grown_onion, ciphers, &versions).await;
+
+        assert_eq!(target, peeled_onion.target);
+    }
+
+    #[async_std::test]
+    async fn replayed_onion_is_rejected() {
+        // Relays before layer padding pass layers on as they are, so the grower can peel them.
+        let (mut onionizer, _) = circuit(&[CELL_VERSION - 1; 3]);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let grown_onion = onionizer
+            .grow_onion_relay(
+                Message::Payload("Naice test guy".as_bytes().to_vec()),
+                Target::IP(addr),
+            )
+            .await;
+        let Message::Payload(payload) = grown_onion.message else {
+            panic!("expected payload");
+        };
+        let replay = |payload: &Vec<u8>| Onion {
+            circuit_id: None,
+            message: Message::Payload(payload.clone()),
+            target: Target::Relay(0),
+        };
+
+        onionizer.peel_onion(replay(&payload)).await.unwrap();
+        let err = onionizer.peel_onion(replay(&payload)).await.unwrap_err();
+
+        assert!(matches!(err, ProtocolError::FrameRejected(1)));
+    }
 }
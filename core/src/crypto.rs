@@ -1,16 +1,295 @@
-use std::sync::Arc;
+use std::{
+    fmt,
+    ops::{Range, RangeInclusive},
+    sync::Arc,
+};
 
 use aes_gcm::{
     aead::{Aead, NewAead},
     Aes256Gcm, Key, Nonce,
 };
+use chacha20poly1305::ChaCha20Poly1305;
 use ed25519_dalek::{Keypair, Signature, Signer, Verifier};
-use rand_core::{OsRng, RngCore};
-use x25519_dalek::{EphemeralSecret, PublicKey};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+
+use crate::protocol::{
+    codec::CELL_VERSION,
+    onion::{negotiate_version, HelloRequest, HelloResponse, PROTOCOL_VERSIONS},
+};
+
+const NONCE_LEN: usize = 12;
+/// The length of the authentication tag both suites append to a ciphertext.
+pub const TAG_LEN: usize = 16;
 
 pub trait SymmetricCipher {
-    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8>;
-    fn decrypt(&self, ciphertext: &[u8]) -> Vec<u8>;
+    /// Encrypts a frame. The nonce must never be reused with the same key.
+    fn encrypt(&self, nonce: u64, plaintext: &[u8]) -> Result<Vec<u8>, CipherError>;
+    /// Decrypts a frame that was encrypted with the given nonce.
+    fn decrypt(&self, nonce: u64, ciphertext: &[u8]) -> Result<Vec<u8>, CipherError>;
+}
+
+/// Expands a frame counter into the 96-bit nonce used by the AEAD ciphers.
+fn counter_nonce(counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// A symmetric cipher for one direction of a link, paired with the counter of the next frame.
+/// The counter is used as the nonce, so frames only decrypt in the order they were encrypted.
+pub struct CountedCipher<C: SymmetricCipher> {
+    cipher: C,
+    counter: u64,
+}
+impl<C: SymmetricCipher> CountedCipher<C> {
+    pub fn new(cipher: C) -> Self {
+        Self { cipher, counter: 0 }
+    }
+
+    /// Gets the counter of the next frame.
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    /// Encrypts the next frame.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        let next = self
+            .counter
+            .checked_add(1)
+            .ok_or(CipherError::CounterExhausted)?;
+        let ciphertext = self.cipher.encrypt(self.counter, plaintext)?;
+        self.counter = next;
+        Ok(ciphertext)
+    }
+
+    /// Gets a range of the keystream the frame with the given counter is encrypted with. Both
+    /// suites encrypt by XORing the plaintext with it, so it is the ciphertext of zeros. The
+    /// part a frame is encrypted with must never be sent, the part past its end is never used.
+    /// The counter does not advance.
+    pub fn keystream(&self, counter: u64, range: Range<usize>) -> Result<Vec<u8>, CipherError> {
+        let mut keystream = self.cipher.encrypt(counter, &vec![0; range.end])?;
+        keystream.truncate(range.end);
+        Ok(keystream.split_off(range.start))
+    }
+
+    /// Decrypts the next frame. The counter only advances if the frame authenticates.
+    pub fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        let next = self
+            .counter
+            .checked_add(1)
+            .ok_or(CipherError::CounterExhausted)?;
+        let plaintext = self.cipher.decrypt(self.counter, ciphertext)?;
+        self.counter = next;
+        Ok(plaintext)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CipherError {
+    /// The plaintext could not be encrypted.
+    EncryptionFailed,
+    /// The ciphertext is too short to hold an authentication tag.
+    Truncated,
+    /// The authentication tag did not match, the ciphertext has been tampered with
+    /// or was encrypted with a different nonce.
+    TagMismatch,
+    /// Every frame counter has been used, the link must be rekeyed.
+    CounterExhausted,
+}
+
+impl fmt::Display for CipherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherError::EncryptionFailed => write!(f, "encryption failed"),
+            CipherError::Truncated => write!(f, "ciphertext truncated"),
+            CipherError::TagMismatch => write!(f, "authentication tag mismatch"),
+            CipherError::CounterExhausted => write!(f, "frame counter exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for CipherError {}
+
+const CLIENT_TO_SERVER_INFO: &[u8] = b"ronion client to server";
+const SERVER_TO_CLIENT_INFO: &[u8] = b"ronion server to client";
+
+/// The AEAD algorithms a link can be encrypted with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherSuite {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+impl CipherSuite {
+    /// Every suite this library implements.
+    pub const ALL: [CipherSuite; 2] = [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305];
+
+    /// Gets the identifier of the suite on the wire.
+    pub fn id(self) -> u8 {
+        match self {
+            CipherSuite::Aes256Gcm => 0,
+            CipherSuite::ChaCha20Poly1305 => 1,
+        }
+    }
+
+    /// Gets the suite with the given wire identifier, if it is known.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CipherSuite::Aes256Gcm),
+            1 => Some(CipherSuite::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /// Gets every implemented suite, fastest first on this machine.
+    /// ChaCha20-Poly1305 is preferred on CPUs without AES instructions.
+    pub fn preferred() -> Vec<CipherSuite> {
+        if has_aes_instructions() {
+            vec![CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305]
+        } else {
+            vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm]
+        }
+    }
+
+    /// Picks the first suite offered by the client that is also supported, if any.
+    /// param offered: The suites of the HelloRequest, in the client's order of preference
+    /// param supported: The suites the server is willing to use
+    pub fn negotiate(offered: &[CipherSuite], supported: &[CipherSuite]) -> Option<CipherSuite> {
+        offered
+            .iter()
+            .find(|suite| supported.contains(suite))
+            .copied()
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn has_aes_instructions() -> bool {
+    std::arch::is_x86_feature_detected!("aes")
+}
+
+#[cfg(target_arch = "aarch64")]
+fn has_aes_instructions() -> bool {
+    std::arch::is_aarch64_feature_detected!("aes")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn has_aes_instructions() -> bool {
+    false
+}
+
+/// The outcome of the Hello handshake. It is bound into the link keys, so a tampered
+/// negotiation leaves the peers with keys that can't decrypt each other's frames.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Negotiation {
+    /// The protocol versions offered in the HelloRequest.
+    pub offered_versions: RangeInclusive<u8>,
+    /// The suites offered in the HelloRequest.
+    pub offered_suites: Vec<CipherSuite>,
+    /// The protocol version selected in the HelloResponse.
+    pub version: u8,
+    /// The suite selected in the HelloResponse.
+    pub cipher_suite: CipherSuite,
+    /// Whether the HelloRequest asked for cells.
+    pub offered_cells: bool,
+    /// Whether the link is split into cells.
+    pub cells: bool,
+}
+impl Negotiation {
+    /// Picks the parameters of a link from the offers of a HelloRequest. The link is only
+    /// split into cells if both peers want them.
+    /// param hello_req: The HelloRequest of the peer
+    /// param cells: Whether this node wants cells on the link
+    pub fn accept(hello_req: &HelloRequest, cells: bool) -> Result<Self, NegotiationError> {
+        let version =
+            negotiate_version(&hello_req.versions).ok_or(NegotiationError::NoCommonVersion)?;
+        let cipher_suite = CipherSuite::negotiate(&hello_req.cipher_suites, &CipherSuite::ALL)
+            .ok_or(NegotiationError::NoCommonCipherSuite)?;
+        let cells = version >= CELL_VERSION && hello_req.cells && cells;
+        Ok(Self {
+            offered_versions: hello_req.versions.clone(),
+            offered_suites: hello_req.cipher_suites.clone(),
+            version,
+            cipher_suite,
+            offered_cells: hello_req.cells,
+            cells,
+        })
+    }
+
+    /// Checks the parameters picked in a HelloResponse against what HelloRequest::new
+    /// offers. Returns None if the peer picked a version or suite that was never offered,
+    /// or cells that were not asked for.
+    /// param hello_resp: The HelloResponse of the peer
+    /// param offered_cells: Whether the HelloRequest asked for cells
+    pub fn confirm(hello_resp: &HelloResponse, offered_cells: bool) -> Option<Self> {
+        let offered_suites = CipherSuite::preferred();
+        if !PROTOCOL_VERSIONS.contains(&hello_resp.version)
+            || !offered_suites.contains(&hello_resp.cipher_suite)
+            || (hello_resp.cells && !offered_cells)
+        {
+            return None;
+        }
+
+        Some(Self {
+            offered_versions: PROTOCOL_VERSIONS,
+            offered_suites,
+            version: hello_resp.version,
+            cipher_suite: hello_resp.cipher_suite,
+            offered_cells,
+            cells: hello_resp.cells,
+        })
+    }
+
+    fn transcript(&self) -> Vec<u8> {
+        let mut transcript = vec![
+            *self.offered_versions.start(),
+            *self.offered_versions.end(),
+            self.version,
+            self.offered_suites.len() as u8,
+        ];
+        transcript.extend(self.offered_suites.iter().map(|suite| suite.id()));
+        transcript.push(self.cipher_suite.id());
+        if self.version >= CELL_VERSION {
+            transcript.extend_from_slice(&[self.offered_cells as u8, self.cells as u8]);
+        }
+        transcript
+    }
+}
+
+/// The symmetric ciphers of one side of a link, one for each direction.
+#[derive(Clone)]
+pub struct LinkCiphers {
+    pub send: SuiteCipher,
+    pub recv: SuiteCipher,
+}
+
+/// Runs HKDF over the shared secret, salted with the handshake transcript,
+/// returning the (client to server, server to client) keys.
+/// param shared_secret: The X25519 shared secret
+/// param client_public: The client's public key as sent in the HelloRequest
+/// param server_public: The server's signed public key as sent in the HelloResponse
+/// param negotiation: The negotiated parameters of the link
+fn derive_keys(
+    shared_secret: SharedSecret,
+    client_public: &[u8; 32],
+    server_public: &[u8; 96],
+    negotiation: &Negotiation,
+) -> ([u8; 32], [u8; 32]) {
+    let mut transcript = Vec::with_capacity(128);
+    transcript.extend_from_slice(client_public);
+    transcript.extend_from_slice(server_public);
+    transcript.extend(negotiation.transcript());
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&transcript), shared_secret.as_bytes());
+    let mut client_to_server = [0u8; 32];
+    let mut server_to_client = [0u8; 32];
+    hkdf.expand(CLIENT_TO_SERVER_INFO, &mut client_to_server)
+        .expect("32 bytes is a valid hkdf output length");
+    hkdf.expand(SERVER_TO_CLIENT_INFO, &mut server_to_client)
+        .expect("32 bytes is a valid hkdf output length");
+
+    (client_to_server, server_to_client)
 }
 
 pub struct Aes256 {
@@ -29,31 +308,106 @@ impl Clone for Aes256 {
     }
 }
 impl SymmetricCipher for Aes256 {
-    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
-        let mut nonce = [0u8; 12];
-        OsRng {}.fill_bytes(&mut nonce);
+    fn encrypt(&self, nonce: u64, plaintext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        self.aes
+            .encrypt(Nonce::from_slice(&counter_nonce(nonce)), plaintext)
+            .map_err(|_| CipherError::EncryptionFailed)
+    }
 
-        let mut ciphertext = self
-            .aes
-            .encrypt(Nonce::from_slice(&nonce), plaintext)
-            .expect("encryption failed");
+    fn decrypt(&self, nonce: u64, ciphertext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        if ciphertext.len() < TAG_LEN {
+            return Err(CipherError::Truncated);
+        }
 
-        ciphertext.extend(nonce.iter());
+        self.aes
+            .decrypt(Nonce::from_slice(&counter_nonce(nonce)), ciphertext)
+            .map_err(|_| CipherError::TagMismatch)
+    }
+}
 
-        ciphertext
+pub struct ChaCha20 {
+    chacha: Arc<ChaCha20Poly1305>,
+}
+impl ChaCha20 {
+    fn new(key: [u8; 32]) -> ChaCha20 {
+        let chacha = Arc::new(ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(
+            &key,
+        )));
+        Self { chacha }
+    }
+}
+impl Clone for ChaCha20 {
+    fn clone(&self) -> Self {
+        let chacha = self.chacha.clone();
+        Self { chacha }
+    }
+}
+impl SymmetricCipher for ChaCha20 {
+    fn encrypt(&self, nonce: u64, plaintext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        self.chacha
+            .encrypt(
+                chacha20poly1305::Nonce::from_slice(&counter_nonce(nonce)),
+                plaintext,
+            )
+            .map_err(|_| CipherError::EncryptionFailed)
     }
 
-    fn decrypt(&self, ciphertext: &[u8]) -> Vec<u8> {
-        let full_len = ciphertext.len();
-        let nonce = &ciphertext[full_len - 12..full_len];
-        let ciphertext = &ciphertext[..full_len - 12];
+    fn decrypt(&self, nonce: u64, ciphertext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        if ciphertext.len() < TAG_LEN {
+            return Err(CipherError::Truncated);
+        }
 
-        let plaintext = self
-            .aes
-            .decrypt(Nonce::from_slice(&nonce), ciphertext)
-            .expect("decryption failed");
+        self.chacha
+            .decrypt(
+                chacha20poly1305::Nonce::from_slice(&counter_nonce(nonce)),
+                ciphertext,
+            )
+            .map_err(|_| CipherError::TagMismatch)
+    }
+}
+
+/// The cipher of a link, of whichever suite was negotiated in the handshake.
+#[derive(Clone)]
+pub enum SuiteCipher {
+    Aes256(Aes256),
+    ChaCha20(ChaCha20),
+}
+impl SuiteCipher {
+    fn new(suite: CipherSuite, key: [u8; 32]) -> Self {
+        match suite {
+            CipherSuite::Aes256Gcm => SuiteCipher::Aes256(Aes256::new(key)),
+            CipherSuite::ChaCha20Poly1305 => SuiteCipher::ChaCha20(ChaCha20::new(key)),
+        }
+    }
+}
+impl SymmetricCipher for SuiteCipher {
+    fn encrypt(&self, nonce: u64, plaintext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        match self {
+            SuiteCipher::Aes256(cipher) => cipher.encrypt(nonce, plaintext),
+            SuiteCipher::ChaCha20(cipher) => cipher.encrypt(nonce, plaintext),
+        }
+    }
+
+    fn decrypt(&self, nonce: u64, ciphertext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        match self {
+            SuiteCipher::Aes256(cipher) => cipher.decrypt(nonce, ciphertext),
+            SuiteCipher::ChaCha20(cipher) => cipher.decrypt(nonce, ciphertext),
+        }
+    }
+}
 
-        plaintext
+/// Reasons the Hello handshake can fail, sent to the peer in a Close.
+#[derive(Debug, PartialEq)]
+pub enum NegotiationError {
+    NoCommonVersion,
+    NoCommonCipherSuite,
+}
+impl fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NegotiationError::NoCommonVersion => write!(f, "no common protocol version"),
+            NegotiationError::NoCommonCipherSuite => write!(f, "no common cipher suite"),
+        }
     }
 }
 
@@ -89,11 +443,22 @@ impl ServerSecret {
         target
     }
 
-    /// Combines secret and peer public key into a SymmetricCipher.
-    pub fn symmetric_cipher(self, peer_public: [u8; 32]) -> Aes256 {
-        let peer_public = PublicKey::from(peer_public);
+    /// Combines secret and peer public key into the ciphers of a link.
+    /// param peer_public: The public key of the HelloRequest
+    /// param negotiation: The negotiated parameters of the link
+    pub fn symmetric_ciphers(
+        self,
+        peer_public: [u8; 32],
+        negotiation: &Negotiation,
+    ) -> LinkCiphers {
+        let public_key = self.public_key();
         let shared_secret = self.secret.diffie_hellman(&PublicKey::from(peer_public));
-        Aes256::new(shared_secret.to_bytes())
+        let (client_to_server, server_to_client) =
+            derive_keys(shared_secret, &peer_public, &public_key, negotiation);
+        LinkCiphers {
+            send: SuiteCipher::new(negotiation.cipher_suite, server_to_client),
+            recv: SuiteCipher::new(negotiation.cipher_suite, client_to_server),
+        }
     }
 }
 
@@ -101,6 +466,11 @@ impl ServerSecret {
 pub struct ServerCrypto {
     keypair: Keypair,
 }
+impl Default for ServerCrypto {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl ServerCrypto {
     /// Creates a ServerCrypto with a random signing keypair.
     pub fn new() -> Self {
@@ -125,10 +495,16 @@ impl ServerCrypto {
         self.keypair.public.to_bytes()
     }
 
+    /// Signs the data with the signing keypair, so anyone with the signing public key can
+    /// check where it came from.
+    pub fn sign(&self, data: &[u8]) -> [u8; 64] {
+        self.keypair.sign(data).to_bytes()
+    }
+
     /// Generate a new secret.
     pub fn gen_secret(&self) -> ServerSecret {
         ServerSecret {
-            secret: EphemeralSecret::new(&mut OsRng {}),
+            secret: EphemeralSecret::new(OsRng {}),
             keypair: Keypair::from_bytes(&self.keypair.to_bytes()).unwrap(),
         }
     }
@@ -145,8 +521,15 @@ impl ClientSecret {
         PublicKey::from(&self.secret).to_bytes()
     }
 
-    ///Combines secret and public key of peer to a SymmetricCipher.
-    pub fn symmetric_cipher(self, peer_public: [u8; 96]) -> Result<Aes256, SignatureError> {
+    /// Verifies the signed public key of the peer and combines it with the secret into
+    /// the ciphers of a link.
+    /// param peer_public: The signed public key of the HelloResponse
+    /// param negotiation: The negotiated parameters of the link
+    pub fn symmetric_ciphers(
+        self,
+        peer_public: [u8; 96],
+        negotiation: &Negotiation,
+    ) -> Result<LinkCiphers, SignatureError> {
         let key: [u8; 32] = peer_public[0..32].try_into().unwrap();
         let signature =
             Signature::from_bytes(&peer_public[32..96]).map_err(|_| SignatureError::InvalidData)?;
@@ -154,9 +537,14 @@ impl ClientSecret {
             .verify(&key, &signature)
             .map_err(|_| SignatureError::InvalidSignature)?;
 
+        let public_key = self.public_key();
         let shared_secret = self.secret.diffie_hellman(&PublicKey::from(key));
-        let cipher = Aes256::new(shared_secret.to_bytes());
-        Ok(cipher)
+        let (client_to_server, server_to_client) =
+            derive_keys(shared_secret, &public_key, &peer_public, negotiation);
+        Ok(LinkCiphers {
+            send: SuiteCipher::new(negotiation.cipher_suite, client_to_server),
+            recv: SuiteCipher::new(negotiation.cipher_suite, server_to_client),
+        })
     }
 }
 
@@ -168,7 +556,7 @@ impl ClientCrypto {
     /// Creates a ClientCrypto from the peer's signing public key.
     pub fn new(signing_public: &[u8; 32]) -> Result<Self, SigningPublicKeyError> {
         let verifier = ed25519_dalek::PublicKey::from_bytes(signing_public)
-            .map_err(|e| SigningPublicKeyError::InvalidData)?;
+            .map_err(|_| SigningPublicKeyError::InvalidData)?;
         Ok(Self { verifier })
     }
 
@@ -176,7 +564,360 @@ impl ClientCrypto {
     pub fn gen_secret(&self) -> ClientSecret {
         ClientSecret {
             verifier: self.verifier,
-            secret: EphemeralSecret::new(&mut OsRng {}),
+            secret: EphemeralSecret::new(OsRng {}),
+        }
+    }
+
+    /// Checks that the peer signed the data with the given signature.
+    pub fn verify(&self, data: &[u8], signature: &[u8; 64]) -> Result<(), SignatureError> {
+        let signature =
+            Signature::from_bytes(signature).map_err(|_| SignatureError::InvalidData)?;
+        self.verifier
+            .verify(data, &signature)
+            .map_err(|_| SignatureError::InvalidSignature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::RngCore;
+
+    use super::*;
+    use crate::protocol::onion::ClientType;
+
+    fn test_cipher() -> Aes256 {
+        let mut key = [0u8; 32];
+        OsRng {}.fill_bytes(&mut key);
+        Aes256::new(key)
+    }
+
+    #[test]
+    fn decrypt_can_read_output_of_encrypt() {
+        let cipher = test_cipher();
+
+        let ciphertext = cipher.encrypt(7, b"onion").unwrap();
+
+        assert_eq!(cipher.decrypt(7, &ciphertext).unwrap(), b"onion");
+    }
+
+    #[test]
+    fn encrypt_adds_only_the_tag() {
+        let cipher = test_cipher();
+
+        let ciphertext = cipher.encrypt(0, b"onion").unwrap();
+
+        assert_eq!(ciphertext.len(), b"onion".len() + TAG_LEN);
+    }
+
+    #[test]
+    fn decrypt_with_other_nonce() {
+        let cipher = test_cipher();
+        let ciphertext = cipher.encrypt(0, b"onion").unwrap();
+
+        let err = cipher.decrypt(1, &ciphertext).unwrap_err();
+
+        assert_eq!(err, CipherError::TagMismatch);
+    }
+
+    #[test]
+    fn counted_cipher_rejects_replay() {
+        let mut sender = CountedCipher::new(test_cipher());
+        let mut receiver = CountedCipher::new(sender.cipher.clone());
+        let frame = sender.seal(b"onion").unwrap();
+
+        receiver.open(&frame).unwrap();
+        let err = receiver.open(&frame).unwrap_err();
+
+        assert_eq!(err, CipherError::TagMismatch);
+        assert_eq!(receiver.counter(), 1);
+    }
+
+    #[test]
+    fn counted_cipher_rejects_reordering() {
+        let mut sender = CountedCipher::new(test_cipher());
+        let mut receiver = CountedCipher::new(sender.cipher.clone());
+        let first = sender.seal(b"first").unwrap();
+        let second = sender.seal(b"second").unwrap();
+
+        assert_eq!(
+            receiver.open(&second).unwrap_err(),
+            CipherError::TagMismatch
+        );
+        assert_eq!(receiver.open(&first).unwrap(), b"first");
+        assert_eq!(receiver.open(&second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn keystream_continues_past_the_end_of_a_frame() {
+        let mut sender = CountedCipher::new(test_cipher());
+        let plaintext = b"onion".to_vec();
+        let keystream = sender.keystream(0, 0..8).unwrap();
+
+        let frame = sender.seal(&plaintext).unwrap();
+
+        let xored: Vec<u8> = plaintext
+            .iter()
+            .zip(&keystream)
+            .map(|(a, b)| a ^ b)
+            .collect();
+        assert_eq!(frame[..plaintext.len()], xored[..]);
+        assert_eq!(sender.keystream(0, 5..8).unwrap(), keystream[5..]);
+        assert_eq!(sender.counter(), 1);
+    }
+
+    #[test]
+    fn counted_cipher_exhausted() {
+        let mut sender = CountedCipher::new(test_cipher());
+        sender.counter = u64::MAX;
+
+        assert_eq!(
+            sender.seal(b"onion").unwrap_err(),
+            CipherError::CounterExhausted
+        );
+    }
+
+    #[test]
+    fn decrypt_tampered_ciphertext() {
+        let cipher = test_cipher();
+        let mut ciphertext = cipher.encrypt(0, b"onion").unwrap();
+        ciphertext[0] ^= 1;
+
+        let err = cipher.decrypt(0, &ciphertext).unwrap_err();
+
+        assert_eq!(err, CipherError::TagMismatch);
+    }
+
+    fn test_negotiation(cipher_suite: CipherSuite) -> Negotiation {
+        Negotiation {
+            offered_versions: PROTOCOL_VERSIONS,
+            offered_suites: CipherSuite::ALL.to_vec(),
+            version: *PROTOCOL_VERSIONS.end(),
+            cipher_suite,
+            offered_cells: true,
+            cells: true,
+        }
+    }
+
+    fn test_link() -> (LinkCiphers, LinkCiphers) {
+        let negotiation = test_negotiation(CipherSuite::Aes256Gcm);
+        test_link_negotiated(&negotiation, &negotiation)
+    }
+
+    fn test_link_negotiated(
+        client_negotiation: &Negotiation,
+        server_negotiation: &Negotiation,
+    ) -> (LinkCiphers, LinkCiphers) {
+        let server_crypto = ServerCrypto::new();
+        let server_secret = server_crypto.gen_secret();
+        let client_secret = ClientCrypto::new(&server_crypto.signing_public())
+            .unwrap()
+            .gen_secret();
+
+        let client_public = client_secret.public_key();
+        let client = client_secret
+            .symmetric_ciphers(server_secret.public_key(), client_negotiation)
+            .unwrap();
+        let server = server_secret.symmetric_ciphers(client_public, server_negotiation);
+
+        (client, server)
+    }
+
+    #[test]
+    fn link_ciphers_match_across_link() {
+        let (client, server) = test_link();
+
+        let to_server = client.send.encrypt(0, b"request").unwrap();
+        let to_client = server.send.encrypt(0, b"response").unwrap();
+
+        assert_eq!(server.recv.decrypt(0, &to_server).unwrap(), b"request");
+        assert_eq!(client.recv.decrypt(0, &to_client).unwrap(), b"response");
+    }
+
+    #[test]
+    fn link_ciphers_are_direction_separated() {
+        let (client, server) = test_link();
+
+        let to_server = client.send.encrypt(0, b"request").unwrap();
+
+        assert_eq!(
+            client.recv.decrypt(0, &to_server).unwrap_err(),
+            CipherError::TagMismatch
+        );
+        assert_eq!(
+            server.send.decrypt(0, &to_server).unwrap_err(),
+            CipherError::TagMismatch
+        );
+    }
+
+    #[test]
+    fn tampered_server_public_key_is_rejected() {
+        let server_crypto = ServerCrypto::new();
+        let client_secret = ClientCrypto::new(&server_crypto.signing_public())
+            .unwrap()
+            .gen_secret();
+        let mut server_public = server_crypto.gen_secret().public_key();
+        server_public[0] ^= 1;
+
+        assert!(matches!(
+            client_secret
+                .symmetric_ciphers(server_public, &test_negotiation(CipherSuite::Aes256Gcm)),
+            Err(SignatureError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn decrypt_truncated_ciphertext() {
+        let cipher = test_cipher();
+
+        let err = cipher.decrypt(0, &[0u8; TAG_LEN - 1]).unwrap_err();
+
+        assert_eq!(err, CipherError::Truncated);
+    }
+
+    #[test]
+    fn chacha_decrypt_can_read_output_of_encrypt() {
+        let mut key = [0u8; 32];
+        OsRng {}.fill_bytes(&mut key);
+        let cipher = ChaCha20::new(key);
+
+        let ciphertext = cipher.encrypt(7, b"onion").unwrap();
+
+        assert_eq!(ciphertext.len(), b"onion".len() + TAG_LEN);
+        assert_eq!(cipher.decrypt(7, &ciphertext).unwrap(), b"onion");
+        assert_eq!(
+            cipher.decrypt(8, &ciphertext).unwrap_err(),
+            CipherError::TagMismatch
+        );
+    }
+
+    #[test]
+    fn chacha_link_ciphers_match_across_link() {
+        let negotiation = test_negotiation(CipherSuite::ChaCha20Poly1305);
+        let (client, server) = test_link_negotiated(&negotiation, &negotiation);
+
+        let to_server = client.send.encrypt(0, b"request").unwrap();
+
+        assert!(matches!(client.send, SuiteCipher::ChaCha20(_)));
+        assert_eq!(server.recv.decrypt(0, &to_server).unwrap(), b"request");
+    }
+
+    #[test]
+    fn downgraded_negotiation_breaks_link() {
+        let client_negotiation = test_negotiation(CipherSuite::Aes256Gcm);
+        let server_negotiation = Negotiation {
+            offered_suites: vec![CipherSuite::Aes256Gcm],
+            ..client_negotiation.clone()
+        };
+        let (client, server) = test_link_negotiated(&client_negotiation, &server_negotiation);
+
+        let to_server = client.send.encrypt(0, b"request").unwrap();
+
+        assert_eq!(
+            server.recv.decrypt(0, &to_server).unwrap_err(),
+            CipherError::TagMismatch
+        );
+    }
+
+    #[test]
+    fn negotiate_picks_first_supported_offer() {
+        let offered = [CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm];
+
+        assert_eq!(
+            CipherSuite::negotiate(&offered, &CipherSuite::ALL),
+            Some(CipherSuite::ChaCha20Poly1305)
+        );
+        assert_eq!(
+            CipherSuite::negotiate(&offered, &[CipherSuite::Aes256Gcm]),
+            Some(CipherSuite::Aes256Gcm)
+        );
+        assert_eq!(CipherSuite::negotiate(&[], &CipherSuite::ALL), None);
+    }
+
+    #[test]
+    fn confirm_rejects_unoffered_parameters() {
+        let hello_resp = |cipher_suite, version| HelloResponse {
+            signed_public_key: [0u8; 96],
+            cipher_suite,
+            version,
+            cells: true,
+        };
+        let version = *PROTOCOL_VERSIONS.end();
+        let resp = hello_resp(CipherSuite::Aes256Gcm, version);
+
+        assert!(Negotiation::confirm(&resp, true).is_some());
+        assert!(
+            Negotiation::confirm(&hello_resp(CipherSuite::Aes256Gcm, version + 1), true).is_none()
+        );
+        assert!(Negotiation::confirm(&resp, false).is_none());
+    }
+
+    #[test]
+    fn accept_uses_cells_only_if_both_peers_want_them() {
+        let hello_req = |cells| HelloRequest::new(ClientType::Relay, [0u8; 32]).with_cells(cells);
+        let mut old_req = hello_req(true);
+        old_req.versions = 1..=CELL_VERSION - 1;
+
+        assert!(Negotiation::accept(&hello_req(true), true).unwrap().cells);
+        assert!(!Negotiation::accept(&hello_req(true), false).unwrap().cells);
+        assert!(!Negotiation::accept(&hello_req(false), true).unwrap().cells);
+        assert!(!Negotiation::accept(&old_req, true).unwrap().cells);
+    }
+
+    #[test]
+    fn cells_dropped_from_negotiation_break_link() {
+        let client_negotiation = test_negotiation(CipherSuite::Aes256Gcm);
+        let server_negotiation = Negotiation {
+            cells: false,
+            ..client_negotiation.clone()
+        };
+        let (client, server) = test_link_negotiated(&client_negotiation, &server_negotiation);
+
+        let to_server = client.send.encrypt(0, b"request").unwrap();
+
+        assert_eq!(
+            server.recv.decrypt(0, &to_server).unwrap_err(),
+            CipherError::TagMismatch
+        );
+    }
+
+    #[test]
+    fn accept_picks_newest_common_version() {
+        let mut hello_req = HelloRequest::new(ClientType::Relay, [0u8; 32]);
+        hello_req.versions = 0..=u8::MAX;
+
+        let negotiation = Negotiation::accept(&hello_req, true).unwrap();
+
+        assert_eq!(negotiation.version, *PROTOCOL_VERSIONS.end());
+        assert_eq!(negotiation.offered_versions, 0..=u8::MAX);
+    }
+
+    #[test]
+    fn accept_without_common_version() {
+        let mut hello_req = HelloRequest::new(ClientType::Relay, [0u8; 32]);
+        hello_req.versions = PROTOCOL_VERSIONS.end() + 1..=u8::MAX;
+
+        assert_eq!(
+            Negotiation::accept(&hello_req, true).unwrap_err(),
+            NegotiationError::NoCommonVersion
+        );
+    }
+
+    #[test]
+    fn accept_without_common_cipher_suite() {
+        let mut hello_req = HelloRequest::new(ClientType::Relay, [0u8; 32]);
+        hello_req.cipher_suites.clear();
+
+        assert_eq!(
+            Negotiation::accept(&hello_req, true).unwrap_err(),
+            NegotiationError::NoCommonCipherSuite
+        );
+    }
+
+    #[test]
+    fn cipher_suite_ids_roundtrip() {
+        for suite in CipherSuite::ALL {
+            assert_eq!(CipherSuite::from_id(suite.id()), Some(suite));
         }
+        assert_eq!(CipherSuite::from_id(0xFF), None);
     }
 }
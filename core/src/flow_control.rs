@@ -0,0 +1,180 @@
+use std::{
+    future::poll_fn,
+    sync::Mutex,
+    task::{Poll, Waker},
+};
+
+use crate::protocol::error::ProtocolError;
+
+/// The Data messages a circuit may carry in each direction before credit arrives.
+pub const CIRCUIT_WINDOW: u32 = 1000;
+/// The Data messages delivered on a circuit for every circuit level Sendme.
+pub const CIRCUIT_SENDME_INCREMENT: u32 = 100;
+/// The Data messages a stream may carry in each direction before credit arrives.
+pub const STREAM_WINDOW: u32 = 500;
+/// The Data messages delivered on a stream for every stream level Sendme.
+pub const STREAM_SENDME_INCREMENT: u32 = 50;
+
+/// Counts the Data messages that may still be sent before the peer sends credit.
+/// Shared by the task that sends and the task that receives the Sendmes.
+pub struct SendWindow {
+    state: Mutex<SendState>,
+    window: u32,
+    increment: u32,
+}
+
+struct SendState {
+    available: u32,
+    wakers: Vec<Waker>,
+}
+
+impl SendWindow {
+    pub fn new(window: u32, increment: u32) -> Self {
+        Self {
+            state: Mutex::new(SendState {
+                available: window,
+                wakers: Vec::new(),
+            }),
+            window,
+            increment,
+        }
+    }
+
+    pub fn circuit() -> Self {
+        Self::new(CIRCUIT_WINDOW, CIRCUIT_SENDME_INCREMENT)
+    }
+
+    pub fn stream() -> Self {
+        Self::new(STREAM_WINDOW, STREAM_SENDME_INCREMENT)
+    }
+
+    /// Takes room for one Data message if the window is not exhausted.
+    pub fn try_reserve(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.available == 0 {
+            return false;
+        }
+        state.available -= 1;
+        true
+    }
+
+    /// Waits until the window has room for one Data message and takes it.
+    pub async fn reserve(&self) {
+        poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.available == 0 {
+                state.wakers.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            state.available -= 1;
+            Poll::Ready(())
+        })
+        .await
+    }
+
+    /// Adds the credit of a Sendme, waking everyone waiting for room. A peer that sends
+    /// credit for data that was never sent violates the protocol.
+    pub fn credit(&self) -> Result<(), ProtocolError> {
+        let mut state = self.state.lock().unwrap();
+        if state.available + self.increment > self.window {
+            return Err(ProtocolError::FlowControlViolation);
+        }
+        state.available += self.increment;
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Returns the Data messages that may be sent right now.
+    pub fn available(&self) -> u32 {
+        self.state.lock().unwrap().available
+    }
+}
+
+/// Counts the Data messages delivered from the peer, deciding when to send it credit.
+pub struct RecvWindow {
+    remaining: u32,
+    window: u32,
+    increment: u32,
+}
+
+impl RecvWindow {
+    pub fn new(window: u32, increment: u32) -> Self {
+        Self {
+            remaining: window,
+            window,
+            increment,
+        }
+    }
+
+    pub fn circuit() -> Self {
+        Self::new(CIRCUIT_WINDOW, CIRCUIT_SENDME_INCREMENT)
+    }
+
+    pub fn stream() -> Self {
+        Self::new(STREAM_WINDOW, STREAM_SENDME_INCREMENT)
+    }
+
+    /// Counts a Data message that was passed on, returning true if a Sendme is due. Credit
+    /// is only given for delivered data, so a slow reader holds back its peer.
+    pub fn deliver(&mut self) -> bool {
+        self.remaining -= 1;
+        if self.remaining <= self.window - self.increment {
+            self.remaining += self.increment;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor, FutureExt};
+
+    use super::*;
+
+    #[test]
+    fn send_window_is_exhausted_until_credit() {
+        let window = SendWindow::new(2, 2);
+
+        assert!(window.try_reserve());
+        assert!(window.try_reserve());
+        assert!(!window.try_reserve());
+
+        window.credit().unwrap();
+        assert_eq!(window.available(), 2);
+    }
+
+    #[test]
+    fn send_window_rejects_unearned_credit() {
+        let window = SendWindow::new(4, 2);
+        window.try_reserve();
+
+        assert!(matches!(
+            window.credit(),
+            Err(ProtocolError::FlowControlViolation)
+        ));
+    }
+
+    #[test]
+    fn reserve_waits_for_credit() {
+        let window = SendWindow::new(1, 1);
+        executor::block_on(window.reserve());
+
+        let mut reserve = Box::pin(window.reserve());
+        assert!((&mut reserve).now_or_never().is_none());
+
+        window.credit().unwrap();
+        assert!(reserve.now_or_never().is_some());
+    }
+
+    #[test]
+    fn recv_window_sends_credit_every_increment() {
+        let mut window = RecvWindow::new(4, 2);
+
+        let sendmes: Vec<bool> = (0..6).map(|_| window.deliver()).collect();
+
+        assert_eq!(sendmes, [false, true, false, true, false, true]);
+    }
+}
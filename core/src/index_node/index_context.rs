@@ -1,19 +1,166 @@
-use crate::{protocol::onion::Relay, crypto::{ServerCrypto}, uid_generator::UIDGenerator};
+use futures::channel::mpsc::{self, Receiver, Sender};
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Result,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    crypto::ServerCrypto,
+    protocol::{directory::unix_time, onion::Relay},
+    uid_generator::UIDGenerator,
+};
+
+use super::{
+    index_node::{INDEX_MAX_FRAME_LEN, RELAY_TTL},
+    relay_store::{self, StoredRelay},
+};
 
 pub struct IndexContext {
     pub available_relays: Vec<Relay>,
+    /// When each relay in available_relays last pinged, by relay ID.
+    pub last_seen: HashMap<u32, Instant>,
     pub circ_id_generator: UIDGenerator,
     pub relay_id_generator: UIDGenerator,
-    pub crypto: ServerCrypto
+    pub crypto: ServerCrypto,
+    pub max_frame_len: u32,
+    /// Whether the index node wants its links split into cells.
+    pub cells: bool,
+    /// How long a relay stays listed after its last ping.
+    pub relay_ttl: Duration,
+    /// The relays read from the state file that have not pinged since, by relay ID.
+    pub stale: HashSet<u32>,
+    /// Where the relays are kept across restarts, if anywhere.
+    pub state_file: Option<PathBuf>,
+    /// Whether the relays changed since they were last saved to the state file.
+    pub unsaved: bool,
+    /// Wakes the task saving the state file for changes that should not wait for the next periodic save.
+    save_trigger: Sender<()>,
+    /// The wakeups of the task saving the state file, until the task takes them.
+    pub save_requests: Option<Receiver<()>>,
 }
 
 impl IndexContext {
     pub fn new(keypair_bytes: [u8; 64]) -> Self {
+        let (save_trigger, save_requests) = mpsc::channel(1);
         IndexContext {
             available_relays: Vec::new(),
+            last_seen: HashMap::new(),
             circ_id_generator: UIDGenerator::new(10),
             relay_id_generator: UIDGenerator::new(10),
             crypto: ServerCrypto::from_bytes(&keypair_bytes).expect("invalid keypair"),
+            max_frame_len: INDEX_MAX_FRAME_LEN,
+            cells: true,
+            relay_ttl: RELAY_TTL,
+            stale: HashSet::new(),
+            state_file: None,
+            unsaved: false,
+            save_trigger,
+            save_requests: Some(save_requests),
         }
     }
+
+    // Returns whether the relay with the given address and key is listed and pinged since the index node started
+    // param addr: The socket address of the relay
+    // param pub_key: The signing public key of the relay
+    pub fn is_confirmed(&self, addr: SocketAddr, pub_key: &[u8; 32]) -> bool {
+        self.available_relays.iter().any(|relay| {
+            relay.addr == addr && relay.pub_key == *pub_key && !self.stale.contains(&relay.id)
+        })
+    }
+
+    // Reads the relays kept in the given state file and keeps them there from now on. The relays read are stale
+    // until they ping again, and expire like any other relay once their TTL runs out.
+    // param path: The path of the state file
+    pub fn load_relays(&mut self, path: PathBuf) -> Result<()> {
+        let (now, unix_now) = (Instant::now(), unix_time());
+
+        for stored in relay_store::load(&path)? {
+            let age = Duration::from_secs(unix_now.saturating_sub(stored.last_seen));
+            let taken = self
+                .available_relays
+                .iter()
+                .any(|relay| relay.id == stored.relay.id || relay.addr == stored.relay.addr);
+            if taken || age > self.relay_ttl {
+                continue;
+            }
+            // An Instant can't lie before the start of the process on some platforms, in which case the relay
+            // counts as just seen. It still has to ping within the TTL to be listed.
+            let last_seen = now.checked_sub(age).unwrap_or(now);
+
+            let id = stored.relay.id;
+            self.relay_id_generator.take_uid(id);
+            self.last_seen.insert(id, last_seen);
+            self.stale.insert(id);
+            self.available_relays.push(stored.relay);
+        }
+
+        self.state_file = Some(path);
+        Ok(())
+    }
+
+    // Marks the relays as changed since they were last saved. Refreshed ping times wait for the next periodic save,
+    // while relays that were added, dropped, changed or confirmed are saved right away.
+    // param urgent: Whether to save without waiting for the next periodic save
+    pub fn mark_unsaved(&mut self, urgent: bool) {
+        self.unsaved = true;
+        if urgent {
+            // A full queue means a save is due already.
+            let _ = self.save_trigger.try_send(());
+        }
+    }
+
+    // Takes the relays to write to the state file, if there is one and they changed since they were last saved
+    pub fn take_unsaved(&mut self) -> Option<(PathBuf, Vec<StoredRelay>)> {
+        let path = self.state_file.clone().filter(|_| self.unsaved)?;
+        self.unsaved = false;
+        let (now, unix_now) = (Instant::now(), unix_time());
+
+        let relays = self
+            .available_relays
+            .iter()
+            .map(|relay| {
+                let age = self
+                    .last_seen
+                    .get(&relay.id)
+                    .map_or(0, |seen| now.saturating_duration_since(*seen).as_secs());
+                StoredRelay {
+                    relay: relay.clone(),
+                    last_seen: unix_now.saturating_sub(age),
+                }
+            })
+            .collect();
+        Some((path, relays))
+    }
+
+    // Drops the relays that have not pinged within the TTL, freeing their IDs for relays that register later.
+    // Returns whether any relay was dropped.
+    // param now: The time to measure the TTL from
+    pub fn expire_relays(&mut self, now: Instant) -> bool {
+        let listed = self.available_relays.len();
+        let (last_seen, stale, relay_id_generator, ttl) = (
+            &mut self.last_seen,
+            &mut self.stale,
+            &mut self.relay_id_generator,
+            self.relay_ttl,
+        );
+
+        self.available_relays.retain(|relay| {
+            let alive = last_seen
+                .get(&relay.id)
+                .is_some_and(|seen| now.saturating_duration_since(*seen) <= ttl);
+            if !alive {
+                println!("Expired relay: {} @ {:?}", relay.id, relay.addr);
+                last_seen.remove(&relay.id);
+                stale.remove(&relay.id);
+                relay_id_generator.clear_uid(relay.id);
+            }
+            alive
+        });
+
+        self.available_relays.len() != listed
+    }
 }
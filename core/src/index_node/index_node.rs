@@ -1,74 +1,226 @@
-use async_std::{
+use std::{
     io::{Error, ErrorKind, Result},
-    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
-    prelude::*,
-    sync::{Arc, Mutex},
-    task,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use crate::protocol::{
-    io::{RawOnionReader, RawOnionWriter},
-    onion::{Message, Onion, Relay, Target},
+use futures::{
+    channel::mpsc::Receiver,
+    executor,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    lock::Mutex,
+    StreamExt,
 };
 
-use super::index_context::IndexContext;
+use crate::{
+    crypto::{ClientCrypto, Negotiation},
+    protocol::{
+        challenge,
+        io::{RawOnionReader, RawOnionWriter},
+        onion::{
+            ClientType, HelloRequest, HelloResponse, Message, Onion, Relay, RelayPingRequest,
+            SignedDirectory, Target, DIRECTORY_VERSION, RELAY_CHALLENGE_VERSION,
+        },
+    },
+    runtime,
+    transport::{Listener, TcpTransport, Transport},
+};
+
+use super::{index_context::IndexContext, relay_store};
 
-pub struct IndexNode {
+/// The largest frame an index node accepts unless told otherwise. Index requests are
+/// small, so the limit is much lower than for relays.
+pub const INDEX_MAX_FRAME_LEN: u32 = 16 * 1024;
+/// How long an index node lists a relay after its last ping unless told otherwise.
+pub const RELAY_TTL: Duration = Duration::from_secs(90);
+/// How long an index node waits for the Hello handshake when it connects back to a relay.
+pub const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an index node waits for a relay to answer its challenge.
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often an index node saves the ping times of its relays to its state file. Other changes
+/// to its relays are saved right away.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct IndexNode<T: Transport = TcpTransport> {
     ip: IpAddr,
     port: u16,
     context: Arc<Mutex<IndexContext>>,
+    transport: T,
 }
 
 impl IndexNode {
-    // Returns a new IndexNode object
+    // Returns a new IndexNode object that listens over TCP
     // param ip: The IP address this index node should bind to
     // param port: The port this index node should listen on
     // param signing_key_pair: The signing key pair used to generate a cryptography context
     pub fn new(ip: IpAddr, port: u16, signing_key_pair: [u8; 64]) -> Self {
+        Self::with_transport(ip, port, signing_key_pair, TcpTransport)
+    }
+}
+
+impl<T: Transport> IndexNode<T> {
+    // Returns a new IndexNode object that listens over the given transport
+    // param ip: The IP address this index node should bind to
+    // param port: The port this index node should listen on
+    // param signing_key_pair: The signing key pair used to generate a cryptography context
+    // param transport: The transport to listen on
+    pub fn with_transport(ip: IpAddr, port: u16, signing_key_pair: [u8; 64], transport: T) -> Self {
         Self {
-            ip: ip,
-            port: port,
+            ip,
+            port,
             context: Arc::new(Mutex::new(IndexContext::new(signing_key_pair))),
+            transport,
         }
     }
 
+    // Sets the largest frame this index node accepts from its peers
+    // param max_frame_len: The maximum frame length in bytes
+    pub fn set_max_frame_len(&self, max_frame_len: u32) {
+        executor::block_on(self.context.lock()).max_frame_len = max_frame_len;
+    }
+
+    // Sets whether this index node wants its links split into cells, which hide the size of onions
+    // from anyone watching the links. Links only use them if both peers want them.
+    // param cells: Whether to use cells
+    pub fn set_cells(&self, cells: bool) {
+        executor::block_on(self.context.lock()).cells = cells;
+    }
+
+    // Sets how long a relay stays listed after its last ping. Relays that miss it are dropped
+    // from the relays handed out and their IDs are given to relays that register later.
+    // param relay_ttl: The time a relay has to ping again
+    pub fn set_relay_ttl(&self, relay_ttl: Duration) {
+        executor::block_on(self.context.lock()).relay_ttl = relay_ttl;
+    }
+
+    // Keeps the relays of this index node in the given state file, so consumers still get them after a
+    // restart. The relays read from it are handed out until their TTL runs out, but are stale until they
+    // ping again, and are challenged like new relays when they do.
+    // param path: The path of the state file, created once a relay registers
+    pub fn set_state_file(&self, path: impl Into<PathBuf>) -> Result<()> {
+        executor::block_on(self.context.lock()).load_relays(path.into())
+    }
+
     // Starts the IndexNode server, causing it to listen to the socket address specified in IndexNode::new()
     pub fn start(&self) {
         let socket = SocketAddr::new(self.ip, self.port);
         let listen_future = self.listen(socket);
 
-        task::block_on(listen_future); // bytte til async?
+        runtime::block_on(listen_future); // bytte til async?
+    }
+
+    // Binds the socket address specified in IndexNode::new() and serves it in the background,
+    // returning once the index node accepts connections
+    pub async fn spawn(self) -> Result<()> {
+        let listener = self
+            .transport
+            .listen(SocketAddr::new(self.ip, self.port))
+            .await?;
+
+        runtime::spawn(async move { self.serve(listener).await });
+        Ok(())
     }
 
     // Helper method for listening on a socket address and handling the incoming connections
     // param socket: The specified socket address to listen on
     async fn listen(&self, socket: SocketAddr) {
-        let listener = TcpListener::bind(socket)
+        let listener = self
+            .transport
+            .listen(socket)
             .await
             .expect("Failed to bind to socket");
 
-        let mut incoming = listener.incoming();
-        while let Some(stream) = incoming.next().await {
+        self.serve(listener).await
+    }
+
+    // Helper method for handling the incoming connections of a bound listener
+    // param listener: The listener to accept connections on
+    async fn serve(&self, listener: T::Listener) {
+        if let Some(requests) = self.context.lock().await.save_requests.take() {
+            runtime::spawn(Self::keep_saved(self.context.clone(), requests));
+        }
+
+        loop {
+            let (stream, peer_addr) = listener.accept().await.expect("Failed to read from stream");
             let context = self.context.clone();
-            let handler_future = async {
-                Self::handle_connection(stream.expect("Failed to read from stream"), context)
+            let transport = self.transport.clone();
+            let handler_future = async move {
+                Self::handle_connection(stream, peer_addr, context, transport)
                     .await
                     .expect("Failed to handle connection")
             };
 
-            task::spawn(handler_future);
+            runtime::spawn(handler_future);
         }
     }
 
-    // Helper method for handling a TcpStream connection and respond to index node queries
-    // param stream: The TCP stream used in the connection to handle
+    // Saves the relays to the state file right after they change, and their ping times periodically
+    // param context: Index node context holding the relays and the state file
+    // param requests: The wakeups for changes that are saved right away
+    async fn keep_saved(context: Arc<Mutex<IndexContext>>, mut requests: Receiver<()>) {
+        loop {
+            // Timing out only means that no change needs saving right away.
+            let _ = runtime::timeout(STATE_SAVE_INTERVAL, requests.next()).await;
+            Self::save_relays(&context).await;
+        }
+    }
+
+    // Writes the relays to the state file if they changed since they were last saved. The file is
+    // written on a blocking thread without holding the context, so requests don't wait for the disk.
+    // Failing to write it only loses the changes on the next restart.
+    // param context: Index node context holding the relays and the state file
+    async fn save_relays(context: &Mutex<IndexContext>) {
+        let unsaved = context.lock().await.take_unsaved();
+        let (path, relays) = match unsaved {
+            Some(unsaved) => unsaved,
+            None => return,
+        };
+
+        let saved = runtime::spawn_blocking(move || {
+            relay_store::save(&path, &relays).map_err(|err| (path, err))
+        })
+        .await;
+        if let Err((path, err)) = saved {
+            println!("Failed to save relays to {:?}: {}", path, err);
+        }
+    }
+
+    // Helper method for handling a connection and respond to index node queries
+    // param stream: The stream used in the connection to handle
+    // param peer_addr: The socket address of the other side of the connection
     // param context: Index node context required for management of relays, id generation and cryptography in a static context
-    async fn handle_connection(stream: TcpStream, context: Arc<Mutex<IndexContext>>) -> Result<()> {
-        let mut reader = RawOnionReader::new(&stream);
-        let mut writer = RawOnionWriter::new(&stream);
+    // param transport: The transport to reach relays over when checking them
+    async fn handle_connection<S: AsyncRead + AsyncWrite>(
+        stream: S,
+        peer_addr: SocketAddr,
+        context: Arc<Mutex<IndexContext>>,
+        transport: T,
+    ) -> Result<()> {
+        let (max_frame_len, cells) = {
+            let context = context.lock().await;
+            (context.max_frame_len, context.cells)
+        };
+        let (reader, writer) = stream.split();
+        let mut reader = RawOnionReader::new(reader).with_max_frame_len(max_frame_len);
+        let mut writer = RawOnionWriter::new(writer);
 
         let hello = reader.read().await?;
-        let peer_key = Self::get_peer_key(hello)?;
+        let hello_req = Self::get_hello_request(hello)?;
+        let negotiation = match Negotiation::accept(&hello_req, cells) {
+            Ok(negotiation) => negotiation,
+            Err(err) => {
+                writer
+                    .write(Onion {
+                        circuit_id: None,
+                        message: Message::Close(Some(err.to_string())),
+                        target: Target::Current,
+                    })
+                    .await?;
+                return Err(Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
 
         let secret = {
             let mut guard = context.lock().await;
@@ -80,32 +232,205 @@ impl IndexNode {
         writer
             .write(Onion {
                 circuit_id: None,
-                message: Message::HelloResponse(secret.public_key()),
+                message: Message::HelloResponse(HelloResponse {
+                    signed_public_key: secret.public_key(),
+                    cipher_suite: negotiation.cipher_suite,
+                    version: negotiation.version,
+                    cells: negotiation.cells,
+                }),
                 target: Target::Current,
             })
             .await?;
 
-        let symmetric_cipher = secret.symmetric_cipher(peer_key);
-        let peer_addr = stream.peer_addr()?;
-        let mut reader = reader.with_cipher(symmetric_cipher.clone());
-        let mut writer = writer.with_cipher(symmetric_cipher);
+        let ciphers = secret.symmetric_ciphers(hello_req.public_key, &negotiation);
+        let mut reader = reader
+            .with_version(negotiation.version)
+            .with_cells(negotiation.cells)
+            .with_cipher(ciphers.recv);
+        let mut writer = writer
+            .with_version(negotiation.version)
+            .with_cells(negotiation.cells)
+            .with_cipher(ciphers.send);
 
         loop {
             let in_onion = match reader.read().await {
                 Ok(in_onion) => in_onion,
-                Err(err) => match err.kind() {
-                    ErrorKind::UnexpectedEof => return Ok(()),
-                    _ => return Err(err),
-                },
+                Err(err) if err.is_eof() => return Ok(()),
+                Err(err) => return Err(err.into()),
             };
-            let out_onion = Self::handle_onion(in_onion, peer_addr, context.clone()).await?;
+
+            // Relays are challenged before they are listed, unless they are listed with the
+            // same key already and pinged since the index node started.
+            if let Message::RelayPingRequest(request) = &in_onion.message {
+                let relay_addr = SocketAddr::new(peer_addr.ip(), request.port);
+                let confirmed = context
+                    .lock()
+                    .await
+                    .is_confirmed(relay_addr, &request.signing_public);
+
+                let refusal = match negotiation.version >= RELAY_CHALLENGE_VERSION {
+                    true if confirmed => None,
+                    true => {
+                        let nonce = challenge::new_nonce();
+                        writer
+                            .write(Onion {
+                                target: Target::Current,
+                                circuit_id: None,
+                                message: Message::RelayChallenge(nonce),
+                            })
+                            .await?;
+                        // A relay that never answers must not hold the connection forever.
+                        match runtime::timeout(CHALLENGE_TIMEOUT, reader.read()).await {
+                            Ok(answer) => Self::check_relay(
+                                request, relay_addr, nonce, answer?, &context, &transport,
+                            )
+                            .await
+                            .err(),
+                            Err(_) => Some(Error::new(
+                                ErrorKind::TimedOut,
+                                "relay did not answer the challenge in time",
+                            )),
+                        }
+                    }
+                    false => Some(Error::new(
+                        ErrorKind::Unsupported,
+                        format!(
+                            "relays must speak protocol version {} to be listed",
+                            RELAY_CHALLENGE_VERSION
+                        ),
+                    )),
+                };
+                if let Some(err) = refusal {
+                    println!("Refused relay @ {:?}: {}", relay_addr, err);
+                    writer
+                        .write(Onion {
+                            target: Target::Current,
+                            circuit_id: None,
+                            message: Message::Close(Some(err.to_string())),
+                        })
+                        .await?;
+                    return Ok(());
+                }
+            }
+
+            let out_onion =
+                Self::handle_onion(in_onion, peer_addr, negotiation.version, context.clone())
+                    .await?;
             writer.write(out_onion).await?;
         }
     }
 
-    fn get_peer_key(hello: Onion) -> Result<[u8; 32]> {
+    // Checks that a relay that pinged holds the signing key it pinged with, and that it can be
+    // reached on the port it advertised
+    // param request: The ping of the relay
+    // param relay_addr: The socket address the relay would be listed with
+    // param nonce: The nonce the relay was challenged with
+    // param answer: The onion the relay answered the challenge with
+    // param context: Index node context holding the signing key of the index node
+    // param transport: The transport to reach the relay over
+    async fn check_relay(
+        request: &RelayPingRequest,
+        relay_addr: SocketAddr,
+        nonce: [u8; 32],
+        answer: Onion,
+        context: &Mutex<IndexContext>,
+        transport: &T,
+    ) -> Result<()> {
+        let signature = match answer.message {
+            Message::RelayChallengeResponse(signature) => signature,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Expected challenge response",
+                ))
+            }
+        };
+        let (index_signing_pub_key, max_frame_len) = {
+            let context = context.lock().await;
+            (context.crypto.signing_public(), context.max_frame_len)
+        };
+        challenge::check_answer(
+            &nonce,
+            &index_signing_pub_key,
+            &request.signing_public,
+            &signature,
+        )
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                "challenge not signed with relay key",
+            )
+        })?;
+
+        let reach = Self::reach_relay(
+            transport,
+            relay_addr,
+            &request.signing_public,
+            max_frame_len,
+        );
+        runtime::timeout(REACHABILITY_TIMEOUT, reach)
+            .await
+            .and_then(|reached| reached)
+            .map_err(|err| Error::new(err.kind(), format!("relay unreachable: {}", err)))
+    }
+
+    // Connects to a relay and completes a Hello handshake with it, which only succeeds if
+    // the relay listening there signs with the given key
+    // param transport: The transport to reach the relay over
+    // param relay_addr: The socket address the relay listens on
+    // param relay_signing_pub_key: The signing public key the relay claims
+    // param max_frame_len: The largest frame to accept from the relay
+    async fn reach_relay(
+        transport: &T,
+        relay_addr: SocketAddr,
+        relay_signing_pub_key: &[u8; 32],
+        max_frame_len: u32,
+    ) -> Result<()> {
+        let crypto = ClientCrypto::new(relay_signing_pub_key)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid signing key"))?;
+        let secret = crypto.gen_secret();
+
+        let stream = transport.connect(relay_addr).await?;
+        let (reader, writer) = stream.split();
+        let mut reader = RawOnionReader::new(reader).with_max_frame_len(max_frame_len);
+        let mut writer = RawOnionWriter::new(writer);
+        // The index node connects like a consumer, which the relay lets go once it hangs up.
+        writer
+            .write(Onion {
+                target: Target::Current,
+                circuit_id: None,
+                message: Message::HelloRequest(HelloRequest::new(
+                    ClientType::Consumer,
+                    secret.public_key(),
+                )),
+            })
+            .await?;
+
+        let response = match reader.read().await?.message {
+            Message::HelloResponse(response) => response,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Expected Hello response",
+                ))
+            }
+        };
+        let negotiation = Negotiation::confirm(&response, true)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Unoffered version or suite"))?;
+        secret
+            .symmetric_ciphers(response.signed_public_key, &negotiation)
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::PermissionDenied,
+                    "handshake not signed with relay key",
+                )
+            })?;
+        Ok(())
+    }
+
+    fn get_hello_request(hello: Onion) -> Result<HelloRequest> {
         if let Message::HelloRequest(req) = hello.message {
-            Ok(req.public_key)
+            Ok(req)
         } else {
             Err(Error::new(ErrorKind::InvalidData, "Expected Hello request"))
         }
@@ -114,39 +439,77 @@ impl IndexNode {
     // Reads the contents of an onion and creates an appropriate response onion
     // param onion: The onion to read the contens of
     // param peer_addr: The socket address of the peer who sent the onion
+    // param version: The protocol version negotiated with the peer. Relays that ping must have
+    // been checked with check_relay unless they are confirmed with the same key already.
     // param context: Index node context required for management of relays, id generation and cryptography in a static context
-    // param peer_key: The public key of the peer who sent the onion
     async fn handle_onion(
         onion: Onion,
         peer_addr: SocketAddr,
+        version: u8,
         context: Arc<Mutex<IndexContext>>,
     ) -> Result<Onion> {
         let mut guard = context.lock().await;
         let context_locked = &mut *guard;
 
+        let now = Instant::now();
+        if context_locked.expire_relays(now) {
+            context_locked.mark_unsaved(true);
+        }
+
         let reply = match onion.message {
-            Message::GetRelaysRequest() => Onion {
-                target: Target::Current,
-                circuit_id: Some(context_locked.circ_id_generator.get_uid()),
-                message: Message::GetRelaysResponse(context_locked.available_relays.clone()),
-            },
+            Message::GetRelaysRequest() => {
+                let relays = context_locked.available_relays.clone();
+                // Relays that stop pinging are dropped within the TTL, so the list holds for as long
+                let message = match version >= DIRECTORY_VERSION {
+                    true => Message::Directory(SignedDirectory::issue(
+                        relays,
+                        context_locked.relay_ttl,
+                        &context_locked.crypto,
+                    )),
+                    false => Message::GetRelaysResponse(relays),
+                };
+                Onion {
+                    target: Target::Current,
+                    circuit_id: Some(context_locked.circ_id_generator.get_uid()),
+                    message,
+                }
+            }
             Message::RelayPingRequest(request) => {
                 let relay_addr = SocketAddr::new(peer_addr.ip(), request.port);
 
                 let existing_relay = context_locked
                     .available_relays
-                    .iter()
+                    .iter_mut()
                     .find(|relay| relay.addr == relay_addr);
 
-                if existing_relay.is_none() {
-                    let id = context_locked.relay_id_generator.get_uid();
-                    println!("Registered relay: {} @ {:?}", id, relay_addr);
-                    context_locked.available_relays.push(Relay {
-                        id,
-                        addr: relay_addr,
-                        pub_key: request.signing_public,
-                    });
+                let (id, changed) = match existing_relay {
+                    // A relay that pings again may have changed its exit policy, or its key
+                    // if it restarted.
+                    Some(relay) => {
+                        let changed = relay.pub_key != request.signing_public
+                            || relay.exit_policy != request.exit_policy;
+                        relay.pub_key = request.signing_public;
+                        relay.exit_policy = request.exit_policy;
+                        (relay.id, changed)
+                    }
+                    None => {
+                        let id = context_locked.relay_id_generator.get_uid();
+                        println!("Registered relay: {} @ {:?}", id, relay_addr);
+                        context_locked.available_relays.push(Relay {
+                            id,
+                            addr: relay_addr,
+                            pub_key: request.signing_public,
+                            exit_policy: request.exit_policy,
+                        });
+                        (id, true)
+                    }
+                };
+                context_locked.last_seen.insert(id, now);
+                let confirmed = context_locked.stale.remove(&id);
+                if confirmed {
+                    println!("Confirmed relay: {} @ {:?}", id, relay_addr);
                 }
+                context_locked.mark_unsaved(changed || confirmed);
 
                 Onion {
                     target: Target::Current,
@@ -164,3 +527,171 @@ impl IndexNode {
         Ok(reply)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::super::relay_store::StoredRelay;
+    use super::*;
+    use crate::{
+        crypto::ServerCrypto,
+        protocol::{
+            directory::unix_time,
+            onion::{PolicySummary, Relay, RelayPingRequest},
+        },
+        relay_node::exit_policy::ExitPolicy,
+    };
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    // Pings the index as the relay listening on the given port of localhost
+    async fn ping(context: &Arc<Mutex<IndexContext>>, port: u16) {
+        let ping = Onion {
+            target: Target::Current,
+            circuit_id: None,
+            message: Message::RelayPingRequest(RelayPingRequest {
+                port,
+                signing_public: [0; 32],
+                exit_policy: ExitPolicy::default().summary(),
+            }),
+        };
+        let peer_addr = SocketAddr::new(LOCALHOST, 50000);
+        IndexNode::<TcpTransport>::handle_onion(
+            ping,
+            peer_addr,
+            DIRECTORY_VERSION,
+            context.clone(),
+        )
+        .await
+        .unwrap();
+    }
+
+    // Moves the last pings of all relays the given time into the past, as if it had passed
+    async fn age_relays(context: &Arc<Mutex<IndexContext>>, by: Duration) {
+        for seen in context.lock().await.last_seen.values_mut() {
+            *seen -= by;
+        }
+    }
+
+    // Returns the ports and IDs of the relays the index hands out
+    async fn relays(context: &Arc<Mutex<IndexContext>>) -> Vec<(u16, u32)> {
+        let request = Onion {
+            target: Target::Current,
+            circuit_id: None,
+            message: Message::GetRelaysRequest(),
+        };
+        let peer_addr = SocketAddr::new(LOCALHOST, 50000);
+        let reply = IndexNode::<TcpTransport>::handle_onion(
+            request,
+            peer_addr,
+            DIRECTORY_VERSION,
+            context.clone(),
+        )
+        .await
+        .unwrap();
+        let index_signing_pub_key = context.lock().await.crypto.signing_public();
+        let relays = match reply.message {
+            Message::Directory(directory) => directory
+                .verify(&index_signing_pub_key, unix_time())
+                .unwrap(),
+            message => panic!("unexpected reply: {:?}", message),
+        };
+        relays
+            .iter()
+            .map(|relay| (relay.addr.port(), relay.id))
+            .collect()
+    }
+
+    #[test]
+    fn silent_relays_expire_and_free_their_ids() {
+        let mut context = IndexContext::new(ServerCrypto::new().to_bytes());
+        context.relay_ttl = Duration::from_secs(3);
+        let context = Arc::new(Mutex::new(context));
+        let two_thirds_ttl = Duration::from_secs(2);
+
+        runtime::block_on(async {
+            ping(&context, 9001).await;
+            ping(&context, 9002).await;
+            assert_eq!(relays(&context).await, vec![(9001, 0), (9002, 1)]);
+
+            // Only the first relay keeps pinging.
+            age_relays(&context, two_thirds_ttl).await;
+            ping(&context, 9001).await;
+            age_relays(&context, two_thirds_ttl).await;
+            assert_eq!(relays(&context).await, vec![(9001, 0)]);
+
+            ping(&context, 9003).await;
+            assert_eq!(relays(&context).await, vec![(9001, 0), (9003, 1)]);
+        });
+    }
+
+    #[test]
+    fn relays_that_pinged_before_the_ttl_are_not_reloaded() {
+        let state_file =
+            std::env::temp_dir().join(format!("ronion-index-old-{}", std::process::id()));
+        let relay = |port, last_seen| StoredRelay {
+            relay: Relay {
+                id: port as u32 - 9001,
+                addr: SocketAddr::new(LOCALHOST, port),
+                pub_key: [0; 32],
+                exit_policy: PolicySummary::default(),
+            },
+            last_seen,
+        };
+        let unix_now = unix_time();
+        relay_store::save(
+            &state_file,
+            &[relay(9001, unix_now - 10), relay(9002, unix_now - 3600)],
+        )
+        .unwrap();
+
+        let mut context = IndexContext::new(ServerCrypto::new().to_bytes());
+        context.load_relays(state_file.clone()).unwrap();
+        std::fs::remove_file(&state_file).unwrap();
+        let context = Arc::new(Mutex::new(context));
+
+        runtime::block_on(async {
+            assert_eq!(relays(&context).await, vec![(9001, 0)]);
+        });
+    }
+
+    #[test]
+    fn relays_survive_restart_as_stale_until_they_ping() {
+        let state_file = std::env::temp_dir().join(format!("ronion-index-{}", std::process::id()));
+        let keypair = ServerCrypto::new().to_bytes();
+        let relay_addr = |port| SocketAddr::new(LOCALHOST, port);
+
+        let mut context = IndexContext::new(keypair);
+        context.load_relays(state_file.clone()).unwrap();
+        let context = Arc::new(Mutex::new(context));
+        runtime::block_on(async {
+            ping(&context, 9001).await;
+            ping(&context, 9002).await;
+            IndexNode::<TcpTransport>::save_relays(&context).await;
+        });
+
+        // The restarted index node hands the relays out under their IDs, but does not trust
+        // them until they ping again.
+        let mut restarted = IndexContext::new(keypair);
+        restarted.load_relays(state_file.clone()).unwrap();
+        assert!(!restarted.is_confirmed(relay_addr(9001), &[0; 32]));
+        let restarted = Arc::new(Mutex::new(restarted));
+
+        runtime::block_on(async {
+            assert_eq!(relays(&restarted).await, vec![(9001, 0), (9002, 1)]);
+
+            ping(&restarted, 9002).await;
+            ping(&restarted, 9003).await;
+            assert_eq!(
+                relays(&restarted).await,
+                vec![(9001, 0), (9002, 1), (9003, 2)]
+            );
+
+            let restarted = restarted.lock().await;
+            assert!(!restarted.is_confirmed(relay_addr(9001), &[0; 32]));
+            assert!(restarted.is_confirmed(relay_addr(9002), &[0; 32]));
+        });
+        std::fs::remove_file(&state_file).unwrap();
+    }
+}
@@ -1,2 +1,3 @@
 mod index_context;
+mod relay_store;
 pub mod index_node;
\ No newline at end of file
@@ -0,0 +1,165 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use crate::protocol::{
+    codec::{deserialize_relays, serialize_relays},
+    onion::{Relay, EXIT_POLICY_VERSION},
+};
+
+/// Marks the state files of index nodes.
+const MAGIC: &[u8; 4] = b"RIDX";
+/// The format of the state files this index node writes and reads.
+const FORMAT_VERSION: u8 = 1;
+/// The largest relay ID a state file may hold. Index nodes hand out the lowest free IDs, so
+/// larger ones only come from corrupt files and would make the ID generator grow huge.
+const MAX_RELAY_ID: u32 = 1 << 16;
+
+/// A relay as an index node keeps it across restarts.
+#[derive(PartialEq, Debug)]
+pub struct StoredRelay {
+    pub relay: Relay,
+    /// When the relay last pinged, in seconds since the Unix epoch.
+    pub last_seen: u64,
+}
+
+// Writes the relays to the state file. The file is only replaced once the new one is
+// written, so an index node that stops halfway leaves the old one behind.
+// param path: The path of the state file
+// param relays: The relays to keep
+pub fn save(path: &Path, relays: &[StoredRelay]) -> Result<()> {
+    let mut partial = OsString::from(path);
+    partial.push(".partial");
+
+    fs::write(&partial, encode(relays))?;
+    fs::rename(&partial, path)
+}
+
+// Reads the relays of a state file, or none if there is no state file yet
+// param path: The path of the state file
+pub fn load(path: &Path) -> Result<Vec<StoredRelay>> {
+    match fs::read(path) {
+        Ok(data) => decode(&data),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+// The file starts with the magic and the format version, followed by the number of relays
+// as a big endian u32, their last ping times as big endian u64s and the relays encoded as
+// in a GetRelaysResponse with exit policy summaries.
+fn encode(relays: &[StoredRelay]) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.push(FORMAT_VERSION);
+    data.extend_from_slice(&(relays.len() as u32).to_be_bytes());
+    for stored in relays {
+        data.extend_from_slice(&stored.last_seen.to_be_bytes());
+    }
+
+    let relays: Vec<Relay> = relays.iter().map(|stored| stored.relay.clone()).collect();
+    data.extend(serialize_relays(&relays, EXIT_POLICY_VERSION));
+    data
+}
+
+fn decode(data: &[u8]) -> Result<Vec<StoredRelay>> {
+    let invalid = |what: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid state file: {}", what),
+        )
+    };
+
+    let rest = data
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| invalid("not a state file"))?;
+    let (version, rest) = rest.split_first().ok_or_else(|| invalid("truncated"))?;
+    if *version != FORMAT_VERSION {
+        return Err(invalid(&format!("unknown format version {}", version)));
+    }
+
+    let (count, rest) = rest
+        .split_at_checked(4)
+        .ok_or_else(|| invalid("truncated"))?;
+    let count = u32::from_be_bytes(count.try_into().unwrap()) as usize;
+    let times_len = count.checked_mul(8).ok_or_else(|| invalid("truncated"))?;
+    let (times, relays) = rest
+        .split_at_checked(times_len)
+        .ok_or_else(|| invalid("truncated"))?;
+
+    let relays = deserialize_relays(relays, EXIT_POLICY_VERSION)?;
+    if relays.len() != count {
+        return Err(invalid("relay count does not match"));
+    }
+    if relays.iter().any(|relay| relay.id > MAX_RELAY_ID) {
+        return Err(invalid("relay ID out of range"));
+    }
+
+    let last_seen = times
+        .chunks_exact(8)
+        .map(|time| u64::from_be_bytes(time.try_into().unwrap()));
+    Ok(relays
+        .into_iter()
+        .zip(last_seen)
+        .map(|(relay, last_seen)| StoredRelay { relay, last_seen })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::onion::PolicySummary;
+
+    fn stored(id: u32, last_seen: u64) -> StoredRelay {
+        StoredRelay {
+            relay: Relay {
+                id,
+                addr: format!("127.0.0.1:{}", 9000 + id).parse().unwrap(),
+                pub_key: [id as u8; 32],
+                exit_policy: PolicySummary {
+                    ports: vec![443..=443],
+                },
+            },
+            last_seen,
+        }
+    }
+
+    #[test]
+    fn saved_relays_load_again() {
+        let path = std::env::temp_dir().join(format!("ronion-store-{}", std::process::id()));
+        let relays = vec![stored(0, 1_700_000_000), stored(4, 1_700_000_030)];
+
+        save(&path, &relays).unwrap();
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, relays);
+    }
+
+    #[test]
+    fn missing_state_file_holds_no_relays() {
+        let path = std::env::temp_dir().join("ronion-store-that-does-not-exist");
+
+        assert_eq!(load(&path).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn decode_rejects_truncated_and_foreign_files() {
+        let data = encode(&[stored(1, 1_700_000_000)]);
+
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        assert!(decode(&data[..9]).is_err());
+        assert!(decode(b"not a state file").is_err());
+    }
+
+    #[test]
+    fn decode_rejects_huge_relay_ids() {
+        let mut huge = stored(1, 1_700_000_000);
+        huge.relay.id = MAX_RELAY_ID + 1;
+        let data = encode(&[huge]);
+
+        assert!(decode(&data).is_err());
+    }
+}
@@ -1,6 +1,9 @@
 pub mod consumer_node;
 pub mod crypto;
+pub mod flow_control;
 pub mod index_node;
 pub mod protocol;
 pub mod relay_node;
+pub mod runtime;
+pub mod transport;
 mod uid_generator;
@@ -0,0 +1,110 @@
+use rand_core::{OsRng, RngCore};
+
+use crate::crypto::{ClientCrypto, ServerCrypto, SignatureError};
+
+/// Prefixes the data a relay signs to answer a challenge. Relays sign the 32 byte keys of
+/// their Hello handshakes with the same key, so a nonce must never be signed on its own.
+const CHALLENGE_CONTEXT: &[u8] = b"ronion relay challenge";
+
+/// Creates the random nonce of a RelayChallenge.
+pub fn new_nonce() -> [u8; 32] {
+    let mut nonce = [0; 32];
+    OsRng {}.fill_bytes(&mut nonce);
+    nonce
+}
+
+// The data a relay signs to answer the challenge of the index node with the given key.
+fn challenge_data(nonce: &[u8; 32], index_signing_pub_key: &[u8; 32]) -> Vec<u8> {
+    let mut data = CHALLENGE_CONTEXT.to_vec();
+    data.extend_from_slice(nonce);
+    data.extend_from_slice(index_signing_pub_key);
+    data
+}
+
+/// Signs a challenge of an index node with the signing key of the relay.
+/// param nonce: The nonce of the RelayChallenge
+/// param index_signing_pub_key: The signing public key of the index node that sent it
+/// param crypto: The cryptography context holding the signing keypair of the relay
+pub fn answer_challenge(
+    nonce: &[u8; 32],
+    index_signing_pub_key: &[u8; 32],
+    crypto: &ServerCrypto,
+) -> [u8; 64] {
+    crypto.sign(&challenge_data(nonce, index_signing_pub_key))
+}
+
+/// Checks that the relay with the given signing public key answered a challenge of the
+/// index node with the given key. Answers meant for another index node are rejected.
+/// param nonce: The nonce the index node sent
+/// param index_signing_pub_key: The signing public key of the index node
+/// param relay_signing_pub_key: The signing public key the relay claims
+/// param signature: The signature of the RelayChallengeResponse
+pub fn check_answer(
+    nonce: &[u8; 32],
+    index_signing_pub_key: &[u8; 32],
+    relay_signing_pub_key: &[u8; 32],
+    signature: &[u8; 64],
+) -> Result<(), SignatureError> {
+    let crypto =
+        ClientCrypto::new(relay_signing_pub_key).map_err(|_| SignatureError::InvalidData)?;
+    crypto.verify(&challenge_data(nonce, index_signing_pub_key), signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answer_of_relay_checks_out() {
+        let (index, relay) = (ServerCrypto::new(), ServerCrypto::new());
+        let nonce = new_nonce();
+
+        let signature = answer_challenge(&nonce, &index.signing_public(), &relay);
+
+        let checked = check_answer(
+            &nonce,
+            &index.signing_public(),
+            &relay.signing_public(),
+            &signature,
+        );
+        assert!(checked.is_ok());
+    }
+
+    #[test]
+    fn answer_without_the_claimed_key_is_rejected() {
+        let (index, relay) = (ServerCrypto::new(), ServerCrypto::new());
+        let nonce = new_nonce();
+
+        let signature = answer_challenge(&nonce, &index.signing_public(), &ServerCrypto::new());
+
+        let checked = check_answer(
+            &nonce,
+            &index.signing_public(),
+            &relay.signing_public(),
+            &signature,
+        );
+        assert!(checked.is_err());
+    }
+
+    #[test]
+    fn answer_for_other_index_or_nonce_is_rejected() {
+        let (index, relay) = (ServerCrypto::new(), ServerCrypto::new());
+        let nonce = new_nonce();
+        let signature = answer_challenge(&nonce, &index.signing_public(), &relay);
+
+        let other_index = check_answer(
+            &nonce,
+            &ServerCrypto::new().signing_public(),
+            &relay.signing_public(),
+            &signature,
+        );
+        let other_nonce = check_answer(
+            &new_nonce(),
+            &index.signing_public(),
+            &relay.signing_public(),
+            &signature,
+        );
+        assert!(other_index.is_err());
+        assert!(other_nonce.is_err());
+    }
+}
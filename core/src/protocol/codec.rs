@@ -0,0 +1,1146 @@
+use super::{
+    bitwriter::BitWriter,
+    error::ProtocolError,
+    onion::{
+        ClientType, DirectoryDocument, HelloRequest, HelloResponse, Message, Onion, PolicySummary,
+        RefusalReason, Relay, RelayPingRequest, SignedDirectory, StreamData, StreamRefusal, Target,
+        DIRECTORY_DOCUMENT_VERSION, DIRECTORY_VERSION, EXIT_POLICY_VERSION,
+    },
+    varint::{self, VarIntReadable, VarIntWritable},
+};
+use crate::crypto::CipherSuite;
+
+use bytes::{BufMut, BytesMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Message types from this value and up don't fit in MSGT and are sent as a VarInt
+/// with the EXT bit set.
+const EXTENDED_MESSAGE_TYPES: u32 = 8;
+
+/// The first protocol version in which the peers of a link negotiate sending encrypted frames
+/// as fixed-size cells, and in which relays pad the layers they pass on.
+pub const CELL_VERSION: u8 = 2;
+/// The length of the plaintext of a cell: a header followed by data and zero padding.
+pub const CELL_LEN: usize = 512;
+const CELL_HEADER_LEN: usize = 2;
+pub const CELL_DATA_LEN: usize = CELL_LEN - CELL_HEADER_LEN;
+/// Set in the cell header when the onion continues in the next cell.
+const CELL_MORE: u16 = 0x8000;
+
+/// The outcome of decoding from a buffer that may hold only part of the input.
+#[derive(Debug, PartialEq)]
+pub enum Decoded<T> {
+    /// The value and the number of bytes of the buffer it was decoded from.
+    Done(T, usize),
+    /// The buffer ends early and at least this many more bytes are needed. Asking for
+    /// no more than that never reads past the end of the value.
+    NeedMore(usize),
+}
+
+impl<T> Decoded<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Decoded<U> {
+        match self {
+            Decoded::Done(value, len) => Decoded::Done(f(value), len),
+            Decoded::NeedMore(more) => Decoded::NeedMore(more),
+        }
+    }
+}
+
+enum DecodeError {
+    NeedMore(usize),
+    Invalid(ProtocolError),
+}
+
+impl From<ProtocolError> for DecodeError {
+    fn from(err: ProtocolError) -> Self {
+        DecodeError::Invalid(err)
+    }
+}
+
+// The unread part of the buffer being decoded.
+struct Input<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let remaining = self.src.len() - self.pos;
+        if remaining < len {
+            return Err(DecodeError::NeedMore(len - remaining));
+        }
+        let bytes = &self.src[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u32, DecodeError> {
+        match u32::from_varint(&self.src[self.pos..]) {
+            Ok((value, len)) => {
+                self.pos += len;
+                Ok(value)
+            }
+            // The VarInt continues past the end of the buffer.
+            Err(varint::Error::Malformed) => Err(DecodeError::NeedMore(1)),
+            Err(varint::Error::Overflow) => Err(ProtocolError::VarIntOverflow.into()),
+        }
+    }
+}
+
+fn decode<'a, T>(
+    src: &'a [u8],
+    decode_value: impl FnOnce(&mut Input<'a>) -> Result<T, DecodeError>,
+) -> Result<Decoded<T>, ProtocolError> {
+    let mut input = Input { src, pos: 0 };
+    match decode_value(&mut input) {
+        Ok(value) => Ok(Decoded::Done(value, input.pos)),
+        Err(DecodeError::NeedMore(more)) => Ok(Decoded::NeedMore(more)),
+        Err(DecodeError::Invalid(err)) => Err(err),
+    }
+}
+
+/// Decodes an onion from the start of a buffer, rejecting message types newer than
+/// the negotiated version.
+/// param src: The buffer to decode the onion from
+/// param version: The protocol version negotiated with the peer
+/// param max_message_len: The longest message content to accept
+pub fn decode_onion(
+    src: &[u8],
+    version: u8,
+    max_message_len: u32,
+) -> Result<Decoded<Onion>, ProtocolError> {
+    decode(src, |input| {
+        let header = input.byte()?;
+        let msgt = header.read_bits(5, 3);
+        let ext = header.read_bits(4, 1);
+        let cip = header.read_bits(3, 1);
+        let opt1 = header.read_bits(2, 1);
+        let tgt = header.read_bits(0, 2);
+
+        let target = match tgt {
+            // Relay
+            0 => Target::Relay(input.varint()?),
+            // IP
+            1 => {
+                let ip = match opt1 {
+                    0 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(input.take(4)?).unwrap())),
+                    _ => IpAddr::V6(Ipv6Addr::from(
+                        <[u8; 16]>::try_from(input.take(16)?).unwrap(),
+                    )),
+                };
+                let port = u16::from_be_bytes(input.take(2)?.try_into().unwrap());
+                Target::IP(SocketAddr::new(ip, port))
+            }
+            // Current
+            2 => Target::Current,
+            // Domain, OPT1 is reserved
+            3 if opt1 == 0 => {
+                let name_len = input.byte()?;
+                let name = std::str::from_utf8(input.take(name_len as usize)?)
+                    .map_err(|_| ProtocolError::InvalidDomain)?;
+                if name.is_empty() {
+                    return Err(ProtocolError::InvalidDomain.into());
+                }
+                let name = name.to_owned();
+                let port = u16::from_be_bytes(input.take(2)?.try_into().unwrap());
+                Target::Domain(name, port)
+            }
+            tgt => return Err(ProtocolError::InvalidTarget(tgt).into()),
+        };
+        if target.min_version() > version {
+            return Err(ProtocolError::UnnegotiatedTarget(tgt).into());
+        }
+
+        let circuit_id = match cip {
+            0 => None,
+            _ => Some(input.varint()?),
+        };
+
+        let msgt = match ext {
+            0 => msgt as u32,
+            _ => {
+                let ext_msgt = input.varint()?;
+                // Types that fit in MSGT must be sent there, so every type has one encoding.
+                if msgt != 0 || ext_msgt < EXTENDED_MESSAGE_TYPES {
+                    return Err(ProtocolError::InvalidMessageType(ext_msgt).into());
+                }
+                ext_msgt
+            }
+        };
+
+        let message_len = input.varint()?;
+        if message_len > max_message_len {
+            return Err(ProtocolError::FrameTooLarge(message_len).into());
+        }
+
+        let message = decode_message(msgt, input.take(message_len as usize)?, version)?;
+        if message.min_version() > version {
+            return Err(ProtocolError::UnnegotiatedMessageType(msgt).into());
+        }
+
+        Ok(Onion {
+            circuit_id,
+            message,
+            target,
+        })
+    })
+}
+
+fn decode_message(msgt: u32, message_raw: &[u8], version: u8) -> Result<Message, ProtocolError> {
+    let message = match msgt {
+        0 => {
+            let (client_byte, public_key) = message_raw
+                .split_first()
+                .ok_or(ProtocolError::InvalidMessageLength("hello request"))?;
+            // Nodes that predate version negotiation send only the public key.
+            let (public_key, versions, suite_ids) = match public_key.len() {
+                32 => (public_key, 1..=1, &[] as &[u8]),
+                len if len >= 34 => {
+                    let (public_key, rest) = public_key.split_at(32);
+                    (public_key, rest[0]..=rest[1], &rest[2..])
+                }
+                _ => return Err(ProtocolError::InvalidMessageLength("hello request")),
+            };
+            Message::HelloRequest(HelloRequest {
+                client_type: match client_byte.read_bits(7, 1) {
+                    0 => ClientType::Relay,
+                    1 => ClientType::Consumer,
+                    ct => return Err(ProtocolError::InvalidClientType(ct)),
+                },
+                public_key: public_key.try_into().unwrap(),
+                versions,
+                cipher_suites: deserialize_cipher_suites(suite_ids),
+                cells: client_byte.read_bits(6, 1) == 1,
+            })
+        }
+        1 => {
+            let length_err = || ProtocolError::InvalidMessageLength("hello response");
+            // Nodes that predate negotiation send only the signed public key.
+            let (cipher_suite, version) = match message_raw.len() {
+                96 => (CipherSuite::Aes256Gcm, 1),
+                98 | 99 => (
+                    CipherSuite::from_id(message_raw[96])
+                        .ok_or(ProtocolError::InvalidCipherSuite(message_raw[96]))?,
+                    message_raw[97],
+                ),
+                _ => return Err(length_err()),
+            };
+            // Responses that select cell negotiation say whether the link uses cells.
+            let cells = match message_raw.get(98) {
+                Some(cells) if version >= CELL_VERSION => *cells != 0,
+                None if version < CELL_VERSION => false,
+                _ => return Err(length_err()),
+            };
+            Message::HelloResponse(HelloResponse {
+                signed_public_key: message_raw[..96].try_into().unwrap(),
+                cipher_suite,
+                version,
+                cells,
+            })
+        }
+        2 => Message::Close(if !message_raw.is_empty() {
+            Some(String::from_utf8_lossy(message_raw).to_string())
+        } else {
+            None
+        }),
+        3 => Message::Payload(message_raw.to_vec()),
+        4 => Message::GetRelaysRequest(),
+        5 => Message::GetRelaysResponse(deserialize_relays(message_raw, version)?),
+        6 => {
+            let length_err = || ProtocolError::InvalidMessageLength("relay ping request");
+            let (head, summary_raw) = message_raw.split_at_checked(34).ok_or_else(length_err)?;
+            let exit_policy = match version >= EXIT_POLICY_VERSION {
+                true => match deserialize_policy_summary(summary_raw)? {
+                    (summary, len) if len == summary_raw.len() => summary,
+                    _ => return Err(length_err()),
+                },
+                false if summary_raw.is_empty() => PolicySummary::default(),
+                false => return Err(length_err()),
+            };
+            Message::RelayPingRequest(RelayPingRequest {
+                port: u16::from_be_bytes(head[0..2].try_into().unwrap()),
+                signing_public: head[2..].try_into().unwrap(),
+                exit_policy,
+            })
+        }
+        7 => Message::RelayPingResponse(),
+        8 => Message::BeginStream(decode_stream_id(message_raw, "begin stream")?),
+        9 => {
+            let (stream_id, id_bytes) = decode_varint(message_raw)?;
+            Message::Data(StreamData {
+                stream_id,
+                data: message_raw[id_bytes..].to_vec(),
+            })
+        }
+        10 => Message::EndStream(decode_stream_id(message_raw, "end stream")?),
+        11 => Message::Sendme(match message_raw.is_empty() {
+            true => None,
+            false => Some(decode_stream_id(message_raw, "sendme")?),
+        }),
+        12 => {
+            let (reason, stream_id) = message_raw
+                .split_first()
+                .ok_or(ProtocolError::InvalidMessageLength("stream refused"))?;
+            Message::StreamRefused(StreamRefusal {
+                stream_id: decode_stream_id(stream_id, "stream refused")?,
+                reason: RefusalReason::from_id(*reason)
+                    .ok_or(ProtocolError::InvalidRefusalReason(*reason))?,
+            })
+        }
+        13 => {
+            let length_err = || ProtocolError::InvalidMessageLength("directory");
+            let split = message_raw.len().checked_sub(64).ok_or_else(length_err)?;
+            let (document, signature) = message_raw.split_at(split);
+            Message::Directory(SignedDirectory {
+                document: deserialize_directory_document(document)?,
+                signature: signature.try_into().unwrap(),
+            })
+        }
+        14 => Message::RelayChallenge(
+            message_raw
+                .try_into()
+                .map_err(|_| ProtocolError::InvalidMessageLength("relay challenge"))?,
+        ),
+        15 => Message::RelayChallengeResponse(
+            message_raw
+                .try_into()
+                .map_err(|_| ProtocolError::InvalidMessageLength("relay challenge response"))?,
+        ),
+        16 => Message::StreamOpened(decode_stream_id(message_raw, "stream opened")?),
+        msgt => return Err(ProtocolError::InvalidMessageType(msgt)),
+    };
+
+    Ok(message)
+}
+
+// Decodes a VarInt from the start of a message, returning it with its length in bytes.
+fn decode_varint(message_raw: &[u8]) -> Result<(u32, usize), ProtocolError> {
+    u32::from_varint(message_raw).map_err(|err| match err {
+        varint::Error::Overflow => ProtocolError::VarIntOverflow,
+        varint::Error::Malformed => ProtocolError::VarIntMalformed,
+    })
+}
+
+// Decodes a message that holds nothing but a stream ID.
+fn decode_stream_id(message_raw: &[u8], what: &'static str) -> Result<u32, ProtocolError> {
+    let (stream_id, id_bytes) = decode_varint(message_raw)?;
+    if id_bytes != message_raw.len() {
+        return Err(ProtocolError::InvalidMessageLength(what));
+    }
+    Ok(stream_id)
+}
+
+/// Encodes an onion to the end of a buffer, refusing message types newer than the
+/// negotiated version.
+/// param onion: The onion to encode
+/// param version: The protocol version negotiated with the peer
+/// param dst: The buffer to encode the onion into
+pub fn encode_onion(onion: Onion, version: u8, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let msgt = message_type(&onion.message);
+    if onion.message.min_version() > version {
+        return Err(ProtocolError::UnnegotiatedMessageType(msgt));
+    }
+
+    let (tgt, opt1) = match onion.target {
+        Target::Relay(_) => (0, 0),
+        Target::IP(addr) if addr.is_ipv6() => (1, 1),
+        Target::IP(_) => (1, 0),
+        Target::Current => (2, 0),
+        Target::Domain(ref name, _) => {
+            if name.is_empty() || name.len() > u8::MAX as usize {
+                return Err(ProtocolError::InvalidDomain);
+            }
+            (3, 0)
+        }
+    };
+    if onion.target.min_version() > version {
+        return Err(ProtocolError::UnnegotiatedTarget(tgt));
+    }
+    let cip = onion.circuit_id.is_some() as u8;
+    let (msgt_bits, ext) = if msgt < EXTENDED_MESSAGE_TYPES {
+        (msgt as u8, 0)
+    } else {
+        (0, 1)
+    };
+
+    let mut header = 0u8;
+    header.write_bits(5, msgt_bits, 3);
+    header.write_bits(4, ext, 1);
+    header.write_bits(3, cip, 1);
+    header.write_bits(2, opt1, 1);
+    header.write_bits(0, tgt, 2);
+    dst.put_u8(header);
+
+    match onion.target {
+        Target::Relay(id) => put_varint(dst, id),
+        Target::IP(addr) => {
+            match addr.ip() {
+                IpAddr::V4(v4) => dst.put_slice(&v4.octets()),
+                IpAddr::V6(v6) => dst.put_slice(&v6.octets()),
+            }
+            dst.put_u16(addr.port());
+        }
+        Target::Domain(name, port) => {
+            dst.put_u8(name.len() as u8);
+            dst.put_slice(name.as_bytes());
+            dst.put_u16(port);
+        }
+        Target::Current => (),
+    }
+    if let Some(id) = onion.circuit_id {
+        put_varint(dst, id);
+    }
+    if ext != 0 {
+        put_varint(dst, msgt);
+    }
+
+    let message_raw = encode_message(onion.message, version);
+    put_varint(dst, message_raw.len() as u32);
+    dst.put_slice(&message_raw);
+
+    Ok(())
+}
+
+// Returns the type number a message is sent with.
+fn message_type(message: &Message) -> u32 {
+    match message {
+        Message::HelloRequest(_) => 0,
+        Message::HelloResponse(_) => 1,
+        Message::Close(_) => 2,
+        Message::Payload(_) => 3,
+        Message::GetRelaysRequest() => 4,
+        Message::GetRelaysResponse(_) => 5,
+        Message::RelayPingRequest(_) => 6,
+        Message::RelayPingResponse() => 7,
+        Message::BeginStream(_) => 8,
+        Message::Data(_) => 9,
+        Message::EndStream(_) => 10,
+        Message::Sendme(_) => 11,
+        Message::StreamRefused(_) => 12,
+        Message::Directory(_) => 13,
+        Message::RelayChallenge(_) => 14,
+        Message::RelayChallengeResponse(_) => 15,
+        Message::StreamOpened(_) => 16,
+    }
+}
+
+fn encode_message(message: Message, version: u8) -> Vec<u8> {
+    match message {
+        Message::HelloRequest(req) => {
+            let mut client_byte = 0u8;
+            let client_bits = match req.client_type {
+                ClientType::Relay => 0,
+                ClientType::Consumer => 1,
+            };
+            client_byte.write_bits(7, client_bits, 1);
+            client_byte.write_bits(6, req.cells as u8, 1);
+
+            let mut message_raw = vec![client_byte];
+            message_raw.extend_from_slice(&req.public_key);
+            message_raw.extend_from_slice(&[*req.versions.start(), *req.versions.end()]);
+            message_raw.extend(req.cipher_suites.iter().map(|suite| suite.id()));
+            message_raw
+        }
+        Message::HelloResponse(resp) => {
+            let mut message_raw = resp.signed_public_key.to_vec();
+            message_raw.extend_from_slice(&[resp.cipher_suite.id(), resp.version]);
+            if resp.version >= CELL_VERSION {
+                message_raw.push(resp.cells as u8);
+            }
+            message_raw
+        }
+        Message::Close(text) => text.map_or(Vec::new(), String::into_bytes),
+        Message::Payload(data) => data,
+        Message::GetRelaysRequest() => Vec::new(),
+        Message::GetRelaysResponse(relays) => serialize_relays(&relays, version),
+        Message::RelayPingRequest(request) => {
+            let mut message_raw = request.port.to_be_bytes().to_vec();
+            message_raw.extend_from_slice(&request.signing_public);
+            if version >= EXIT_POLICY_VERSION {
+                serialize_policy_summary(&request.exit_policy, &mut message_raw);
+            }
+            message_raw
+        }
+        Message::RelayPingResponse() | Message::Sendme(None) => Vec::new(),
+        Message::BeginStream(stream_id)
+        | Message::EndStream(stream_id)
+        | Message::StreamOpened(stream_id)
+        | Message::Sendme(Some(stream_id)) => {
+            let (id, id_bytes) = stream_id.to_varint();
+            id[..id_bytes].to_vec()
+        }
+        Message::Data(stream_data) => {
+            let (id, id_bytes) = stream_data.stream_id.to_varint();
+            let mut message_raw = id[..id_bytes].to_vec();
+            message_raw.extend_from_slice(&stream_data.data);
+            message_raw
+        }
+        Message::StreamRefused(refusal) => {
+            let (id, id_bytes) = refusal.stream_id.to_varint();
+            let mut message_raw = vec![refusal.reason.id()];
+            message_raw.extend_from_slice(&id[..id_bytes]);
+            message_raw
+        }
+        Message::Directory(directory) => {
+            let mut message_raw = serialize_directory_document(&directory.document);
+            message_raw.extend_from_slice(&directory.signature);
+            message_raw
+        }
+        Message::RelayChallenge(nonce) => nonce.to_vec(),
+        Message::RelayChallengeResponse(signature) => signature.to_vec(),
+    }
+}
+
+/// Decodes a length-prefixed frame from the start of a buffer. Frames longer than the
+/// limit are rejected as soon as their length is known.
+/// param src: The buffer to decode the frame from
+/// param max_frame_len: The longest frame to accept
+pub fn decode_frame(src: &[u8], max_frame_len: u32) -> Result<Decoded<&[u8]>, ProtocolError> {
+    decode(src, |input| {
+        let len = input.varint()?;
+        if len > max_frame_len {
+            return Err(ProtocolError::FrameTooLarge(len).into());
+        }
+        input.take(len as usize)
+    })
+}
+
+/// Encodes a frame to the end of a buffer, prefixed with its length.
+/// param frame: The frame to encode
+/// param dst: The buffer to encode the frame into
+pub fn encode_frame(frame: &[u8], dst: &mut BytesMut) {
+    put_varint(dst, frame.len() as u32);
+    dst.put_slice(frame);
+}
+
+/// Gets the length of the header of an onion without circuit ID that carries a Payload of
+/// the given length to a relay.
+/// param relay_id: The relay the onion targets
+/// param payload_len: The length of the payload
+pub fn payload_header_len(relay_id: u32, payload_len: usize) -> usize {
+    1 + relay_id.to_varint().1 + (payload_len as u32).to_varint().1
+}
+
+fn put_varint(dst: &mut BytesMut, value: u32) {
+    let (value_vi, value_vi_bytes) = value.to_varint();
+    dst.put_slice(&value_vi[..value_vi_bytes]);
+}
+
+/// Splits an encoded onion into cells, zero padding the last one.
+pub fn split_cells(plain_onion: &[u8]) -> Vec<Vec<u8>> {
+    let chunks: Vec<&[u8]> = plain_onion.chunks(CELL_DATA_LEN).collect();
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let more = if i + 1 < chunks.len() { CELL_MORE } else { 0 };
+            let mut cell = vec![0u8; CELL_LEN];
+            cell[..CELL_HEADER_LEN].copy_from_slice(&(more | chunk.len() as u16).to_be_bytes());
+            cell[CELL_HEADER_LEN..CELL_HEADER_LEN + chunk.len()].copy_from_slice(chunk);
+            cell
+        })
+        .collect()
+}
+
+/// Appends the data of a cell to the onion, returning true if the onion continues in
+/// the next cell.
+pub fn join_cell(plain_onion: &mut Vec<u8>, cell: &[u8]) -> Result<bool, ProtocolError> {
+    if cell.len() != CELL_LEN {
+        return Err(ProtocolError::MalformedCell);
+    }
+
+    let header = u16::from_be_bytes([cell[0], cell[1]]);
+    let data_len = (header & !CELL_MORE) as usize;
+    if data_len > CELL_DATA_LEN {
+        return Err(ProtocolError::MalformedCell);
+    }
+
+    plain_onion.extend_from_slice(&cell[CELL_HEADER_LEN..CELL_HEADER_LEN + data_len]);
+    Ok(header & CELL_MORE != 0)
+}
+
+/// Serializes relays for a GetRelaysResponse. Their exit policy summaries are left out
+/// for peers that predate exit policies.
+pub fn serialize_relays(relays: &[Relay], version: u8) -> Vec<u8> {
+    let mut vec = Vec::new();
+    for relay in relays {
+        let mut leading = 0u8;
+        let ip_bit = if relay.addr.is_ipv6() { 1 } else { 0 };
+        leading.write_bits(7, ip_bit, 1);
+        vec.push(leading);
+
+        match relay.addr.ip() {
+            IpAddr::V4(v4) => vec.extend(v4.octets().iter()),
+            IpAddr::V6(v6) => vec.extend(v6.octets().iter()),
+        };
+        vec.extend(relay.addr.port().to_be_bytes().iter());
+        vec.extend(relay.pub_key.iter());
+
+        let (id, id_bytes) = relay.id.to_varint();
+        vec.extend(id[0..id_bytes].iter());
+
+        if version >= EXIT_POLICY_VERSION {
+            serialize_policy_summary(&relay.exit_policy, &mut vec);
+        }
+    }
+
+    vec
+}
+
+/// Serializes a directory document the way its signature covers it: the document version,
+/// the valid-after and valid-until times as big endian u64s and the relays, which always
+/// carry their exit policy summaries.
+pub fn serialize_directory_document(document: &DirectoryDocument) -> Vec<u8> {
+    let mut vec = vec![document.version];
+    vec.extend_from_slice(&document.valid_after.to_be_bytes());
+    vec.extend_from_slice(&document.valid_until.to_be_bytes());
+    vec.extend(serialize_relays(&document.relays, DIRECTORY_VERSION));
+    vec
+}
+
+// Reads a directory document, rejecting versions this node doesn't know.
+fn deserialize_directory_document(data: &[u8]) -> Result<DirectoryDocument, ProtocolError> {
+    let (&version, data) = data
+        .split_first()
+        .ok_or(ProtocolError::InvalidMessageLength("directory"))?;
+    if version != DIRECTORY_DOCUMENT_VERSION {
+        return Err(ProtocolError::InvalidDirectoryVersion(version));
+    }
+    let (times, relays) = data
+        .split_at_checked(16)
+        .ok_or(ProtocolError::InvalidMessageLength("directory"))?;
+
+    Ok(DirectoryDocument {
+        version,
+        valid_after: u64::from_be_bytes(times[..8].try_into().unwrap()),
+        valid_until: u64::from_be_bytes(times[8..].try_into().unwrap()),
+        relays: deserialize_relays(relays, DIRECTORY_VERSION)?,
+    })
+}
+
+// Appends a policy summary as the number of port ranges followed by the first and last
+// port of every range.
+fn serialize_policy_summary(summary: &PolicySummary, dst: &mut Vec<u8>) {
+    let (count, count_bytes) = (summary.ports.len() as u32).to_varint();
+    dst.extend_from_slice(&count[..count_bytes]);
+    for ports in &summary.ports {
+        dst.extend_from_slice(&ports.start().to_be_bytes());
+        dst.extend_from_slice(&ports.end().to_be_bytes());
+    }
+}
+
+// Reads a policy summary from the start of the data, returning it with its length in bytes.
+fn deserialize_policy_summary(data: &[u8]) -> Result<(PolicySummary, usize), ProtocolError> {
+    let (count, count_bytes) = decode_varint(data)?;
+    let len = count_bytes + count as usize * 4;
+    let ranges = data
+        .get(count_bytes..len)
+        .ok_or(ProtocolError::InvalidMessageLength("policy summary"))?;
+
+    let ports = ranges
+        .chunks(4)
+        .map(|range| {
+            u16::from_be_bytes([range[0], range[1]])..=u16::from_be_bytes([range[2], range[3]])
+        })
+        .collect();
+    Ok((PolicySummary { ports }, len))
+}
+
+// Reads the cipher suites offered in a HelloRequest. Ids this node doesn't know are
+// skipped; a request without any ids comes from a node that only speaks AES-256-GCM.
+fn deserialize_cipher_suites(suite_ids: &[u8]) -> Vec<CipherSuite> {
+    if suite_ids.is_empty() {
+        return vec![CipherSuite::Aes256Gcm];
+    }
+
+    suite_ids
+        .iter()
+        .filter_map(|&id| CipherSuite::from_id(id))
+        .collect()
+}
+
+pub fn deserialize_relays(mut data: &[u8], version: u8) -> Result<Vec<Relay>, ProtocolError> {
+    let range_err = || ProtocolError::InvalidMessageLength("relay");
+    let mut vec = Vec::new();
+
+    while !data.is_empty() {
+        let ip_bit = data.first().ok_or_else(range_err)?.read_bits(7, 1);
+        data = &data[1..];
+        let (ip_bytes, ip) = match ip_bit {
+            0 => (
+                4,
+                IpAddr::V4(From::<[u8; 4]>::from(
+                    data.get(0..4).ok_or_else(range_err)?.try_into().unwrap(),
+                )),
+            ),
+            1 => (
+                16,
+                IpAddr::V6(From::<[u8; 16]>::from(
+                    data.get(0..16).ok_or_else(range_err)?.try_into().unwrap(),
+                )),
+            ),
+            bit => return Err(ProtocolError::InvalidIpVersion(bit)),
+        };
+        data = &data[ip_bytes..];
+
+        let port = u16::from_be_bytes(data.get(0..2).ok_or_else(range_err)?.try_into().unwrap());
+        data = &data[2..];
+
+        let pub_key = data.get(0..32).ok_or_else(range_err)?.try_into().unwrap();
+        data = &data[32..];
+
+        let (id, id_bytes) = decode_varint(data)?;
+        data = &data[id_bytes..];
+
+        let exit_policy = match version >= EXIT_POLICY_VERSION {
+            true => {
+                let (summary, summary_bytes) = deserialize_policy_summary(data)?;
+                data = &data[summary_bytes..];
+                summary
+            }
+            false => PolicySummary::default(),
+        };
+
+        vec.push(Relay {
+            id,
+            pub_key,
+            addr: SocketAddr::new(ip, port),
+            exit_policy,
+        });
+    }
+
+    Ok(vec)
+}
+
+#[cfg(test)]
+// Header bytes in the tests are grouped by field: MSGT, EXT, CIP, OPT1, TGT.
+#[allow(clippy::unusual_byte_groupings)]
+mod tests {
+    use super::*;
+    use crate::protocol::onion::{
+        PROTOCOL_VERSIONS, RELAY_CHALLENGE_VERSION, STREAM_OPENED_VERSION,
+    };
+
+    fn payload_onion() -> Onion {
+        Onion {
+            circuit_id: Some(300),
+            target: Target::Relay(7),
+            message: Message::Payload(vec![1, 2, 3, 4]),
+        }
+    }
+
+    fn encoded(onion: Onion) -> BytesMut {
+        let mut dst = BytesMut::new();
+        encode_onion(onion, 1, &mut dst).unwrap();
+        dst
+    }
+
+    #[test]
+    fn decode_onion_roundtrip() {
+        let src = encoded(payload_onion());
+
+        let decoded = decode_onion(&src, 1, u32::MAX).unwrap();
+
+        assert_eq!(decoded, Decoded::Done(payload_onion(), src.len()));
+    }
+
+    #[test]
+    fn decode_onion_leaves_trailing_bytes() {
+        let mut src = encoded(payload_onion());
+        let onion_len = src.len();
+        src.put_slice(&[0b010_0_0_0_10, 0]);
+
+        let decoded = decode_onion(&src, 1, u32::MAX).unwrap();
+
+        assert_eq!(decoded, Decoded::Done(payload_onion(), onion_len));
+    }
+
+    #[test]
+    fn decode_onion_needs_more_bytes() {
+        let src = encoded(payload_onion());
+
+        // header, relay id, circuit id (2 bytes), message length, message
+        assert_eq!(
+            decode_onion(&[], 1, u32::MAX).unwrap(),
+            Decoded::NeedMore(1)
+        );
+        assert_eq!(
+            decode_onion(&src[..3], 1, u32::MAX).unwrap(),
+            Decoded::NeedMore(1)
+        );
+        assert_eq!(
+            decode_onion(&src[..5], 1, u32::MAX).unwrap(),
+            Decoded::NeedMore(4)
+        );
+        assert_eq!(
+            decode_onion(&src[..7], 1, u32::MAX).unwrap(),
+            Decoded::NeedMore(2)
+        );
+    }
+
+    #[test]
+    fn decode_onion_byte_by_byte() {
+        let src = encoded(payload_onion());
+
+        let mut len = 0;
+        let onion = loop {
+            match decode_onion(&src[..len], 1, u32::MAX).unwrap() {
+                Decoded::Done(onion, consumed) => {
+                    assert_eq!(consumed, len);
+                    break onion;
+                }
+                Decoded::NeedMore(more) => len += more,
+            }
+        };
+
+        assert_eq!(len, src.len());
+        assert_eq!(onion, payload_onion());
+    }
+
+    #[test]
+    fn decode_onion_rejects_long_message_before_it_arrives() {
+        let err = decode_onion(&[0b011_0_0_0_10, 0x81, 0x08], 1, 1024).unwrap_err();
+
+        assert!(matches!(err, ProtocolError::FrameTooLarge(1025)));
+    }
+
+    #[test]
+    fn stream_messages_roundtrip() {
+        let onions = || {
+            vec![
+                Onion {
+                    circuit_id: None,
+                    target: Target::Domain("example.com".to_string(), 80),
+                    message: Message::BeginStream(300),
+                },
+                Onion {
+                    circuit_id: None,
+                    target: Target::Current,
+                    message: Message::Data(StreamData {
+                        stream_id: 300,
+                        data: vec![1, 2, 3],
+                    }),
+                },
+                Onion {
+                    circuit_id: None,
+                    target: Target::Current,
+                    message: Message::EndStream(300),
+                },
+                Onion {
+                    circuit_id: None,
+                    target: Target::Current,
+                    message: Message::Sendme(Some(300)),
+                },
+                Onion {
+                    circuit_id: None,
+                    target: Target::Current,
+                    message: Message::Sendme(None),
+                },
+            ]
+        };
+
+        for (onion, expected) in onions().into_iter().zip(onions()) {
+            let mut src = BytesMut::new();
+            encode_onion(onion, 5, &mut src).unwrap();
+
+            let decoded = decode_onion(&src, 5, u32::MAX).unwrap();
+
+            assert_eq!(decoded, Decoded::Done(expected, src.len()));
+        }
+    }
+
+    #[test]
+    fn stream_opened_roundtrips_from_version_9() {
+        let onion = || Onion {
+            circuit_id: None,
+            target: Target::Current,
+            message: Message::StreamOpened(300),
+        };
+        let mut src = BytesMut::new();
+        encode_onion(onion(), STREAM_OPENED_VERSION, &mut src).unwrap();
+
+        let decoded = decode_onion(&src, STREAM_OPENED_VERSION, u32::MAX).unwrap();
+        let err = encode_onion(onion(), STREAM_OPENED_VERSION - 1, &mut BytesMut::new());
+
+        assert_eq!(decoded, Decoded::Done(onion(), src.len()));
+        assert!(matches!(
+            err,
+            Err(ProtocolError::UnnegotiatedMessageType(16))
+        ));
+    }
+
+    #[test]
+    fn hello_response_says_whether_links_use_cells_from_cell_version() {
+        let response = |version, cells| Onion {
+            circuit_id: None,
+            target: Target::Current,
+            message: Message::HelloResponse(HelloResponse {
+                signed_public_key: [3; 96],
+                cipher_suite: CipherSuite::Aes256Gcm,
+                version,
+                cells,
+            }),
+        };
+        let without_cells = encoded(response(CELL_VERSION, false));
+        let older = encoded(response(CELL_VERSION - 1, false));
+        // A response that selects cell negotiation but leaves out the cells byte.
+        let mut missing_cells = older.clone();
+        *missing_cells.last_mut().unwrap() = CELL_VERSION;
+
+        let decoded = decode_onion(&without_cells, 1, u32::MAX).unwrap();
+        let decoded_older = decode_onion(&older, 1, u32::MAX).unwrap();
+
+        assert_eq!(
+            decoded,
+            Decoded::Done(response(CELL_VERSION, false), without_cells.len())
+        );
+        assert_eq!(
+            decoded_older,
+            Decoded::Done(response(CELL_VERSION - 1, false), older.len())
+        );
+        assert!(decode_onion(&missing_cells, 1, u32::MAX).is_err());
+    }
+
+    #[test]
+    fn payload_header_len_matches_the_encoded_header() {
+        for (relay_id, payload_len) in [(0, 0), (7, 127), (300, 128), (70000, 20000)] {
+            let mut dst = BytesMut::new();
+            let onion = Onion {
+                circuit_id: None,
+                target: Target::Relay(relay_id),
+                message: Message::Payload(vec![0; payload_len]),
+            };
+            encode_onion(onion, *PROTOCOL_VERSIONS.end(), &mut dst).unwrap();
+
+            assert_eq!(
+                payload_header_len(relay_id, payload_len),
+                dst.len() - payload_len
+            );
+        }
+    }
+
+    fn relay_with_policy() -> Relay {
+        Relay {
+            id: 300,
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 9001),
+            pub_key: [7; 32],
+            exit_policy: PolicySummary {
+                ports: vec![80..=80, 443..=443, 1024..=65535],
+            },
+        }
+    }
+
+    #[test]
+    fn exit_policy_messages_roundtrip() {
+        let messages = || {
+            vec![
+                Message::RelayPingRequest(RelayPingRequest {
+                    port: 9001,
+                    signing_public: [7; 32],
+                    exit_policy: relay_with_policy().exit_policy,
+                }),
+                Message::GetRelaysResponse(vec![relay_with_policy(), relay_with_policy()]),
+                Message::StreamRefused(StreamRefusal {
+                    stream_id: 300,
+                    reason: RefusalReason::ExitPolicy,
+                }),
+            ]
+        };
+
+        for (message, expected) in messages().into_iter().zip(messages()) {
+            let onion = |message| Onion {
+                circuit_id: None,
+                target: Target::Current,
+                message,
+            };
+            let mut src = BytesMut::new();
+            encode_onion(onion(message), 6, &mut src).unwrap();
+
+            let decoded = decode_onion(&src, 6, u32::MAX).unwrap();
+
+            assert_eq!(decoded, Decoded::Done(onion(expected), src.len()));
+        }
+    }
+
+    #[test]
+    fn relay_policy_summary_is_left_out_before_version_6() {
+        let onion = Onion {
+            circuit_id: None,
+            target: Target::Current,
+            message: Message::GetRelaysResponse(vec![relay_with_policy()]),
+        };
+        let mut src = BytesMut::new();
+        encode_onion(onion, 5, &mut src).unwrap();
+
+        let decoded = decode_onion(&src, 5, u32::MAX).unwrap();
+
+        let relay = Relay {
+            exit_policy: PolicySummary::default(),
+            ..relay_with_policy()
+        };
+        let expected = Onion {
+            circuit_id: None,
+            target: Target::Current,
+            message: Message::GetRelaysResponse(vec![relay]),
+        };
+        assert_eq!(decoded, Decoded::Done(expected, src.len()));
+    }
+
+    fn directory_onion() -> Onion {
+        Onion {
+            circuit_id: None,
+            target: Target::Current,
+            message: Message::Directory(SignedDirectory {
+                document: DirectoryDocument {
+                    version: DIRECTORY_DOCUMENT_VERSION,
+                    valid_after: 1_700_000_000,
+                    valid_until: 1_700_000_090,
+                    relays: vec![relay_with_policy(), relay_with_policy()],
+                },
+                signature: [9; 64],
+            }),
+        }
+    }
+
+    #[test]
+    fn directory_roundtrip() {
+        let mut src = BytesMut::new();
+        encode_onion(directory_onion(), DIRECTORY_VERSION, &mut src).unwrap();
+
+        let decoded = decode_onion(&src, DIRECTORY_VERSION, u32::MAX).unwrap();
+
+        assert_eq!(decoded, Decoded::Done(directory_onion(), src.len()));
+    }
+
+    #[test]
+    fn directory_is_refused_before_version_7() {
+        let err = encode_onion(directory_onion(), 6, &mut BytesMut::new()).unwrap_err();
+
+        assert!(matches!(err, ProtocolError::UnnegotiatedMessageType(13)));
+    }
+
+    #[test]
+    fn decode_onion_rejects_unknown_directory_version() {
+        let mut src = BytesMut::new();
+        encode_onion(directory_onion(), DIRECTORY_VERSION, &mut src).unwrap();
+        // The message ends with the document and its signature, the document with its version.
+        let document_len = match directory_onion().message {
+            Message::Directory(directory) => {
+                serialize_directory_document(&directory.document).len()
+            }
+            _ => unreachable!(),
+        };
+        let version_at = src.len() - 64 - document_len;
+        src[version_at] = 2;
+
+        let err = decode_onion(&src, DIRECTORY_VERSION, u32::MAX).unwrap_err();
+
+        assert!(matches!(err, ProtocolError::InvalidDirectoryVersion(2)));
+    }
+
+    #[test]
+    fn relay_challenge_messages_roundtrip() {
+        let messages = || {
+            vec![
+                Message::RelayChallenge([3; 32]),
+                Message::RelayChallengeResponse([5; 64]),
+            ]
+        };
+
+        for (message, expected) in messages().into_iter().zip(messages()) {
+            let onion = |message| Onion {
+                circuit_id: None,
+                target: Target::Current,
+                message,
+            };
+            let mut src = BytesMut::new();
+            encode_onion(onion(message), RELAY_CHALLENGE_VERSION, &mut src).unwrap();
+
+            let decoded = decode_onion(&src, RELAY_CHALLENGE_VERSION, u32::MAX).unwrap();
+
+            assert_eq!(decoded, Decoded::Done(onion(expected), src.len()));
+        }
+    }
+
+    #[test]
+    fn decode_onion_rejects_short_relay_challenge() {
+        let err = decode_onion(
+            &[0b000_1_0_0_10, 14, 2, 1, 2],
+            RELAY_CHALLENGE_VERSION,
+            1024,
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            err,
+            ProtocolError::InvalidMessageLength("relay challenge")
+        ));
+    }
+
+    #[test]
+    fn decode_onion_rejects_unknown_refusal_reason() {
+        let err = decode_onion(&[0b000_1_0_0_10, 12, 2, 9, 5], 6, 1024).unwrap_err();
+
+        assert!(matches!(err, ProtocolError::InvalidRefusalReason(9)));
+    }
+
+    #[test]
+    fn decode_onion_rejects_end_stream_with_trailing_bytes() {
+        let err = decode_onion(&[0b000_1_0_0_10, 10, 2, 5, 0], 4, 1024).unwrap_err();
+
+        assert!(matches!(
+            err,
+            ProtocolError::InvalidMessageLength("end stream")
+        ));
+    }
+
+    #[test]
+    fn decode_onion_rejects_domain_that_is_not_utf8() {
+        let err = decode_onion(&[0b011_0_0_0_11, 2, 0xC3, 0x28, 0, 80, 0], 3, 1024).unwrap_err();
+
+        assert!(matches!(err, ProtocolError::InvalidDomain));
+    }
+
+    #[test]
+    fn encode_onion_refuses_domain_before_version_3() {
+        let onion = Onion {
+            circuit_id: None,
+            message: Message::Payload(Vec::new()),
+            target: Target::Domain("example.com".to_string(), 443),
+        };
+
+        let err = encode_onion(onion, 2, &mut BytesMut::new()).unwrap_err();
+
+        assert!(matches!(err, ProtocolError::UnnegotiatedTarget(3)));
+    }
+
+    #[test]
+    fn decode_frame_needs_more_bytes() {
+        let mut src = BytesMut::new();
+        encode_frame(&[9; 200], &mut src);
+
+        assert_eq!(decode_frame(&src[..1], 1024).unwrap(), Decoded::NeedMore(1));
+        assert_eq!(
+            decode_frame(&src[..2], 1024).unwrap(),
+            Decoded::NeedMore(200)
+        );
+        assert_eq!(
+            decode_frame(&src, 1024).unwrap(),
+            Decoded::Done(&[9u8; 200][..], 202)
+        );
+    }
+
+    #[test]
+    fn decode_frame_too_large() {
+        let mut src = BytesMut::new();
+        encode_frame(&[0; 200], &mut src);
+
+        let err = decode_frame(&src[..2], 100).unwrap_err();
+
+        assert!(matches!(err, ProtocolError::FrameTooLarge(200)));
+    }
+}
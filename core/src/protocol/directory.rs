@@ -0,0 +1,154 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::crypto::{ClientCrypto, ServerCrypto};
+
+use super::{
+    codec::serialize_directory_document,
+    onion::{DirectoryDocument, Relay, SignedDirectory, DIRECTORY_DOCUMENT_VERSION},
+};
+
+/// How far the clock of a node may be off from the clock of the index node before the
+/// directories it issues are rejected.
+pub const CLOCK_SKEW: u64 = 60;
+
+/// Why a directory was rejected.
+#[derive(Debug, PartialEq)]
+pub enum DirectoryError {
+    /// The document was not signed by the index node, or was changed after it was.
+    InvalidSignature,
+    /// The document is not valid yet.
+    NotYetValid,
+    /// The document is no longer valid.
+    Expired,
+}
+
+impl fmt::Display for DirectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DirectoryError::InvalidSignature => write!(f, "invalid directory signature"),
+            DirectoryError::NotYetValid => write!(f, "directory is not valid yet"),
+            DirectoryError::Expired => write!(f, "directory has expired"),
+        }
+    }
+}
+
+impl std::error::Error for DirectoryError {}
+
+/// Gets the current time in seconds since the Unix epoch, as directory documents state it.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+impl SignedDirectory {
+    /// Issues a directory of the given relays that is valid from now for the given lifetime,
+    /// signed with the keypair of the index node.
+    pub fn issue(relays: Vec<Relay>, lifetime: Duration, crypto: &ServerCrypto) -> Self {
+        let valid_after = unix_time();
+        let document = DirectoryDocument {
+            version: DIRECTORY_DOCUMENT_VERSION,
+            valid_after,
+            valid_until: valid_after + lifetime.as_secs(),
+            relays,
+        };
+        let signature = crypto.sign(&serialize_directory_document(&document));
+
+        Self {
+            document,
+            signature,
+        }
+    }
+
+    /// Checks that the index node with the given signing public key signed the directory and
+    /// that it is valid at the given time, returning its relays.
+    /// param index_signing_pub_key: The signing public key of the index node
+    /// param now: The time to check against, in seconds since the Unix epoch
+    pub fn verify(
+        self,
+        index_signing_pub_key: &[u8; 32],
+        now: u64,
+    ) -> Result<Vec<Relay>, DirectoryError> {
+        let crypto = ClientCrypto::new(index_signing_pub_key)
+            .map_err(|_| DirectoryError::InvalidSignature)?;
+        crypto
+            .verify(
+                &serialize_directory_document(&self.document),
+                &self.signature,
+            )
+            .map_err(|_| DirectoryError::InvalidSignature)?;
+
+        if now + CLOCK_SKEW < self.document.valid_after {
+            return Err(DirectoryError::NotYetValid);
+        }
+        if now > self.document.valid_until + CLOCK_SKEW {
+            return Err(DirectoryError::Expired);
+        }
+        Ok(self.document.relays)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::onion::PolicySummary;
+
+    fn relays() -> Vec<Relay> {
+        vec![Relay {
+            id: 3,
+            addr: "127.0.0.1:9001".parse().unwrap(),
+            pub_key: [7; 32],
+            exit_policy: PolicySummary {
+                ports: vec![80..=80, 443..=443],
+            },
+        }]
+    }
+
+    #[test]
+    fn issued_directory_verifies_with_index_key() {
+        let crypto = ServerCrypto::new();
+        let directory = SignedDirectory::issue(relays(), Duration::from_secs(90), &crypto);
+
+        let verified = directory.verify(&crypto.signing_public(), unix_time());
+
+        assert_eq!(verified, Ok(relays()));
+    }
+
+    #[test]
+    fn directory_of_other_index_is_rejected() {
+        let directory =
+            SignedDirectory::issue(relays(), Duration::from_secs(90), &ServerCrypto::new());
+
+        let verified = directory.verify(&ServerCrypto::new().signing_public(), unix_time());
+
+        assert_eq!(verified, Err(DirectoryError::InvalidSignature));
+    }
+
+    #[test]
+    fn tampered_directory_is_rejected() {
+        let crypto = ServerCrypto::new();
+        let mut directory = SignedDirectory::issue(relays(), Duration::from_secs(90), &crypto);
+        directory.document.relays[0].addr = "10.0.0.1:9001".parse().unwrap();
+
+        let verified = directory.verify(&crypto.signing_public(), unix_time());
+
+        assert_eq!(verified, Err(DirectoryError::InvalidSignature));
+    }
+
+    #[test]
+    fn directory_is_only_valid_in_its_time_span() {
+        let crypto = ServerCrypto::new();
+        let directory = SignedDirectory::issue(relays(), Duration::from_secs(90), &crypto);
+        let valid_after = directory.document.valid_after;
+        let key = crypto.signing_public();
+
+        let early = directory.clone().verify(&key, valid_after - CLOCK_SKEW - 1);
+        let late = directory.verify(&key, valid_after + 90 + CLOCK_SKEW + 1);
+
+        assert_eq!(early, Err(DirectoryError::NotYetValid));
+        assert_eq!(late, Err(DirectoryError::Expired));
+    }
+}
@@ -0,0 +1,130 @@
+use std::{fmt, io};
+
+use crate::crypto::CipherError;
+
+/// Errors that can occur while decoding onions from the wire.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The underlying reader failed, or the stream ended early.
+    Io(io::Error),
+    /// The frame could not be decrypted.
+    Cipher(CipherError),
+    /// The frame did not authenticate as the frame with the given counter. It was
+    /// tampered with, replayed, reordered, or a frame before it was dropped.
+    FrameRejected(u64),
+    /// The frame or message with the given length was longer than the reader accepts.
+    FrameTooLarge(u32),
+    /// A cell did not have the fixed cell length, or its header claimed more data than fits.
+    MalformedCell,
+    /// A VarInt did not fit in its target type.
+    VarIntOverflow,
+    /// A VarInt was cut short.
+    VarIntMalformed,
+    /// The TGT field of the header held an unknown value.
+    InvalidTarget(u8),
+    /// The TGT field of the header held a target newer than the negotiated protocol version.
+    UnnegotiatedTarget(u8),
+    /// A domain target was empty, longer than 255 bytes or not valid UTF-8.
+    InvalidDomain,
+    /// The MSGT field of the header held an unknown value.
+    InvalidMessageType(u32),
+    /// The MSGT field of the header held a type newer than the negotiated protocol version.
+    UnnegotiatedMessageType(u32),
+    /// The client type bit of a HelloRequest held an unknown value.
+    InvalidClientType(u8),
+    /// The ip bit of a serialized relay held an unknown value.
+    InvalidIpVersion(u8),
+    /// The cipher suite of a HelloResponse held an unknown value.
+    InvalidCipherSuite(u8),
+    /// The message content did not have the length its type requires.
+    InvalidMessageLength(&'static str),
+    /// The peer sent a Sendme for data that was never sent.
+    FlowControlViolation,
+    /// The reason of a StreamRefused held an unknown value.
+    InvalidRefusalReason(u8),
+    /// A relay of the circuit closed it, giving the reason if any.
+    CircuitClosed(Option<String>),
+    /// A directory document had a version this node doesn't know.
+    InvalidDirectoryVersion(u8),
+    /// The peer sent a message where it makes no sense, instead of the one named.
+    UnexpectedMessage(&'static str),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(err) => write!(f, "io error: {}", err),
+            ProtocolError::Cipher(err) => write!(f, "cipher error: {}", err),
+            ProtocolError::FrameRejected(counter) => write!(f, "frame {} rejected", counter),
+            ProtocolError::FrameTooLarge(len) => {
+                write!(f, "frame of {} bytes exceeds the maximum frame size", len)
+            }
+            ProtocolError::MalformedCell => write!(f, "malformed cell"),
+            ProtocolError::VarIntOverflow => write!(f, "varint overflow"),
+            ProtocolError::VarIntMalformed => write!(f, "malformed varint"),
+            ProtocolError::InvalidTarget(tgt) => write!(f, "invalid target {}", tgt),
+            ProtocolError::UnnegotiatedTarget(tgt) => {
+                write!(f, "target {} needs a newer protocol version", tgt)
+            }
+            ProtocolError::InvalidDomain => write!(f, "invalid domain target"),
+            ProtocolError::InvalidMessageType(msgt) => write!(f, "invalid message type {}", msgt),
+            ProtocolError::UnnegotiatedMessageType(msgt) => {
+                write!(f, "message type {} needs a newer protocol version", msgt)
+            }
+            ProtocolError::InvalidClientType(ct) => write!(f, "invalid client type {}", ct),
+            ProtocolError::InvalidIpVersion(bit) => write!(f, "invalid ip bit {}", bit),
+            ProtocolError::InvalidCipherSuite(id) => write!(f, "invalid cipher suite {}", id),
+            ProtocolError::InvalidMessageLength(what) => {
+                write!(f, "invalid {} message length", what)
+            }
+            ProtocolError::FlowControlViolation => write!(f, "flow control violation"),
+            ProtocolError::InvalidRefusalReason(id) => write!(f, "invalid refusal reason {}", id),
+            ProtocolError::CircuitClosed(Some(reason)) => write!(f, "circuit closed: {}", reason),
+            ProtocolError::CircuitClosed(None) => write!(f, "circuit closed"),
+            ProtocolError::InvalidDirectoryVersion(version) => {
+                write!(f, "invalid directory version {}", version)
+            }
+            ProtocolError::UnexpectedMessage(expected) => {
+                write!(f, "unexpected message, expected {}", expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(err) => Some(err),
+            ProtocolError::Cipher(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        ProtocolError::Io(err)
+    }
+}
+
+impl From<CipherError> for ProtocolError {
+    fn from(err: CipherError) -> Self {
+        ProtocolError::Cipher(err)
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(err: ProtocolError) -> Self {
+        match err {
+            ProtocolError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+impl ProtocolError {
+    /// Returns true if the error was caused by the peer closing the stream.
+    pub fn is_eof(&self) -> bool {
+        matches!(self, ProtocolError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof)
+    }
+}
@@ -1,408 +1,493 @@
 use super::{
-    onion::{ClientType, HelloRequest, Onion, Relay, RelayPingRequest, Target},
-    varint::{self, VarIntWritable},
+    codec::{
+        decode_frame, decode_onion, encode_frame, encode_onion, join_cell, split_cells, Decoded,
+    },
+    error::ProtocolError,
+    onion::{Onion, PROTOCOL_VERSIONS},
 };
-use crate::{crypto::SymmetricCipher, protocol::onion::Message};
+use crate::crypto::{CipherError, CountedCipher, SymmetricCipher, TAG_LEN};
 
-use super::{bitwriter::BitWriter, varint::VarIntReadable};
-use async_std::io::{
-    BufReader, BufWriter, Cursor, Error, ErrorKind, Read, ReadExt, Result, Write, WriteExt,
+use bytes::BytesMut;
+use futures::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
 };
 use std::{
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    io::{ErrorKind, Result},
     pin::Pin,
 };
 
-pub struct RawOnionReader<T: Read> {
+/// The largest frame a reader accepts unless given another limit.
+pub const DEFAULT_MAX_FRAME_LEN: u32 = 1 << 20;
+
+pub struct RawOnionReader<T: AsyncRead> {
     reader: Pin<Box<BufReader<T>>>,
+    version: u8,
+    cells: bool,
+    max_frame_len: u32,
 }
 
-impl<T: Read> RawOnionReader<T> {
+impl<T: AsyncRead> RawOnionReader<T> {
+    /// Creates a reader that speaks the oldest protocol version without cells until told
+    /// otherwise.
     pub fn new(reader: T) -> Self {
         Self {
             reader: Box::pin(BufReader::new(reader)),
+            version: *PROTOCOL_VERSIONS.start(),
+            cells: false,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
         }
     }
 
+    /// Sets the largest frame the reader accepts. Longer frames are rejected with
+    /// ProtocolError::FrameTooLarge before anything is allocated for them.
+    pub fn with_max_frame_len(mut self, max_frame_len: u32) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    /// Sets the protocol version negotiated in the handshake, which decides the
+    /// message types that may be read.
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Sets whether the encrypted frames of the link are cells, as negotiated in the handshake.
+    pub fn with_cells(mut self, cells: bool) -> Self {
+        self.cells = cells;
+        self
+    }
+
     pub fn with_cipher<C: SymmetricCipher>(self, cipher: C) -> OnionReader<T, C> {
-        OnionReader::new(self.reader, cipher)
+        OnionReader::new(
+            self.reader,
+            cipher,
+            self.version,
+            self.cells,
+            self.max_frame_len,
+        )
     }
 
-    pub async fn read(&mut self) -> Result<Onion> {
-        read_onion(&mut self.reader).await
+    pub async fn read(&mut self) -> std::result::Result<Onion, ProtocolError> {
+        read_onion(&mut self.reader, self.version, self.max_frame_len).await
     }
 }
 
-pub struct OnionReader<R: Read, C: SymmetricCipher> {
+pub struct OnionReader<R: AsyncRead, C: SymmetricCipher> {
     reader: Pin<Box<BufReader<R>>>,
-    cipher: C,
+    cipher: CountedCipher<C>,
+    version: u8,
+    cells: bool,
+    max_frame_len: u32,
 }
 
-impl<R: Read, C: SymmetricCipher> OnionReader<R, C> {
-    fn new(reader: Pin<Box<BufReader<R>>>, cipher: C) -> Self {
-        Self { reader, cipher }
+impl<R: AsyncRead, C: SymmetricCipher> OnionReader<R, C> {
+    fn new(
+        reader: Pin<Box<BufReader<R>>>,
+        cipher: C,
+        version: u8,
+        cells: bool,
+        max_frame_len: u32,
+    ) -> Self {
+        Self {
+            reader,
+            cipher: CountedCipher::new(cipher),
+            version,
+            cells,
+            max_frame_len,
+        }
+    }
+
+    /// Gets the protocol version negotiated on the link.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Waits until the peer sent something or closed the link, without reading it. Unlike
+    /// read, it can be cancelled without losing part of an onion.
+    pub async fn ready(&mut self) -> std::result::Result<(), ProtocolError> {
+        self.reader.as_mut().fill_buf().await?;
+        Ok(())
+    }
+
+    /// Reads the next onion of the link, joining its cells if the link uses them. Frames
+    /// that were replayed, reordered or follow a dropped frame are rejected with
+    /// ProtocolError::FrameRejected.
+    pub async fn read(&mut self) -> std::result::Result<Onion, ProtocolError> {
+        let plain_onion = if self.cells {
+            let mut plain_onion = Vec::new();
+            loop {
+                let more = join_cell(&mut plain_onion, &self.read_frame().await?)?;
+                if plain_onion.len() > self.max_frame_len as usize {
+                    return Err(ProtocolError::FrameTooLarge(plain_onion.len() as u32));
+                }
+                if !more {
+                    break plain_onion;
+                }
+            }
+        } else {
+            self.read_frame().await?
+        };
+        decode_plain_onion(&plain_onion, self.version)
     }
 
-    pub async fn read(&mut self) -> Result<Onion> {
-        let len = read_varint::<BufReader<R>, u32>(&mut self.reader).await?;
-        let mut cipher_onion: Vec<u8> = vec![0u8; len as usize];
-        self.reader.read_exact(&mut cipher_onion).await?;
-        let plain_onion = self.cipher.decrypt(&cipher_onion);
-        read_onion(&mut Box::pin(Cursor::new(plain_onion))).await
+    async fn read_frame(&mut self) -> std::result::Result<Vec<u8>, ProtocolError> {
+        let max_frame_len = self.max_frame_len;
+        let cipher_onion = read_decoded(&mut self.reader, |src| {
+            Ok(decode_frame(src, max_frame_len)?.map(<[u8]>::to_vec))
+        })
+        .await?;
+        open_frame(&mut self.cipher, &cipher_onion)
     }
 }
 
-pub struct RawOnionWriter<T: Write> {
+pub struct RawOnionWriter<T: AsyncWrite> {
     writer: Pin<Box<BufWriter<T>>>,
+    version: u8,
+    cells: bool,
 }
-impl<T: Write> RawOnionWriter<T> {
+impl<T: AsyncWrite> RawOnionWriter<T> {
+    /// Creates a writer that speaks the oldest protocol version without cells until told
+    /// otherwise.
     pub fn new(writer: T) -> Self {
         let writer = Box::pin(BufWriter::new(writer));
-        Self { writer }
+        Self {
+            writer,
+            version: *PROTOCOL_VERSIONS.start(),
+            cells: false,
+        }
+    }
+
+    /// Sets the protocol version negotiated in the handshake, which decides the
+    /// message types that may be written.
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
     }
+
+    /// Sets whether the encrypted frames of the link are cells, as negotiated in the handshake.
+    pub fn with_cells(mut self, cells: bool) -> Self {
+        self.cells = cells;
+        self
+    }
+
     pub fn with_cipher<C: SymmetricCipher>(self, cipher: C) -> OnionWriter<T, C> {
-        OnionWriter::new(self.writer, cipher)
+        OnionWriter::new(self.writer, cipher, self.version, self.cells)
     }
 
     pub async fn write(&mut self, onion: Onion) -> Result<()> {
-        write_onion(&mut self.writer, onion).await
+        write_onion(&mut self.writer, onion, self.version).await
     }
 }
 
-pub struct OnionWriter<T: Write, C: SymmetricCipher> {
+pub struct OnionWriter<T: AsyncWrite, C: SymmetricCipher> {
     writer: Pin<Box<BufWriter<T>>>,
-    cipher: C,
+    cipher: CountedCipher<C>,
+    version: u8,
+    cells: bool,
 }
 
-impl<T: Write, C: SymmetricCipher> OnionWriter<T, C> {
-    fn new(writer: Pin<Box<BufWriter<T>>>, cipher: C) -> Self {
-        Self { writer, cipher }
+impl<T: AsyncWrite, C: SymmetricCipher> OnionWriter<T, C> {
+    fn new(writer: Pin<Box<BufWriter<T>>>, cipher: C, version: u8, cells: bool) -> Self {
+        Self {
+            writer,
+            cipher: CountedCipher::new(cipher),
+            version,
+            cells,
+        }
     }
 
+    /// Writes an onion to the link, split into cells if the link uses them.
     pub async fn write(&mut self, onion: Onion) -> Result<()> {
-        let mut cursor = Cursor::new(Vec::new());
-        write_onion(&mut Box::pin(BufWriter::new(cursor.get_mut())), onion).await?;
-        let mut plain_onion = cursor.into_inner();
-        let cipher_onion = self.cipher.encrypt(&mut plain_onion);
-
-        let (len_vi, len_vi_bytes) = (cipher_onion.len() as u32).to_varint();
-        let len_vi = &len_vi[..len_vi_bytes];
-        self.writer.write_all(len_vi).await?;
-        self.writer.write_all(&cipher_onion).await?;
+        let mut plain_onion = BytesMut::new();
+        encode_onion(onion, self.version, &mut plain_onion)?;
+
+        let mut link = BytesMut::new();
+        if self.cells {
+            for cell in split_cells(&plain_onion) {
+                self.seal_frame(&cell, &mut link)?;
+            }
+        } else {
+            self.seal_frame(&plain_onion, &mut link)?;
+        }
+        self.writer.write_all(&link).await?;
         self.writer.flush().await?;
 
         Ok(())
     }
-}
 
-async fn read_varint<R: Read, V: VarIntReadable>(reader: &mut Pin<Box<R>>) -> Result<V::Target> {
-    let mut buf = [0u8; u32::MAX_VARINT_LEN];
-    let mut i = 0;
-    loop {
-        reader.read_exact(&mut buf[i..i + 1]).await?;
-        i += 1;
-        match V::from_varint(&buf[..i]) {
-            Ok((value, _bytes)) => {
-                return Ok(value);
-            }
-            Err(varint::Error::Malformed) => {
-                // not enough data, continue
-            }
-            Err(varint::Error::Overflow) => {
-                return Err(Error::new(ErrorKind::InvalidData, "varint overflow"));
-            }
-        }
+    fn seal_frame(&mut self, plain_frame: &[u8], dst: &mut BytesMut) -> Result<()> {
+        let cipher_onion = self.cipher.seal(plain_frame).map_err(ProtocolError::from)?;
+        encode_frame(&cipher_onion, dst);
+
+        Ok(())
     }
 }
 
-pub fn serialize_relays(relays: &[Relay]) -> Vec<u8> {
-    let mut vec = Vec::new();
-    for relay in relays {
-        let mut leading = 0u8;
-        let ip_bit = if relay.addr.is_ipv6() { 1 } else { 0 };
-        leading.write_bits(7, ip_bit, 1);
-        vec.push(leading);
-
-        match relay.addr.ip() {
-            IpAddr::V4(v4) => vec.extend(v4.octets().iter()),
-            IpAddr::V6(v6) => vec.extend(v6.octets().iter()),
-        };
-        vec.extend(relay.addr.port().to_be_bytes().iter());
-        vec.extend(relay.pub_key.iter());
+/// Seals an onion as one layer of a circuit, using the next counter of the layer.
+/// param onion: The onion to seal
+/// param cipher: The layer's cipher in the direction the onion travels
+/// param version: The protocol version negotiated with the layer's relay
+pub async fn seal_layer<C: SymmetricCipher>(
+    onion: Onion,
+    cipher: &mut CountedCipher<C>,
+    version: u8,
+) -> Result<Vec<u8>> {
+    let mut plain_onion = BytesMut::new();
+    encode_onion(onion, version, &mut plain_onion)?;
+    Ok(cipher.seal(&plain_onion).map_err(ProtocolError::from)?)
+}
 
-        let (id, id_bytes) = relay.id.to_varint();
-        vec.extend(id[0..id_bytes].iter());
+/// Pads the layer of the next relay that was peeled out of a layer of len bytes back to that
+/// length. A peeled layer carries its tag in front. The tag moves back to the end and the
+/// keystream of the peeled frame past its end is inserted before it, so the padding looks
+/// like ciphertext to the next relay. Layers that can't be padded are passed on as they are.
+/// param layer: The peeled layer of the next relay
+/// param len: The length of the layer it was peeled out of
+/// param cipher: The cipher it was peeled with
+/// param counter: The counter of the frame it was peeled out of
+pub fn pad_layer<C: SymmetricCipher>(
+    mut layer: Vec<u8>,
+    len: usize,
+    cipher: &CountedCipher<C>,
+    counter: u64,
+) -> std::result::Result<Vec<u8>, ProtocolError> {
+    if layer.len() < TAG_LEN || layer.len() > len {
+        return Ok(layer);
     }
-
-    vec
+    let plain_len = len - TAG_LEN;
+    let pad = cipher.keystream(counter, plain_len..plain_len + len - layer.len())?;
+    let tag: Vec<u8> = layer.drain(..TAG_LEN).collect();
+    layer.extend(pad);
+    layer.extend(tag);
+    Ok(layer)
 }
 
-pub fn deserialize_relays(mut data: &[u8]) -> Result<Vec<Relay>> {
-    let range_err = || Error::new(ErrorKind::InvalidData, "slice out of range");
-    let mut vec = Vec::new();
-
-    while data.len() > 0 {
-        let ip_bit = data.get(0).ok_or_else(range_err)?.read_bits(7, 1);
-        data = &data[1..];
-        let (ip_bytes, ip) = match ip_bit {
-            0 => (
-                4,
-                IpAddr::V4(From::<[u8; 4]>::from(
-                    data.get(0..4).ok_or_else(range_err)?.try_into().unwrap(),
-                )),
-            ),
-            1 => (
-                16,
-                IpAddr::V6(From::<[u8; 16]>::from(
-                    data.get(0..16).ok_or_else(range_err)?.try_into().unwrap(),
-                )),
-            ),
-            _ => panic!("invalid ip bit"),
-        };
-        data = &data[ip_bytes..];
-
-        let port = u16::from_be_bytes(data.get(0..2).ok_or_else(range_err)?.try_into().unwrap());
-        data = &data[2..];
+/// Undoes pad_layer on a sealed layer, leaving it the way it is peeled out of the layer of
+/// the previous relay: the pad before the tag is dropped and the tag moves in front.
+/// param layer: The sealed layer
+/// param pad_len: The length of the pad the previous relay inserts
+pub fn unpad_layer(mut layer: Vec<u8>, pad_len: usize) -> Vec<u8> {
+    let tag = layer.split_off(layer.len() - TAG_LEN);
+    layer.truncate(layer.len() - pad_len);
+    [tag, layer].concat()
+}
 
-        let pub_key = data.get(0..32).ok_or_else(range_err)?.try_into().unwrap();
-        data = &data[32..];
+/// Opens one layer of a circuit that was sealed with seal_layer.
+/// param payload: The sealed layer
+/// param cipher: The layer's cipher in the direction the onion travels
+/// param version: The protocol version negotiated with the layer's relay
+pub async fn open_layer<C: SymmetricCipher>(
+    payload: &[u8],
+    cipher: &mut CountedCipher<C>,
+    version: u8,
+) -> std::result::Result<Onion, ProtocolError> {
+    let plain_onion = open_frame(cipher, payload)?;
+    decode_plain_onion(&plain_onion, version)
+}
 
-        let (id, id_bytes) = u32::from_varint(data)
-            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid varint"))?;
-        data = &data[id_bytes..];
+fn open_frame<C: SymmetricCipher>(
+    cipher: &mut CountedCipher<C>,
+    frame: &[u8],
+) -> std::result::Result<Vec<u8>, ProtocolError> {
+    cipher.open(frame).map_err(|err| match err {
+        CipherError::TagMismatch => ProtocolError::FrameRejected(cipher.counter()),
+        err => ProtocolError::Cipher(err),
+    })
+}
 
-        vec.push(Relay {
-            id,
-            pub_key,
-            addr: SocketAddr::new(ip, port),
-        });
+// Decodes the onion of a decrypted frame, which can't announce a message longer than
+// the frame itself.
+fn decode_plain_onion(
+    plain_onion: &[u8],
+    version: u8,
+) -> std::result::Result<Onion, ProtocolError> {
+    match decode_onion(plain_onion, version, plain_onion.len() as u32)? {
+        Decoded::Done(onion, _) => Ok(onion),
+        Decoded::NeedMore(_) => Err(ProtocolError::Io(ErrorKind::UnexpectedEof.into())),
     }
-
-    Ok(vec)
 }
 
-pub async fn read_onion<R: Read>(reader: &mut Pin<Box<R>>) -> Result<Onion> {
-    let mut b = [0u8; 1];
-    reader.read_exact(&mut b[0..1]).await?;
-
-    let msgt = b[0].read_bits(5, 3);
-    let cip = b[0].read_bits(3, 1);
-    let opt1 = b[0].read_bits(2, 1);
-    let tgt = b[0].read_bits(0, 2);
-
-    let target = match tgt {
-        // Relay
-        0 => {
-            let relay_id = read_varint::<R, u32>(reader).await?;
-            Target::Relay(relay_id)
-        }
-        // IP
-        1 => {
-            let ipv4 = opt1 == 0;
-            let ip = match ipv4 {
-                true => {
-                    let mut ip_buf = [0u8; 4];
-                    reader.read_exact(&mut ip_buf).await?;
-                    IpAddr::V4(Ipv4Addr::from(ip_buf))
-                }
-                false => {
-                    let mut ip_buf = [0u8; 16];
-                    reader.read_exact(&mut ip_buf).await?;
-                    IpAddr::V6(Ipv6Addr::from(ip_buf))
-                }
-            };
-            let mut port_buf = [0u8; 2];
-            reader.read_exact(&mut port_buf).await?;
-            let port: u16 = u16::from_be_bytes(port_buf);
-
-            Target::IP(SocketAddr::new(ip, port))
+// Reads from the reader until the value can be decoded, reading only the bytes the
+// decoder asks for so that nothing after the value is consumed.
+async fn read_decoded<R: AsyncRead, T>(
+    reader: &mut Pin<Box<R>>,
+    decode: impl Fn(&[u8]) -> std::result::Result<Decoded<T>, ProtocolError>,
+) -> std::result::Result<T, ProtocolError> {
+    let mut buf = Vec::new();
+    loop {
+        match decode(&buf)? {
+            Decoded::Done(value, _) => return Ok(value),
+            Decoded::NeedMore(more) => {
+                let len = buf.len();
+                buf.resize(len + more, 0);
+                reader.read_exact(&mut buf[len..]).await?;
+            }
         }
-        // Current
-        2 => Target::Current,
-        _ => return Err(Error::new(ErrorKind::InvalidData, "invalid tgt")),
-    };
-
-    let circuit_id = match cip {
-        0 => None,
-        1 => Some(read_varint::<R, u32>(reader).await?),
-        _ => panic!("invalid cip"),
-    };
-
-    let message_len: u32 = read_varint::<R, u32>(reader).await?;
-
-    let mut message_raw: Vec<u8> = vec![0u8; message_len as usize];
-    reader.read_exact(&mut message_raw[..]).await?;
-
-    let message = match msgt {
-        0 => Message::HelloRequest(HelloRequest {
-            client_type: match message_raw[0].read_bits(7, 1) {
-                0 => ClientType::Relay,
-                1 => ClientType::Consumer,
-                _ => panic!("invalid client type"),
-            },
-            public_key: message_raw[1..].try_into().map_err(|_| {
-                Error::new(
-                    ErrorKind::InvalidData,
-                    "invalid hello request message length",
-                )
-            })?,
-        }),
-        1 => Message::HelloResponse(message_raw.try_into().map_err(|_| {
-            Error::new(
-                ErrorKind::InvalidData,
-                "invalid hello response message length",
-            )
-        })?),
-        2 => Message::Close(if message_len > 0 {
-            Some(String::from_utf8_lossy(&message_raw).to_string())
-        } else {
-            None
-        }),
-        3 => Message::Payload(message_raw),
-        4 => Message::GetRelaysRequest(),
-        5 => Message::GetRelaysResponse(deserialize_relays(&message_raw)?),
-        6 => Message::RelayPingRequest(RelayPingRequest {
-            port: u16::from_be_bytes(message_raw[0..2].try_into().unwrap()),
-            signing_public: message_raw[2..]
-                .try_into()
-                .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid relay ping request"))?,
-        }),
-        7 => Message::RelayPingResponse(),
-        _ => panic!("illegal message id"),
-    };
+    }
+}
 
-    Ok(Onion {
-        circuit_id,
-        message,
-        target,
-    })
+/// Reads an onion, rejecting message types newer than the negotiated version.
+/// param reader: The reader to read the onion from
+/// param version: The protocol version negotiated with the peer
+/// param max_message_len: The longest message content to allocate for
+pub async fn read_onion<R: AsyncRead>(
+    reader: &mut Pin<Box<R>>,
+    version: u8,
+    max_message_len: u32,
+) -> std::result::Result<Onion, ProtocolError> {
+    read_decoded(reader, |src| decode_onion(src, version, max_message_len)).await
 }
 
-pub async fn write_onion<'a, W: Write>(
+/// Writes an onion, refusing message types newer than the negotiated version.
+/// param writer: The writer to write the onion to
+/// param onion: The onion to write
+/// param version: The protocol version negotiated with the peer
+pub async fn write_onion<W: AsyncWrite>(
     writer: &mut Pin<Box<BufWriter<W>>>,
     onion: Onion,
+    version: u8,
 ) -> Result<()> {
-    let mut buf = [0u8; 128];
-    let target_index = 1;
+    let mut buf = BytesMut::new();
+    encode_onion(onion, version, &mut buf)?;
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
 
-    let (tgt, opt1, offset) = match onion.target {
-        Target::Relay(id) => {
-            let len = id.write_varint(&mut buf[1..]).unwrap();
-            (0, 0, len)
-        }
-        Target::IP(addr) => {
-            let (ip_len, opt1) = match addr.ip() {
-                IpAddr::V4(v4) => {
-                    let octets = v4.octets();
-                    buf[target_index..]
-                        .iter_mut()
-                        .zip(octets)
-                        .for_each(|(dst, src)| *dst = src);
-                    (octets.len(), 0)
-                }
-                IpAddr::V6(v6) => {
-                    let octets = v6.octets();
-                    buf[target_index..]
-                        .iter_mut()
-                        .zip(octets)
-                        .for_each(|(dst, src)| *dst = src);
-                    (octets.len(), 1)
-                }
-            };
-            let port_index = target_index + ip_len;
-            let port_bytes = addr.port().to_be_bytes();
-            buf[port_index..]
-                .iter_mut()
-                .zip(port_bytes)
-                .for_each(|(dst, src)| *dst = src);
-            (1, opt1, ip_len + port_bytes.len())
-        }
-        Target::Current => (2, 0, 0usize),
-    };
+    Ok(())
+}
 
-    let circuit_id_index = target_index + offset;
-    let (cip, offset) = match onion.circuit_id {
-        Some(id) => (1, id.write_varint(&mut buf[circuit_id_index..]).unwrap()),
-        None => (0, 0),
+#[cfg(test)]
+// Header bytes in the tests are grouped by field: MSGT, EXT, CIP, OPT1, TGT.
+#[allow(clippy::unusual_byte_groupings)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::{CipherError, CipherSuite},
+        protocol::{
+            codec::{CELL_DATA_LEN, CELL_LEN},
+            onion::{
+                ClientType, HelloRequest, HelloResponse, Message, PolicySummary, Relay,
+                RelayPingRequest, Target,
+            },
+        },
     };
+    use futures::io::Cursor;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-    let message_len_index = circuit_id_index + offset;
-
-    // TODO: refactor so this variable isnt needed
-    let mut message_vec = None;
-    let (msgt, message_len) = match onion.message {
-        Message::HelloRequest(ref data) => (0, data.public_key.len() + 1),
-        Message::HelloResponse(ref data) => (1, data.len()),
-        Message::Close(ref text) => (2, text.as_ref().map_or(0, |x| x.as_bytes().len())),
-        Message::Payload(ref data) => (3, data.len()),
-        Message::GetRelaysRequest() => (4, 0),
-        Message::GetRelaysResponse(ref data) => {
-            let vec = serialize_relays(&data[..]);
-            let len = vec.len();
-            message_vec = Some(vec);
-            (5, len)
+    struct NoopSymmetricCipher {}
+    impl SymmetricCipher for NoopSymmetricCipher {
+        fn encrypt(
+            &self,
+            _nonce: u64,
+            plaintext: &[u8],
+        ) -> std::result::Result<Vec<u8>, CipherError> {
+            Ok(Vec::from(plaintext))
         }
-        Message::RelayPingRequest(ref data) => (6, 34),
-        Message::RelayPingResponse() => (7, 0),
-    };
+        fn decrypt(
+            &self,
+            _nonce: u64,
+            ciphertext: &[u8],
+        ) -> std::result::Result<Vec<u8>, CipherError> {
+            Ok(Vec::from(ciphertext))
+        }
+    }
 
-    buf[0].write_bits(5, msgt, 3);
-    buf[0].write_bits(3, cip, 1);
-    buf[0].write_bits(2, opt1, 1);
-    buf[0].write_bits(0, tgt, 2);
-
-    let offset = message_len
-        .write_varint(&mut buf[message_len_index..])
-        .unwrap();
-    let message_index = message_len_index + offset;
-    writer.write_all(&buf[..message_index]).await?;
-
-    match onion.message {
-        Message::HelloRequest(req) => {
-            let mut bitbuf = [0u8];
-            let client_bits = match req.client_type {
-                ClientType::Relay => 0,
-                ClientType::Consumer => 1,
-            };
-            bitbuf[0].write_bits(7, client_bits, 1);
-            writer.write_all(&bitbuf).await?;
-            writer.write_all(&req.public_key[..]).await?;
+    // Prefixes frames with their nonce and only decrypts them with the same nonce.
+    struct SequencedSymmetricCipher {}
+    impl SymmetricCipher for SequencedSymmetricCipher {
+        fn encrypt(
+            &self,
+            nonce: u64,
+            plaintext: &[u8],
+        ) -> std::result::Result<Vec<u8>, CipherError> {
+            let mut ciphertext = nonce.to_be_bytes().to_vec();
+            ciphertext.extend(plaintext);
+            Ok(ciphertext)
         }
-        Message::HelloResponse(signed_public_key) => {
-            writer.write_all(&signed_public_key[..]).await?
+        fn decrypt(
+            &self,
+            nonce: u64,
+            ciphertext: &[u8],
+        ) -> std::result::Result<Vec<u8>, CipherError> {
+            match ciphertext.split_at(8) {
+                (prefix, plaintext) if prefix == nonce.to_be_bytes() => Ok(Vec::from(plaintext)),
+                _ => Err(CipherError::TagMismatch),
+            }
         }
-        Message::Close(text) => {
-            writer
-                .write_all(text.as_ref().map_or(&[] as &[u8], |x| x.as_bytes()))
-                .await?
+    }
+
+    // Writes each onion as a separate encrypted frame of the same link.
+    async fn sequenced_frames(onions: Vec<Onion>) -> Vec<Vec<u8>> {
+        let mut link = Vec::new();
+        let mut writer = RawOnionWriter::new(&mut link).with_cipher(SequencedSymmetricCipher {});
+        let mut frame_ends = Vec::new();
+        for onion in onions {
+            writer.write(onion).await.unwrap();
+            frame_ends.push(writer.writer.get_ref().len());
         }
-        Message::Payload(data) => writer.write_all(&data[..]).await?,
-        Message::GetRelaysRequest() => (),
-        Message::GetRelaysResponse(_relays) => writer.write_all(&message_vec.unwrap()).await?,
-        Message::RelayPingRequest(request) => {
-            writer.write_all(&request.port.to_be_bytes()).await?;
-            writer.write_all(&request.signing_public).await?;
+        drop(writer);
+
+        let mut start = 0;
+        frame_ends
+            .into_iter()
+            .map(|end| {
+                let frame = link[start..end].to_vec();
+                start = end;
+                frame
+            })
+            .collect()
+    }
+
+    fn close_onion(reason: &str) -> Onion {
+        Onion {
+            circuit_id: None,
+            target: Target::Current,
+            message: Message::Close(Some(reason.to_string())),
         }
-        Message::RelayPingResponse() => (),
-    };
+    }
 
-    writer.flush().await?;
+    async fn read_sequenced_frames(
+        frames: &[&Vec<u8>],
+    ) -> Vec<std::result::Result<Onion, ProtocolError>> {
+        let link: Vec<u8> = frames
+            .iter()
+            .flat_map(|frame| frame.iter().copied())
+            .collect();
+        let mut reader =
+            RawOnionReader::new(Cursor::new(link)).with_cipher(SequencedSymmetricCipher {});
+        let mut onions = Vec::new();
+        for _ in frames {
+            onions.push(reader.read().await);
+        }
+        onions
+    }
 
-    Ok(())
-}
+    #[async_std::test]
+    async fn encrypted_onion_replay_is_rejected() {
+        let frames = sequenced_frames(vec![close_onion("first")]).await;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let onions = read_sequenced_frames(&[&frames[0], &frames[0]]).await;
 
-    struct NoopSymmetricCipher {}
-    impl SymmetricCipher for NoopSymmetricCipher {
-        fn encrypt(&self, ciphertext: &[u8]) -> Vec<u8> {
-            Vec::from(ciphertext)
-        }
-        fn decrypt(&self, plaintext: &[u8]) -> Vec<u8> {
-            Vec::from(plaintext)
-        }
+        assert_eq!(onions[0].as_ref().unwrap(), &close_onion("first"));
+        assert!(matches!(onions[1], Err(ProtocolError::FrameRejected(1))));
+    }
+
+    #[async_std::test]
+    async fn encrypted_onion_reordering_is_rejected() {
+        let frames = sequenced_frames(vec![close_onion("first"), close_onion("second")]).await;
+
+        let onions = read_sequenced_frames(&[&frames[1], &frames[0]]).await;
+
+        assert!(matches!(onions[0], Err(ProtocolError::FrameRejected(0))));
+    }
+
+    #[async_std::test]
+    async fn encrypted_onion_drop_is_rejected() {
+        let frames = sequenced_frames(vec![close_onion("first"), close_onion("second")]).await;
+
+        let onions = read_sequenced_frames(&[&frames[1]]).await;
+
+        assert!(matches!(onions[0], Err(ProtocolError::FrameRejected(0))));
     }
 
     macro_rules! onion_rw_test {
@@ -485,17 +570,25 @@ mod tests {
                 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7,
                 8, 9, 0, 1
             ],
+            versions: 1..=7,
+            cipher_suites: vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm],
+            cells: true,
         })
     );
 
     onion_rw_message_test!(
         onion_read_write_message_hello_response,
-        Message::HelloResponse([
-            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8,
-            9, 0, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5,
-            6, 7, 8, 9, 0, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2,
-            3, 4, 5, 6, 7, 8, 9, 0, 1,
-        ])
+        Message::HelloResponse(HelloResponse {
+            signed_public_key: [
+                0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7,
+                8, 9, 0, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3,
+                4, 5, 6, 7, 8, 9, 0, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
+                0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1,
+            ],
+            cipher_suite: CipherSuite::ChaCha20Poly1305,
+            version: 7,
+            cells: true,
+        })
     );
 
     onion_rw_message_test!(onion_read_write_message_close_empty, Message::Close(None));
@@ -514,6 +607,7 @@ mod tests {
                 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7,
                 8, 9, 0, 1
             ],
+            exit_policy: PolicySummary::default(),
         }])
     );
 
@@ -524,7 +618,8 @@ mod tests {
             signing_public: [
                 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7,
                 8, 9, 0, 1
-            ]
+            ],
+            exit_policy: PolicySummary::default(),
         })
     );
     onion_rw_message_test!(
@@ -550,6 +645,7 @@ mod tests {
                         0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4,
                         5, 6, 7, 8, 9, 0, 1,
                     ],
+                    exit_policy: PolicySummary::default(),
                 }),
             })
             .await
@@ -571,10 +667,399 @@ mod tests {
                     signing_public: [
                         0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4,
                         5, 6, 7, 8, 9, 0, 1
-                    ]
+                    ],
+                    exit_policy: PolicySummary::default(),
                 }),
             },
             onion
         );
     }
+
+    macro_rules! onion_read_error_test {
+        ($name:ident, $frame:expr, $err:pat) => {
+            #[async_std::test]
+            async fn $name() {
+                let frame: &[u8] = &$frame;
+                let mut raw_reader = RawOnionReader::new(Cursor::new(frame.to_vec()));
+
+                let err = raw_reader.read().await.unwrap_err();
+
+                assert!(matches!(err, $err), "unexpected error: {:?}", err);
+            }
+        };
+    }
+
+    onion_read_error_test!(
+        onion_read_invalid_target,
+        [0b000_0_0_1_11, 0],
+        ProtocolError::InvalidTarget(3)
+    );
+
+    onion_read_error_test!(
+        onion_read_unnegotiated_domain_target,
+        [0b011_0_0_0_11, 1, b'a', 0, 80, 0],
+        ProtocolError::UnnegotiatedTarget(3)
+    );
+
+    #[async_std::test]
+    async fn onion_read_write_domain_target() {
+        let onion = || Onion {
+            circuit_id: Some(3),
+            message: Message::Payload(vec![1, 2, 3]),
+            target: Target::Domain("example.com".to_string(), 443),
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        let mut raw_writer = RawOnionWriter::new(cursor.get_mut()).with_version(3);
+
+        raw_writer.write(onion()).await.unwrap();
+
+        cursor.set_position(0);
+        let mut raw_reader = RawOnionReader::new(cursor).with_version(3);
+        assert_eq!(onion(), raw_reader.read().await.unwrap());
+    }
+
+    onion_read_error_test!(
+        onion_read_unknown_extended_message_type,
+        [0b000_1_0_0_10, 127, 0],
+        ProtocolError::InvalidMessageType(127)
+    );
+
+    onion_read_error_test!(
+        onion_read_unnegotiated_stream_message,
+        [0b000_1_0_0_10, 8, 1, 5],
+        ProtocolError::UnnegotiatedMessageType(8)
+    );
+
+    onion_read_error_test!(
+        onion_read_extended_message_type_that_fits_msgt,
+        [0b000_1_0_0_10, 3, 0],
+        ProtocolError::InvalidMessageType(3)
+    );
+
+    onion_read_error_test!(
+        onion_read_extended_message_type_with_msgt,
+        [0b011_1_0_0_10, 9, 0],
+        ProtocolError::InvalidMessageType(9)
+    );
+
+    onion_read_error_test!(
+        onion_read_truncated_extended_message_type,
+        [0b000_1_0_0_10, 0x80],
+        ProtocolError::Io(_)
+    );
+
+    onion_read_error_test!(
+        onion_read_truncated_frame,
+        [0b011_0_0_0_10, 5, 1, 2],
+        ProtocolError::Io(_)
+    );
+
+    onion_read_error_test!(
+        onion_read_circuit_id_varint_overflow,
+        [0b011_0_1_0_10, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+        ProtocolError::VarIntOverflow
+    );
+
+    onion_read_error_test!(
+        onion_read_empty_hello_request,
+        [0b000_0_0_0_10, 0],
+        ProtocolError::InvalidMessageLength(_)
+    );
+
+    onion_read_error_test!(
+        onion_read_short_hello_request,
+        [0b000_0_0_0_10, 3, 0x80, 1, 2],
+        ProtocolError::InvalidMessageLength(_)
+    );
+
+    onion_read_error_test!(
+        onion_read_short_hello_response,
+        [0b001_0_0_0_10, 1, 0],
+        ProtocolError::InvalidMessageLength(_)
+    );
+
+    onion_read_error_test!(
+        onion_read_hello_request_without_version_range_end,
+        [
+            0b000_0_0_0_10,
+            34,
+            0x80,
+            0,
+            1,
+            2,
+            3,
+            4,
+            5,
+            6,
+            7,
+            8,
+            9,
+            0,
+            1,
+            2,
+            3,
+            4,
+            5,
+            6,
+            7,
+            8,
+            9,
+            0,
+            1,
+            2,
+            3,
+            4,
+            5,
+            6,
+            7,
+            8,
+            9,
+            0,
+            1,
+            1
+        ],
+        ProtocolError::InvalidMessageLength(_)
+    );
+
+    onion_read_error_test!(
+        onion_read_short_relay_ping_request,
+        [0b110_0_0_0_10, 1, 0xCA],
+        ProtocolError::InvalidMessageLength(_)
+    );
+
+    onion_read_error_test!(
+        onion_read_truncated_relay,
+        [0b101_0_0_0_10, 3, 0, 1, 2],
+        ProtocolError::InvalidMessageLength(_)
+    );
+
+    #[async_std::test]
+    async fn onion_read_malformed_relay_id() {
+        let mut message = vec![0u8; 1 + 4 + 2 + 32];
+        message.push(0b10000000);
+        let mut frame = vec![0b101_0_0_0_10, message.len() as u8];
+        frame.extend(message);
+
+        let err = RawOnionReader::new(Cursor::new(frame))
+            .read()
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ProtocolError::VarIntMalformed));
+    }
+
+    #[async_std::test]
+    async fn encrypted_onion_read_malformed() {
+        let frame = vec![2, 0b110_0_0_0_10, 0];
+        let mut reader =
+            RawOnionReader::new(Cursor::new(frame)).with_cipher(NoopSymmetricCipher {});
+
+        let err = reader.read().await.unwrap_err();
+
+        assert!(matches!(err, ProtocolError::InvalidMessageLength(_)));
+    }
+
+    fn payload_onion(len: usize) -> Onion {
+        Onion {
+            circuit_id: Some(7),
+            target: Target::Current,
+            message: Message::Payload((0..len).map(|i| i as u8).collect()),
+        }
+    }
+
+    async fn cell_link(onion: Onion) -> Vec<u8> {
+        let mut link = Vec::new();
+        let mut writer = RawOnionWriter::new(&mut link)
+            .with_cells(true)
+            .with_cipher(NoopSymmetricCipher {});
+        writer.write(onion).await.unwrap();
+        drop(writer);
+        link
+    }
+
+    #[async_std::test]
+    async fn cell_frames_have_constant_length() {
+        let cell_frame_len = 2 + CELL_LEN;
+
+        assert_eq!(cell_link(payload_onion(0)).await.len(), cell_frame_len);
+        assert_eq!(
+            cell_link(payload_onion(CELL_DATA_LEN - 16)).await.len(),
+            cell_frame_len
+        );
+        assert_eq!(
+            cell_link(payload_onion(1200)).await.len(),
+            3 * cell_frame_len
+        );
+    }
+
+    #[async_std::test]
+    async fn links_without_cells_send_onions_in_one_frame() {
+        let mut link = Vec::new();
+        let mut writer = RawOnionWriter::new(&mut link)
+            .with_version(*PROTOCOL_VERSIONS.end())
+            .with_cipher(NoopSymmetricCipher {});
+        writer.write(payload_onion(600)).await.unwrap();
+        drop(writer);
+
+        let onion = RawOnionReader::new(Cursor::new(&link))
+            .with_version(*PROTOCOL_VERSIONS.end())
+            .with_cipher(NoopSymmetricCipher {})
+            .read()
+            .await
+            .unwrap();
+
+        assert!(link.len() < 2 * CELL_LEN);
+        assert_eq!(onion, payload_onion(600));
+    }
+
+    #[async_std::test]
+    async fn cell_onion_read_write() {
+        let link = cell_link(payload_onion(1200)).await;
+
+        let onion = RawOnionReader::new(Cursor::new(link))
+            .with_cells(true)
+            .with_cipher(NoopSymmetricCipher {})
+            .read()
+            .await
+            .unwrap();
+
+        assert_eq!(onion, payload_onion(1200));
+    }
+
+    #[async_std::test]
+    async fn cell_read_short_cell() {
+        let frame = vec![3, 0, 1, 0b111_0_0_0_10];
+        let mut reader = RawOnionReader::new(Cursor::new(frame))
+            .with_cells(true)
+            .with_cipher(NoopSymmetricCipher {});
+
+        let err = reader.read().await.unwrap_err();
+
+        assert!(matches!(err, ProtocolError::MalformedCell));
+    }
+
+    #[async_std::test]
+    async fn cell_read_overlong_cell_data() {
+        let mut link = cell_link(payload_onion(0)).await;
+        link[2..4].copy_from_slice(&(CELL_DATA_LEN as u16 + 1).to_be_bytes());
+        let mut reader = RawOnionReader::new(Cursor::new(link))
+            .with_cells(true)
+            .with_cipher(NoopSymmetricCipher {});
+
+        let err = reader.read().await.unwrap_err();
+
+        assert!(matches!(err, ProtocolError::MalformedCell));
+    }
+
+    #[async_std::test]
+    async fn onion_read_message_too_large() {
+        let frame = vec![0b011_0_0_0_10, 17];
+        let mut reader = RawOnionReader::new(Cursor::new(frame)).with_max_frame_len(16);
+
+        let err = reader.read().await.unwrap_err();
+
+        assert!(matches!(err, ProtocolError::FrameTooLarge(17)));
+    }
+
+    #[async_std::test]
+    async fn encrypted_onion_read_frame_too_large() {
+        let frame = vec![0xFF, 0xFF, 0xFF, 0xFF, 0x0F];
+        let mut reader =
+            RawOnionReader::new(Cursor::new(frame)).with_cipher(NoopSymmetricCipher {});
+
+        let err = reader.read().await.unwrap_err();
+
+        assert!(matches!(err, ProtocolError::FrameTooLarge(u32::MAX)));
+    }
+
+    #[async_std::test]
+    async fn cell_read_onion_too_large() {
+        let link = cell_link(payload_onion(1200)).await;
+        let mut reader = RawOnionReader::new(Cursor::new(link))
+            .with_cells(true)
+            .with_max_frame_len(1000)
+            .with_cipher(NoopSymmetricCipher {});
+
+        let err = reader.read().await.unwrap_err();
+
+        assert!(matches!(err, ProtocolError::FrameTooLarge(_)));
+    }
+
+    #[test]
+    fn pad_layer_moves_the_tag_behind_keystream() {
+        // The keystream of this cipher is the nonce followed by zeros.
+        let cipher = CountedCipher::new(SequencedSymmetricCipher {});
+        let counter = u64::from_be_bytes([1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut layer = vec![2u8; TAG_LEN];
+        layer.push(1);
+
+        let padded = pad_layer(layer.clone(), layer.len() + 3, &cipher, counter).unwrap();
+
+        assert_eq!(padded[..1], [1]);
+        assert_eq!(padded[1..4], [5, 6, 7]);
+        assert_eq!(padded[4..], [2u8; TAG_LEN]);
+        assert_eq!(unpad_layer(padded, 3), layer);
+        assert_eq!(pad_layer(vec![1u8; 5], 8, &cipher, 0).unwrap(), [1u8; 5]);
+    }
+
+    async fn read_message(frame: Vec<u8>) -> Message {
+        RawOnionReader::new(Cursor::new(frame))
+            .read()
+            .await
+            .unwrap()
+            .message
+    }
+
+    #[async_std::test]
+    async fn onion_read_hello_request_without_cipher_suites() {
+        let mut frame = vec![0b000_0_0_0_10, 33, 0x80];
+        frame.extend([0u8; 32]);
+
+        let Message::HelloRequest(req) = read_message(frame).await else {
+            panic!("expected hello request");
+        };
+
+        assert_eq!(req.versions, 1..=1);
+        assert_eq!(req.cipher_suites, vec![CipherSuite::Aes256Gcm]);
+    }
+
+    #[async_std::test]
+    async fn onion_read_hello_request_skips_unknown_cipher_suites() {
+        let mut frame = vec![0b000_0_0_0_10, 37, 0x80];
+        frame.extend([0u8; 32]);
+        frame.extend([1, 1, 0xFF, 1]);
+
+        let Message::HelloRequest(req) = read_message(frame).await else {
+            panic!("expected hello request");
+        };
+
+        assert_eq!(req.cipher_suites, vec![CipherSuite::ChaCha20Poly1305]);
+    }
+
+    #[async_std::test]
+    async fn onion_read_hello_response_without_cipher_suite() {
+        let mut frame = vec![0b001_0_0_0_10, 96];
+        frame.extend([0u8; 96]);
+
+        let Message::HelloResponse(resp) = read_message(frame).await else {
+            panic!("expected hello response");
+        };
+
+        assert_eq!(resp.cipher_suite, CipherSuite::Aes256Gcm);
+        assert_eq!(resp.version, 1);
+    }
+
+    #[async_std::test]
+    async fn onion_read_hello_response_unknown_cipher_suite() {
+        let mut frame = vec![0b001_0_0_0_10, 98];
+        frame.extend([0u8; 96]);
+        frame.extend([0xFF, 1]);
+
+        let err = RawOnionReader::new(Cursor::new(frame))
+            .read()
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ProtocolError::InvalidCipherSuite(0xFF)));
+    }
 }
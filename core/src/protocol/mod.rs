@@ -1,4 +1,8 @@
 mod bitwriter;
 mod varint;
+pub mod challenge;
+pub mod codec;
+pub mod directory;
+pub mod error;
 pub mod onion;
 pub mod io;
@@ -1,19 +1,101 @@
-use async_std::net::SocketAddr;
+use std::{net::SocketAddr, ops::RangeInclusive};
+
+use crate::crypto::CipherSuite;
 
 type RelayID = u32;
 
+/// The protocol versions this node speaks. A HelloRequest without a version range
+/// comes from a node that only speaks version 1. Version 2 negotiates fixed-size cells
+/// and pads layers, version 3 adds domain name targets, version 4 adds streams,
+/// version 5 adds flow control, version 6 adds exit policies, version 7 adds signed
+/// directories, version 8 adds relay challenges and version 9 confirms opened streams.
+pub const PROTOCOL_VERSIONS: RangeInclusive<u8> = 1..=9;
+
+/// The first protocol version that advertises the exit policy summaries of relays.
+pub const EXIT_POLICY_VERSION: u8 = 6;
+
+/// The first protocol version in which index nodes answer a GetRelaysRequest with a signed
+/// Directory instead of a GetRelaysResponse.
+pub const DIRECTORY_VERSION: u8 = 7;
+
+/// The format of the directory documents this node issues and reads.
+pub const DIRECTORY_DOCUMENT_VERSION: u8 = 1;
+
+/// The first protocol version in which index nodes challenge relays before listing them.
+/// Index nodes refuse to list relays that ping with an older version.
+pub const RELAY_CHALLENGE_VERSION: u8 = 8;
+
+/// The first protocol version in which exit relays answer a BeginStream they opened with a
+/// StreamOpened.
+pub const STREAM_OPENED_VERSION: u8 = 9;
+
+/// Picks the newest version in both the offered range and PROTOCOL_VERSIONS, if any.
+/// param offered: The version range of a HelloRequest
+pub fn negotiate_version(offered: &RangeInclusive<u8>) -> Option<u8> {
+    let newest = *offered.end().min(PROTOCOL_VERSIONS.end());
+    let oldest = *offered.start().max(PROTOCOL_VERSIONS.start());
+    (oldest <= newest).then_some(newest)
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Target {
     Relay(RelayID),
     IP(SocketAddr),
+    /// A host name and port, resolved by the exit relay.
+    Domain(String, u16),
     Current,
 }
 
+impl Target {
+    /// Gets the oldest protocol version that has this target.
+    pub fn min_version(&self) -> u8 {
+        match self {
+            Target::Relay(_) | Target::IP(_) | Target::Current => 1,
+            Target::Domain(..) => 3,
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct Relay {
     pub id: RelayID,
     pub addr: SocketAddr,
     pub pub_key: [u8; 32],
+    pub exit_policy: PolicySummary,
+}
+
+/// The ports a relay lets streams exit to, as advertised to the index. Relays that
+/// predate exit policies advertise no ports.
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct PolicySummary {
+    /// Sorted, disjoint ranges of the ports the relay accepts streams to on public addresses.
+    pub ports: Vec<RangeInclusive<u16>>,
+}
+
+impl PolicySummary {
+    /// Returns true if the relay accepts streams to the given port on public addresses.
+    pub fn allows_port(&self, port: u16) -> bool {
+        self.ports.iter().any(|ports| ports.contains(&port))
+    }
+}
+
+/// The relays an index node lists, and the time span the list may be used in. Times are
+/// seconds since the Unix epoch.
+#[derive(PartialEq, Clone, Debug)]
+pub struct DirectoryDocument {
+    pub version: u8,
+    pub valid_after: u64,
+    pub valid_until: u64,
+    pub relays: Vec<Relay>,
+}
+
+/// A directory document signed by the index node that issued it, so anyone with the signing
+/// public key of the index node can check it, however they got it.
+#[derive(PartialEq, Clone, Debug)]
+pub struct SignedDirectory {
+    pub document: DirectoryDocument,
+    /// The signature over the encoded document.
+    pub signature: [u8; 64],
 }
 
 #[derive(PartialEq, Debug)]
@@ -26,18 +108,98 @@ pub enum ClientType {
 pub struct HelloRequest {
     pub client_type: ClientType,
     pub public_key: [u8; 32],
+    pub versions: RangeInclusive<u8>,
+    pub cipher_suites: Vec<CipherSuite>,
+    /// Whether the peer asks for the link to be split into cells.
+    pub cells: bool,
+}
+
+impl HelloRequest {
+    /// Creates a HelloRequest offering every protocol version and cipher suite this node
+    /// supports, asking for cells.
+    pub fn new(client_type: ClientType, public_key: [u8; 32]) -> Self {
+        Self {
+            client_type,
+            public_key,
+            versions: PROTOCOL_VERSIONS,
+            cipher_suites: CipherSuite::preferred(),
+            cells: true,
+        }
+    }
+
+    /// Sets whether the HelloRequest asks for cells.
+    pub fn with_cells(mut self, cells: bool) -> Self {
+        self.cells = cells;
+        self
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct HelloResponse {
+    pub signed_public_key: [u8; 96],
+    pub cipher_suite: CipherSuite,
+    pub version: u8,
+    /// Whether the link is split into cells.
+    pub cells: bool,
 }
 
 #[derive(PartialEq, Debug)]
 pub struct RelayPingRequest {
     pub port: u16,
     pub signing_public: [u8; 32],
+    pub exit_policy: PolicySummary,
+}
+
+/// Bytes sent over a stream of a circuit, in either direction.
+#[derive(PartialEq, Debug)]
+pub struct StreamData {
+    pub stream_id: u32,
+    pub data: Vec<u8>,
+}
+
+/// Why the exit relay did not open a stream.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum RefusalReason {
+    /// The exit policy of the relay rejects the target.
+    ExitPolicy,
+    /// The domain of the target did not resolve.
+    ResolveFailed,
+    /// None of the addresses of the target accepted the connection.
+    ConnectFailed,
+}
+
+impl RefusalReason {
+    /// Gets the id the reason is sent with.
+    pub fn id(self) -> u8 {
+        match self {
+            RefusalReason::ExitPolicy => 0,
+            RefusalReason::ResolveFailed => 1,
+            RefusalReason::ConnectFailed => 2,
+        }
+    }
+
+    /// Gets the reason with the given id, if it is known.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(RefusalReason::ExitPolicy),
+            1 => Some(RefusalReason::ResolveFailed),
+            2 => Some(RefusalReason::ConnectFailed),
+            _ => None,
+        }
+    }
+}
+
+/// The answer of the exit relay to a BeginStream it did not open.
+#[derive(PartialEq, Debug)]
+pub struct StreamRefusal {
+    pub stream_id: u32,
+    pub reason: RefusalReason,
 }
 
 #[derive(PartialEq, Debug)]
 pub enum Message {
     HelloRequest(HelloRequest),
-    HelloResponse([u8; 96]),
+    HelloResponse(HelloResponse),
 
     Close(Option<String>),
     Payload(Vec<u8>),
@@ -47,6 +209,48 @@ pub enum Message {
 
     RelayPingRequest(RelayPingRequest),
     RelayPingResponse(),
+
+    /// Opens the stream with the given ID to the target of the onion at the exit relay.
+    BeginStream(u32),
+    Data(StreamData),
+    /// Closes the stream with the given ID. Sent by either end of the circuit.
+    EndStream(u32),
+    /// Gives the peer credit to send more Data, on the stream with the given ID or on
+    /// the whole circuit.
+    Sendme(Option<u32>),
+    StreamRefused(StreamRefusal),
+    /// The answer of the index node to a GetRelaysRequest from version 7.
+    Directory(SignedDirectory),
+    /// Asks a relay that pinged the index node to sign the given nonce with its signing key.
+    RelayChallenge([u8; 32]),
+    /// The signature of the relay over the challenge it was sent.
+    RelayChallengeResponse([u8; 64]),
+    /// Tells the consumer that the exit relay connected the stream with the given ID.
+    StreamOpened(u32),
+}
+
+impl Message {
+    /// Gets the oldest protocol version that has this message type.
+    pub fn min_version(&self) -> u8 {
+        match self {
+            Message::HelloRequest(_)
+            | Message::HelloResponse(_)
+            | Message::Close(_)
+            | Message::Payload(_)
+            | Message::GetRelaysRequest()
+            | Message::GetRelaysResponse(_)
+            | Message::RelayPingRequest(_)
+            | Message::RelayPingResponse() => 1,
+            Message::BeginStream(_) | Message::Data(_) | Message::EndStream(_) => 4,
+            Message::Sendme(_) => 5,
+            Message::StreamRefused(_) => EXIT_POLICY_VERSION,
+            Message::Directory(_) => DIRECTORY_VERSION,
+            Message::RelayChallenge(_) | Message::RelayChallengeResponse(_) => {
+                RELAY_CHALLENGE_VERSION
+            }
+            Message::StreamOpened(_) => STREAM_OPENED_VERSION,
+        }
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -0,0 +1,329 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+use crate::protocol::onion::PolicySummary;
+
+/// The address ranges the default exit policy rejects: private, loopback, link-local
+/// and other ranges that are not reachable on the public internet.
+const NON_PUBLIC_RANGES: [(IpAddr, u8); 11] = [
+    (IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(100, 64, 0, 0)), 10),
+    (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)), 16),
+    (IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), 12),
+    (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16),
+    (IpAddr::V6(Ipv6Addr::UNSPECIFIED), 128),
+    (IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+    (IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), 7),
+    (IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), 10),
+];
+
+/// Decides which addresses a relay lets streams exit to. The first rule that matches an
+/// address decides, and addresses no rule matches are rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExitPolicy {
+    rules: Vec<ExitRule>,
+}
+
+impl ExitPolicy {
+    pub fn new(rules: Vec<ExitRule>) -> Self {
+        Self { rules }
+    }
+
+    /// Checks whether a stream may exit to the given address. IPv4 addresses mapped into
+    /// IPv6 are checked as the IPv4 address they are.
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(ip, addr.port()))
+            .is_some_and(|rule| rule.action == ExitAction::Accept)
+    }
+
+    /// Summarizes the policy as the ports it accepts on public addresses. Only the rules
+    /// that cover a whole address family decide a port, so rules for narrower ranges,
+    /// like the rejected private ranges, don't show in the summary.
+    pub fn summary(&self) -> PolicySummary {
+        let mut ports: Vec<RangeInclusive<u16>> = Vec::new();
+        for port in 0..=u16::MAX {
+            let accepted = self
+                .rules
+                .iter()
+                .filter(|rule| rule.network.is_none_or(|network| network.prefix_len == 0))
+                .find(|rule| rule.ports.contains(&port))
+                .is_some_and(|rule| rule.action == ExitAction::Accept);
+            if !accepted {
+                continue;
+            }
+            match ports.last_mut() {
+                Some(last) if *last.end() + 1 == port => *last = *last.start()..=port,
+                _ => ports.push(port..=port),
+            }
+        }
+        PolicySummary { ports }
+    }
+}
+
+impl Default for ExitPolicy {
+    /// Rejects the private and loopback ranges and accepts everything else.
+    fn default() -> Self {
+        let mut rules: Vec<ExitRule> = NON_PUBLIC_RANGES
+            .iter()
+            .map(|&(addr, prefix_len)| ExitRule {
+                action: ExitAction::Reject,
+                network: Some(Cidr::new(addr, prefix_len).unwrap()),
+                ports: 0..=u16::MAX,
+            })
+            .collect();
+        rules.push(ExitRule {
+            action: ExitAction::Accept,
+            network: None,
+            ports: 0..=u16::MAX,
+        });
+        Self { rules }
+    }
+}
+
+impl FromStr for ExitPolicy {
+    type Err = ExitPolicyError;
+
+    /// Parses rules separated by commas, like "reject 10.0.0.0/8:*, accept *:80-443".
+    fn from_str(policy: &str) -> Result<Self, ExitPolicyError> {
+        let rules = policy
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitAction {
+    Accept,
+    Reject,
+}
+
+/// Accepts or rejects the ports of a range of addresses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExitRule {
+    pub action: ExitAction,
+    /// The addresses of the rule, or None for every address.
+    pub network: Option<Cidr>,
+    pub ports: RangeInclusive<u16>,
+}
+
+impl ExitRule {
+    fn matches(&self, ip: IpAddr, port: u16) -> bool {
+        self.ports.contains(&port) && self.network.is_none_or(|network| network.contains(ip))
+    }
+}
+
+impl FromStr for ExitRule {
+    type Err = ExitPolicyError;
+
+    /// Parses a rule like "accept 192.168.0.0/16:80-443", "reject [::1]:*" or "accept *:22".
+    fn from_str(rule: &str) -> Result<Self, ExitPolicyError> {
+        let invalid = || ExitPolicyError::InvalidRule(rule.trim().to_string());
+
+        let (action, pattern) = rule.trim().split_once(' ').ok_or_else(invalid)?;
+        let action = match action {
+            "accept" => ExitAction::Accept,
+            "reject" => ExitAction::Reject,
+            _ => return Err(invalid()),
+        };
+        let (network, ports) = pattern.trim().rsplit_once(':').ok_or_else(invalid)?;
+
+        let network = match network {
+            "*" => None,
+            network => Some(network.parse()?),
+        };
+        let ports = match ports {
+            "*" => 0..=u16::MAX,
+            ports => {
+                let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+                let first = first.parse().map_err(|_| invalid())?;
+                let last = last.parse().map_err(|_| invalid())?;
+                if first > last {
+                    return Err(invalid());
+                }
+                first..=last
+            }
+        };
+
+        Ok(Self {
+            action,
+            network,
+            ports,
+        })
+    }
+}
+
+/// A range of addresses given by an address and the length of its network prefix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Creates the range, failing if the prefix is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, ExitPolicyError> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(ExitPolicyError::InvalidPrefixLength(prefix_len));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// Checks whether the address is in the range. Addresses of the other IP version never are.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                network.to_bits().into(),
+                ip.to_bits().into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.to_bits(), ip.to_bits(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Compares the first prefix_len bits of two addresses that are bits long.
+fn prefix_matches(network: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    let shift = (bits - prefix_len) as u32;
+    network.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = ExitPolicyError;
+
+    /// Parses "10.0.0.0/8" or "[fc00::]/7". An address without a prefix length is a
+    /// range of that one address.
+    fn from_str(cidr: &str) -> Result<Self, ExitPolicyError> {
+        let invalid = || ExitPolicyError::InvalidRule(cidr.to_string());
+
+        let (addr, prefix_len) = match cidr.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (cidr, None),
+        };
+        let addr = addr.trim_start_matches('[').trim_end_matches(']');
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix_len = match (prefix_len, addr) {
+            (Some(prefix_len), _) => prefix_len.parse().map_err(|_| invalid())?,
+            (None, IpAddr::V4(_)) => 32,
+            (None, IpAddr::V6(_)) => 128,
+        };
+
+        Self::new(addr, prefix_len)
+    }
+}
+
+/// Errors that can occur while parsing an exit policy.
+#[derive(Debug, PartialEq)]
+pub enum ExitPolicyError {
+    /// The rule was not an accept or reject of an address and port pattern.
+    InvalidRule(String),
+    /// The network prefix was longer than its address.
+    InvalidPrefixLength(u8),
+}
+
+impl fmt::Display for ExitPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitPolicyError::InvalidRule(rule) => write!(f, "invalid exit rule '{}'", rule),
+            ExitPolicyError::InvalidPrefixLength(len) => {
+                write!(f, "invalid network prefix length {}", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExitPolicyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn default_policy_rejects_private_and_loopback() {
+        let policy = ExitPolicy::default();
+
+        assert!(!policy.allows(addr("127.0.0.1:80")));
+        assert!(!policy.allows(addr("10.1.2.3:443")));
+        assert!(!policy.allows(addr("192.168.1.1:22")));
+        assert!(!policy.allows(addr("[::1]:80")));
+        assert!(!policy.allows(addr("[fd00::1]:80")));
+        assert!(policy.allows(addr("93.184.216.34:80")));
+        assert!(policy.allows(addr("[2606:2800:220:1::1]:443")));
+    }
+
+    #[test]
+    fn mapped_ipv4_is_checked_as_ipv4() {
+        let policy = ExitPolicy::default();
+
+        assert!(!policy.allows(addr("[::ffff:127.0.0.1]:80")));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let policy: ExitPolicy = "accept 10.0.0.1:22, reject 10.0.0.0/8:*, accept *:20-25"
+            .parse()
+            .unwrap();
+
+        assert!(policy.allows(addr("10.0.0.1:22")));
+        assert!(!policy.allows(addr("10.0.0.2:22")));
+        assert!(policy.allows(addr("1.1.1.1:22")));
+        assert!(!policy.allows(addr("1.1.1.1:80")));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_rules() {
+        assert_eq!(
+            "allow *:*".parse::<ExitRule>(),
+            Err(ExitPolicyError::InvalidRule("allow *:*".to_string()))
+        );
+        assert_eq!(
+            "accept *:443-80".parse::<ExitRule>(),
+            Err(ExitPolicyError::InvalidRule("accept *:443-80".to_string()))
+        );
+        assert_eq!(
+            "reject 10.0.0.0/33:*".parse::<ExitRule>(),
+            Err(ExitPolicyError::InvalidPrefixLength(33))
+        );
+    }
+
+    #[test]
+    fn parse_ipv6_rule() {
+        let rule: ExitRule = "reject [fc00::]/7:80".parse().unwrap();
+
+        assert!(rule.matches("fd12::1".parse().unwrap(), 80));
+        assert!(!rule.matches("fd12::1".parse().unwrap(), 81));
+        assert!(!rule.matches("2001:db8::1".parse().unwrap(), 80));
+    }
+
+    #[test]
+    fn summary_skips_rules_for_narrower_ranges() {
+        let policy: ExitPolicy = "reject 1.2.3.0/24:*, accept 10.0.0.0/8:22, reject *:25, accept *:20-30, accept 0.0.0.0/0:443"
+            .parse()
+            .unwrap();
+
+        assert_eq!(policy.summary().ports, vec![20..=24, 26..=30, 443..=443]);
+        assert_eq!(ExitPolicy::default().summary().ports, vec![0..=u16::MAX]);
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    SinkExt, StreamExt,
+};
+
+use crate::{
+    flow_control::CIRCUIT_WINDOW,
+    protocol::{
+        error::ProtocolError,
+        onion::{Message, Onion, Target},
+    },
+    runtime,
+    uid_generator::UIDGenerator,
+};
+
+use super::tunnel::{OnionTunnel, TunnelOnionReader, TunnelOnionWriter};
+
+/// The onions a link holds for one circuit before it closes the circuit. Flow control keeps
+/// the Data of a circuit below its window, so only a peer that ignores it fills the queue,
+/// and the reader never waits for one circuit while the others on its link starve.
+const CIRCUIT_QUEUE_LEN: usize = 2 * CIRCUIT_WINDOW as usize;
+/// The onions waiting to be written on a link before senders wait for the connection.
+const LINK_QUEUE_LEN: usize = 64;
+
+/// The onions a link received for one of its circuits, in the order they arrived. It ends
+/// once the circuit is closed on the link or the link goes down.
+pub type CircuitQueue = Receiver<Onion>;
+
+/// A tunnel to another relay that carries many circuits. A task of its own writes the
+/// onions sent on the link, and the single LinkReader of the link hands every onion it
+/// reads to the queue of the circuit it belongs to, so circuits sharing the link never see
+/// each other's onions and no circuit waits for the connection while another writes.
+///
+/// The relay that connected opens the circuits of a link and picks their IDs, the other
+/// relay accepts them.
+pub struct Link {
+    peer_addr: SocketAddr,
+    outgoing: Sender<Onion>,
+    circuits: Mutex<LinkCircuits>,
+}
+
+struct LinkCircuits {
+    ids: UIDGenerator,
+    queues: HashMap<u32, Sender<Onion>>,
+    /// Set once the link went down, after which no circuit can be added.
+    down: bool,
+}
+
+/// Reads a link, owned by the one task that does.
+pub struct LinkReader {
+    reader: TunnelOnionReader,
+    link: Arc<Link>,
+}
+
+impl Link {
+    // Returns a new Link carrying circuits on the given tunnel, with the reader to read it
+    // with. The writer of the tunnel is handed to a task that ends once the link is dropped.
+    // param tunnel: The tunnel to the other relay
+    pub fn spawn(tunnel: OnionTunnel) -> (Arc<Link>, LinkReader) {
+        let peer_addr = tunnel.peer_addr();
+        let (reader, writer) = tunnel.into_split();
+        let (outgoing, queue) = mpsc::channel(LINK_QUEUE_LEN);
+        runtime::spawn(Self::write(writer, queue, peer_addr));
+
+        let link = Arc::new(Link {
+            peer_addr,
+            outgoing,
+            circuits: Mutex::new(LinkCircuits {
+                ids: UIDGenerator::new(10),
+                queues: HashMap::new(),
+                down: false,
+            }),
+        });
+        let reader = LinkReader {
+            reader,
+            link: link.clone(),
+        };
+        (link, reader)
+    }
+
+    // Writes the onions sent on the link until it is dropped or the connection fails
+    // param writer: The writer of the tunnel
+    // param queue: The onions to write
+    // param peer_addr: The socket address of the other relay
+    async fn write(
+        mut writer: TunnelOnionWriter,
+        mut queue: Receiver<Onion>,
+        peer_addr: SocketAddr,
+    ) {
+        while let Some(onion) = queue.next().await {
+            if let Err(err) = writer.write(onion).await {
+                println!("Failed to write to {}: {}", peer_addr, err);
+                return;
+            }
+        }
+    }
+
+    // Returns the socket address of the other side of the link
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    // Queues an onion to be written on the link, waiting while the queue is full
+    pub async fn send_onion(&self, onion: Onion) -> Result<()> {
+        self.outgoing.clone().send(onion).await.map_err(|_| {
+            Error::new(
+                ErrorKind::NotConnected,
+                format!("link to {} is down", self.peer_addr),
+            )
+        })
+    }
+
+    // Queues an onion to be written on the link without waiting. While the queue is full,
+    // a task of its own waits for room, so the reader of the link never waits for the writer.
+    // An onion for a link that is down is dropped.
+    pub fn queue_onion(&self, onion: Onion) {
+        let mut outgoing = self.outgoing.clone();
+        if let Err(err) = outgoing.try_send(onion) {
+            if err.is_full() {
+                runtime::spawn(async move { outgoing.send(err.into_inner()).await });
+            }
+        }
+    }
+
+    // Opens a circuit on the link, returning the ID picked for it and the queue of its
+    // onions, or None if the link is down
+    pub fn open_circuit(&self) -> Option<(u32, CircuitQueue)> {
+        let mut circuits = self.circuits.lock().unwrap();
+        if circuits.down {
+            return None;
+        }
+        let id = circuits.ids.get_uid();
+        let (sender, queue) = mpsc::channel(CIRCUIT_QUEUE_LEN);
+        circuits.queues.insert(id, sender);
+        Some((id, queue))
+    }
+
+    // Accepts a circuit the other relay opened, returning the queue of its onions, or None
+    // if the link is down or the ID is taken
+    // param id: The ID the other relay picked for the circuit
+    pub fn accept_circuit(&self, id: u32) -> Option<CircuitQueue> {
+        let mut circuits = self.circuits.lock().unwrap();
+        if circuits.down || circuits.queues.contains_key(&id) {
+            return None;
+        }
+        let (sender, queue) = mpsc::channel(CIRCUIT_QUEUE_LEN);
+        circuits.queues.insert(id, sender);
+        Some(queue)
+    }
+
+    // Stops handing onions to a circuit, ending its queue, and frees its ID
+    // param id: The ID of the circuit on the link
+    pub fn close_circuit(&self, id: u32) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if circuits.queues.remove(&id).is_some() {
+            circuits.ids.clear_uid(id);
+        }
+    }
+
+    // Closes a circuit whose peer sends faster than the circuit handles its onions. The
+    // queue is closed rather than removed, so the circuit still frees its ID once its task
+    // sees the queue end, and the peer is told why since the task won't tell it.
+    // param queue: The full queue of the circuit
+    // param circuit_id: The ID of the circuit on the link
+    fn overflow(&self, mut queue: Sender<Onion>, circuit_id: u32) {
+        queue.close_channel();
+        println!(
+            "Closing circuit {} on link to {}: its queue overflowed",
+            circuit_id, self.peer_addr
+        );
+
+        let close = Onion {
+            target: Target::Current,
+            circuit_id: Some(circuit_id),
+            message: Message::Close(Some("circuit queue overflowed".to_string())),
+        };
+        self.queue_onion(close);
+    }
+
+    // Takes the link down, ending the queues of all its circuits
+    fn shut_down(&self) {
+        let mut circuits = self.circuits.lock().unwrap();
+        circuits.down = true;
+        circuits.queues.clear();
+    }
+}
+
+impl LinkReader {
+    // Returns the link this reader reads
+    pub fn link(&self) -> &Arc<Link> {
+        &self.link
+    }
+
+    // Reads the link until an onion arrives that belongs to no circuit on it, handing the
+    // others to the queues of their circuits. Returns None once the other relay closed the
+    // link. When the link goes down, the queues of all its circuits end. A circuit whose
+    // queue overflows is closed on the link and its queue ends once it is drained.
+    pub async fn recv_unrouted(&mut self) -> std::result::Result<Option<Onion>, ProtocolError> {
+        loop {
+            let onion = match self.reader.read().await {
+                Ok(onion) => onion,
+                Err(err) => {
+                    self.link.shut_down();
+                    return if err.is_eof() { Ok(None) } else { Err(err) };
+                }
+            };
+
+            let circuit_id = match onion.circuit_id {
+                Some(circuit_id) => circuit_id,
+                None => return Ok(Some(onion)),
+            };
+            let queue = self
+                .link
+                .circuits
+                .lock()
+                .unwrap()
+                .queues
+                .get(&circuit_id)
+                .cloned();
+            let mut queue = match queue {
+                Some(queue) => queue,
+                None => return Ok(Some(onion)),
+            };
+            match queue.try_send(onion) {
+                Ok(()) => {}
+                Err(err) if err.is_full() => self.link.overflow(queue, circuit_id),
+                // The task of the circuit may have ended already, which drops the onion.
+                Err(_) => {}
+            }
+        }
+    }
+}
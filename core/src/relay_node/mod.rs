@@ -1,3 +1,5 @@
+pub mod exit_policy;
+mod link;
 pub mod relay_node;
 mod relay_context;
 mod tunnel;
\ No newline at end of file
@@ -1,36 +1,126 @@
-use std::{collections::HashMap, net::SocketAddr, rc::Rc, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use crate::crypto::Aes256;
-use async_std::net::TcpStream;
+use crate::crypto::{CountedCipher, SuiteCipher};
+use futures::{channel::mpsc::Sender, future::AbortHandle};
 
-use crate::{crypto::ServerCrypto, protocol::onion::Relay, uid_generator::UIDGenerator};
+use crate::{
+    crypto::ServerCrypto,
+    flow_control::{RecvWindow, SendWindow},
+    protocol::onion::{Onion, RefusalReason, Relay},
+};
 
-use super::tunnel::OnionTunnel;
+use super::{
+    exit_policy::ExitPolicy,
+    link::Link,
+    relay_node::{HEARTBEAT_INTERVAL, RELAY_MAX_FRAME_LEN},
+};
 
+/// The state the tasks of a relay share. Circuits and links are run by tasks of their own,
+/// so what is kept here are the handles to reach them and the relay's configuration. Every
+/// lock is held only to look something up or change it, never across an await.
 pub struct RelayContext {
-    pub circuits: HashMap<u32, Arc<Circuit>>, //HashMap<u32, Arc<OnionChannel>>,
-    pub relay_tunnels: HashMap<SocketAddr, Arc<OnionTunnel>>,
-    pub indexed_relays: Vec<Relay>,
-    pub circ_id_generator: UIDGenerator,
+    /// Reaches the tasks of the circuits through this relay, by the address of the previous
+    /// relay and the ID the circuit has on the link to it.
+    pub circuits: Mutex<HashMap<(SocketAddr, u32), Sender<CircuitEvent>>>,
+    /// The links this relay opened to other relays, by their address.
+    pub relay_tunnels: Mutex<HashMap<SocketAddr, Arc<Link>>>,
+    pub indexed_relays: Mutex<Vec<Relay>>,
+    /// The address and signing public key of the index node the relay registered at.
+    pub index: Mutex<Option<(SocketAddr, [u8; 32])>>,
     pub crypto: ServerCrypto,
+    pub max_frame_len: AtomicU32,
+    /// Whether the relay wants its links split into cells.
+    pub cells: AtomicBool,
+    /// Decides which addresses the streams of circuits ending here may exit to.
+    pub exit_policy: Mutex<ExitPolicy>,
+    /// How often the relay pings its index node and refreshes the relays it knows of.
+    pub heartbeat_interval: Mutex<Duration>,
 }
 
 impl RelayContext {
     pub fn new() -> Self {
         Self {
-            circuits: HashMap::new(),
-            relay_tunnels: HashMap::new(),
-            indexed_relays: Vec::new(),
-            circ_id_generator: UIDGenerator::new(10),
+            circuits: Mutex::new(HashMap::new()),
+            relay_tunnels: Mutex::new(HashMap::new()),
+            indexed_relays: Mutex::new(Vec::new()),
+            index: Mutex::new(None),
             crypto: ServerCrypto::new(),
+            max_frame_len: AtomicU32::new(RELAY_MAX_FRAME_LEN),
+            cells: AtomicBool::new(true),
+            exit_policy: Mutex::new(ExitPolicy::default()),
+            heartbeat_interval: Mutex::new(HEARTBEAT_INTERVAL),
         }
     }
 }
 
+/// What the task of a circuit handles, in the order it arrives.
+pub enum CircuitEvent {
+    /// An onion the previous relay sent on the circuit.
+    Forward(Onion),
+    /// An onion the next relay sent back on the circuit.
+    Backward(Onion),
+    /// The link to the previous relay went down.
+    PreviousHopGone,
+    /// The link to the next relay went down.
+    NextHopGone,
+    /// The connection of a stream is open.
+    StreamOpened(u32),
+    /// The stream could not be opened.
+    StreamRefused(u32, RefusalReason),
+    /// The endpoint of a stream sent data.
+    EndpointData(u32, Vec<u8>),
+    /// Data of the stream was written to its endpoint.
+    EndpointDelivered(u32),
+    /// The endpoint of a stream hung up, or its connection failed.
+    EndpointClosed(u32),
+}
+
+/// The state of a circuit, owned by the task of the circuit.
 pub struct Circuit {
+    /// The ID of the circuit on the link towards the consumer.
     pub id: u32,
-    pub symmetric_cipher: Aes256,
-    pub peel_tunnel_addr: SocketAddr,
-    pub layer_tunnel_addr: SocketAddr,
-    pub endpoint_connection: Option<TcpStream>,
+    pub peel_cipher: CountedCipher<SuiteCipher>,
+    pub layer_cipher: CountedCipher<SuiteCipher>,
+    pub version: u8,
+    pub peel_link: Arc<Link>,
+    /// The next relay of the circuit, once the consumer extended it past this relay.
+    pub next_hop: Option<NextHop>,
+    /// The streams opened at the exit of this circuit, by stream ID.
+    pub streams: HashMap<u32, ExitStream>,
+    /// Credit for Data sent back towards the consumer, shared with the tasks reading endpoints.
+    pub send_window: Arc<SendWindow>,
+    /// Data delivered from the consumer since the last circuit level Sendme.
+    pub recv_window: RecvWindow,
+    /// Reaches the task of the circuit, handed to the tasks of its streams.
+    pub events: Sender<CircuitEvent>,
+}
+
+impl Circuit {
+    // Returns the key of the circuit in the relay context
+    pub fn key(&self) -> (SocketAddr, u32) {
+        (self.peel_link.peer_addr(), self.id)
+    }
+}
+
+pub struct NextHop {
+    pub circuit_id: u32,
+    pub link: Arc<Link>,
+}
+
+pub struct ExitStream {
+    /// Hands data to the task of the stream, which writes what is left of it to the endpoint
+    /// once this is dropped and closes the connection.
+    pub writer: Sender<Vec<u8>>,
+    /// Stops reading the endpoint.
+    pub reader: AbortHandle,
+    pub send_window: Arc<SendWindow>,
+    pub recv_window: RecvWindow,
 }
@@ -1,404 +1,1336 @@
-use std::{borrow::BorrowMut, sync::Arc};
-
-use async_std::{
-    io::{Cursor, Error, ErrorKind, Result, Write},
-    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
-    prelude::*,
-    sync::Mutex,
-    task,
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
 };
 
-use crate::{crypto::Aes256, protocol::onion::RelayPingRequest};
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    future::{abortable, join, AbortHandle, AbortRegistration, Abortable},
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    stream::{self, BoxStream, SelectAll},
+    SinkExt, StreamExt,
+};
+
+use crate::{crypto::CountedCipher, protocol::onion::RelayPingRequest};
 use crate::{
-    crypto::{ClientCrypto, ClientSecret, ServerCrypto, ServerSecret},
+    crypto::{ClientCrypto, Negotiation, ServerSecret},
+    flow_control::{RecvWindow, SendWindow, STREAM_WINDOW},
     protocol::{
-        io::{RawOnionReader, RawOnionWriter},
-        onion::{ClientType, HelloRequest, Message, Onion, Relay, Target},
+        challenge,
+        codec::CELL_VERSION,
+        directory::unix_time,
+        error::ProtocolError,
+        io::{open_layer, pad_layer, seal_layer},
+        onion::{
+            ClientType, HelloRequest, HelloResponse, Message, Onion, RefusalReason, Relay,
+            StreamData, StreamRefusal, Target, DIRECTORY_VERSION, EXIT_POLICY_VERSION,
+            STREAM_OPENED_VERSION,
+        },
     },
+    runtime,
+    transport::{Listener, TcpTransport, Transport},
 };
 
 use super::{
-    relay_context::{Circuit, RelayContext},
-    tunnel::OnionTunnel,
+    exit_policy::ExitPolicy,
+    link::{CircuitQueue, Link, LinkReader},
+    relay_context::{Circuit, CircuitEvent, ExitStream, NextHop, RelayContext},
+    tunnel::{OnionTunnel, TunnelOnionReader, TunnelOnionWriter},
 };
 
-pub struct RelayNode {
+/// The largest frame a relay node accepts unless told otherwise.
+pub const RELAY_MAX_FRAME_LEN: u32 = 1 << 20;
+/// The most bytes the exit relay reads from an endpoint into one Data message.
+const ENDPOINT_READ_LEN: usize = 4096;
+/// How often a relay node pings its index node unless told otherwise, well within the time
+/// the index node lists a relay after its last ping.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// The wait before pinging an index node that could not be reached again. It doubles with
+/// every ping that fails, up to the heartbeat interval.
+const HEARTBEAT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// The events waiting for the task of a circuit before the tasks of its streams wait for it.
+const CIRCUIT_EVENTS_LEN: usize = 64;
+
+// Where the Close of a circuit came from, so it is passed on in the other directions.
+#[derive(Clone, Copy, PartialEq)]
+enum CloseOrigin {
+    PreviousHop,
+    NextHop,
+    Here,
+}
+
+// Why a circuit was closed, if known, and where the Close came from.
+type CircuitClose = (Option<String>, CloseOrigin);
+
+pub struct RelayNode<T: Transport = TcpTransport> {
     ip: IpAddr,
     port: u16,
-    context: Arc<Mutex<RelayContext>>,
+    context: Arc<RelayContext>,
+    transport: T,
 }
 
 impl RelayNode {
-    // Returns a new RelayNode object
+    // Returns a new RelayNode object that reaches other nodes over TCP
     // param ip: The IP address this relay node should bind to
     // param port: The port this relay node should listen on
     pub fn new(ip: IpAddr, port: u16) -> Self {
+        Self::with_transport(ip, port, TcpTransport)
+    }
+}
+
+impl<T: Transport> RelayNode<T> {
+    // Returns a new RelayNode object that reaches other nodes over the given transport
+    // param ip: The IP address this relay node should bind to
+    // param port: The port this relay node should listen on
+    // param transport: The transport to listen on and reach other nodes over
+    pub fn with_transport(ip: IpAddr, port: u16, transport: T) -> Self {
         Self {
             ip,
             port,
-            context: Arc::new(Mutex::new(RelayContext::new())),
+            context: Arc::new(RelayContext::new()),
+            transport,
         }
     }
 
+    // Sets the largest frame this relay node accepts from its peers
+    // param max_frame_len: The maximum frame length in bytes
+    pub fn set_max_frame_len(&self, max_frame_len: u32) {
+        self.context
+            .max_frame_len
+            .store(max_frame_len, Ordering::Relaxed);
+    }
+
+    // Sets whether this relay node wants its links split into cells, which hide the size of onions
+    // from anyone watching the links. Links only use them if both peers want them.
+    // param cells: Whether to ask for cells
+    pub fn set_cells(&self, cells: bool) {
+        self.context.cells.store(cells, Ordering::Relaxed);
+    }
+
+    // Sets the exit policy deciding which addresses streams may exit to from this relay node.
+    // Set it before the relay node starts, the index node advertises a summary of it to consumers.
+    // param exit_policy: The exit policy of the relay node
+    pub fn set_exit_policy(&self, exit_policy: ExitPolicy) {
+        *self.context.exit_policy.lock().unwrap() = exit_policy;
+    }
+
+    // Sets how often the relay node pings its index node and refreshes the relays it knows of.
+    // It should be well within the time the index node lists a relay after its last ping.
+    // param heartbeat_interval: The time between two pings
+    pub fn set_heartbeat_interval(&self, heartbeat_interval: Duration) {
+        *self.context.heartbeat_interval.lock().unwrap() = heartbeat_interval;
+    }
+
     // Starts the RelayNode server, causing it to listen to the socket address specified in RelayNode::new()
     pub fn start(&self) {
         let socket = SocketAddr::new(self.ip, self.port);
         let listen_future = self.listen(socket);
 
-        task::block_on(listen_future); // bytte til async?
+        runtime::block_on(listen_future); // bytte til async?
     }
 
-    // Registers the relay node at the specified index node, making it visible to other relay nodes and consumers
+    // Binds the socket address specified in RelayNode::new() and serves it in the background,
+    // returning once the relay node accepts connections and is listed at its index node
+    pub async fn spawn(self) -> Result<()> {
+        let listener = self
+            .transport
+            .listen(SocketAddr::new(self.ip, self.port))
+            .await?;
+
+        let (context, transport, port) = (self.context.clone(), self.transport.clone(), self.port);
+        runtime::spawn(async move { self.serve(listener).await });
+        Self::join_index(context, transport, port).await
+    }
+
+    // Registers the relay node at the specified index node, making it visible to other relay nodes and consumers.
+    // The index node checks that it can reach the relay node, so the relay node registers once
+    // it listens, and keeps pinging the index node in the background to stay listed.
     // param index_addr: The socket address of the index node
     // param index_signing_pub_key: The signing public key of the index node
     pub fn register(&self, index_addr: SocketAddr, index_signing_pub_key: [u8; 32]) {
-        let register_future = async {
-            let locked_context = self.context.lock().await;
+        *self.context.index.lock().unwrap() = Some((index_addr, index_signing_pub_key));
+    }
 
-            let tunnel = Self::index_tunnel(index_addr, index_signing_pub_key)
-                .await
-                .expect("Failed to establish onion tunnel with Index node");
+    // Pings the index node the relay node registered at, if any, and keeps pinging it in the
+    // background. The relay node must accept connections already.
+    // param context: Relay node context holding the index node
+    // param transport: The transport to reach the index node over
+    // param port: The port this relay node listens on
+    async fn join_index(context: Arc<RelayContext>, transport: T, port: u16) -> Result<()> {
+        if context.index.lock().unwrap().is_none() {
+            return Ok(());
+        }
+        Self::heartbeat(&context, &transport, port).await?;
 
-            tunnel
-                .send_onion(Onion {
-                    target: Target::Current,
-                    circuit_id: None,
-                    message: Message::RelayPingRequest(RelayPingRequest {
-                        port: self.port,
-                        signing_public: locked_context.crypto.signing_public(),
-                    }),
-                })
-                .await
-                .expect("Failed to send ping request to index node");
+        runtime::spawn(Self::keep_registered(context, transport, port, Ok(())));
+        Ok(())
+    }
 
-            let _ = tunnel.recv_onion().await;
-        };
+    // Pings the index node on every heartbeat interval, so the relay node stays listed and
+    // learns about relays that registered since. While the index node can't be reached, for
+    // instance because it restarts, it is tried again sooner with a growing backoff.
+    // param context: Relay node context holding the index node and the known relays
+    // param transport: The transport to reach the index node over
+    // param port: The port this relay node listens on
+    // param reached: How the last ping went
+    async fn keep_registered(
+        context: Arc<RelayContext>,
+        transport: T,
+        port: u16,
+        mut reached: Result<()>,
+    ) {
+        let mut backoff = None;
+        loop {
+            let interval = *context.heartbeat_interval.lock().unwrap();
+            backoff = match reached {
+                Ok(()) => None,
+                Err(err) => {
+                    let next = match backoff {
+                        Some(backoff) => interval.min(backoff * 2),
+                        None => HEARTBEAT_MIN_BACKOFF.min(interval),
+                    };
+                    println!(
+                        "Failed to reach index node, retrying in {:?}: {}",
+                        next, err
+                    );
+                    Some(next)
+                }
+            };
 
-        task::block_on(register_future);
+            runtime::sleep(backoff.unwrap_or(interval)).await;
+            reached = Self::heartbeat(&context, &transport, port).await;
+        }
+    }
 
-        let index_relays_future = async {
-            self.context.lock().await.indexed_relays.extend(
-                Self::index_all_relays(index_addr, index_signing_pub_key)
-                    .await
-                    .expect("Failed to index relays"),
-            )
-        };
+    // Pings the index node the relay node registered at and refreshes the relays it knows of
+    // param context: Relay node context holding the index node and the known relays
+    // param transport: The transport to reach the index node over
+    // param port: The port this relay node listens on
+    async fn heartbeat(context: &RelayContext, transport: &T, port: u16) -> Result<()> {
+        let index = *context.index.lock().unwrap();
+        let (index_addr, index_signing_pub_key) = index.ok_or_else(|| {
+            Error::new(ErrorKind::NotConnected, "not registered at an index node")
+        })?;
+        let max_frame_len = context.max_frame_len.load(Ordering::Relaxed);
+        let cells = context.cells.load(Ordering::Relaxed);
+        let exit_policy = context.exit_policy.lock().unwrap().summary();
+
+        let tunnel = Self::index_tunnel(
+            transport,
+            index_addr,
+            index_signing_pub_key,
+            max_frame_len,
+            cells,
+        )
+        .await?;
+        tunnel
+            .send_onion(Onion {
+                target: Target::Current,
+                circuit_id: None,
+                message: Message::RelayPingRequest(RelayPingRequest {
+                    port,
+                    signing_public: context.crypto.signing_public(),
+                    exit_policy,
+                }),
+            })
+            .await?;
+        // The index node challenges relays it does not list yet before it answers the ping.
+        loop {
+            match tunnel.recv_onion().await?.message {
+                Message::RelayPingResponse() => break,
+                Message::RelayChallenge(nonce) => {
+                    let signature = challenge::answer_challenge(
+                        &nonce,
+                        &index_signing_pub_key,
+                        &context.crypto,
+                    );
+                    tunnel
+                        .send_onion(Onion {
+                            target: Target::Current,
+                            circuit_id: None,
+                            message: Message::RelayChallengeResponse(signature),
+                        })
+                        .await?;
+                }
+                Message::Close(reason) => {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("index node refused relay: {}", reason.unwrap_or_default()),
+                    ))
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Expected ping response from index node",
+                    ))
+                }
+            }
+        }
 
-        task::block_on(index_relays_future);
+        let relays = Self::index_all_relays(
+            transport,
+            index_addr,
+            index_signing_pub_key,
+            max_frame_len,
+            cells,
+        )
+        .await?;
+        *context.indexed_relays.lock().unwrap() = relays;
+        Ok(())
     }
 
     // Helper method for listening on a socket address and handling the incoming connections
     // param socket: The specified socket address to listen on
     async fn listen(&self, socket: SocketAddr) {
-        let listener = TcpListener::bind(socket)
+        let listener = self
+            .transport
+            .listen(socket)
             .await
             .expect("Failed to bind to socket");
 
-        let mut incoming = listener.incoming();
-        while let Some(stream) = incoming.next().await {
+        let (context, transport, port) = (self.context.clone(), self.transport.clone(), self.port);
+        let register = async move {
+            if context.index.lock().unwrap().is_none() {
+                return;
+            }
+            // An index node that is down at startup is tried again like one that goes down later.
+            let reached = Self::heartbeat(&context, &transport, port).await;
+            Self::keep_registered(context, transport, port, reached).await
+        };
+        join(self.serve(listener), register).await;
+    }
+
+    // Helper method for handling the incoming connections of a bound listener
+    // param listener: The listener to accept connections on
+    async fn serve(&self, listener: T::Listener) {
+        loop {
+            let (stream, peer_addr) = listener.accept().await.expect("Failed to read from stream");
             let context = self.context.clone();
-            let handler_future = async {
-                Self::handle_connection(stream.expect("Failed to read from stream"), context)
-                    .await
-                    .expect("Failed to handle connection")
+            let transport = self.transport.clone();
+            let handler_future = async move {
+                if let Err(err) =
+                    Self::handle_connection(stream, peer_addr, context, transport).await
+                {
+                    println!("Closing connection: {}", err);
+                }
             };
 
-            task::spawn(handler_future);
+            runtime::spawn(handler_future);
         }
     }
 
-    // Helper method for handling a TcpStream connection and respond to index node queries
-    // param stream: The TCP stream used in the connection to handle
-    // param context: Index node context required for management of circuits, tunnels, id generation and cryptography in a static context
-    async fn handle_connection(stream: TcpStream, context: Arc<Mutex<RelayContext>>) -> Result<()> {
-        let (circuit_id, peel_tunnel_arc, hello_req) = {
-            let mut guard = context.lock().await;
-            let context_locked = &mut *guard;
+    // Helper method for handling a connection and respond to index node queries
+    // param stream: The stream used in the connection to handle
+    // param peer_addr: The socket address of the other side of the connection
+    // param context: Relay node context required for management of circuits, links and cryptography in a static context
+    // param transport: The transport to reach other relays over
+    async fn handle_connection(
+        stream: T::Stream,
+        peer_addr: SocketAddr,
+        context: Arc<RelayContext>,
+        transport: T,
+    ) -> Result<()> {
+        let secret = context.crypto.gen_secret();
+        let max_frame_len = context.max_frame_len.load(Ordering::Relaxed);
+        let cells = context.cells.load(Ordering::Relaxed);
+        let (tunnel, hello_req) =
+            Self::establish_sender_tunnel(stream, peer_addr, secret, max_frame_len, cells).await?;
+
+        match hello_req.client_type {
+            //means we are relay_1
+            ClientType::Consumer => Self::serve_consumer(tunnel, context, transport).await,
+            ClientType::Relay => {
+                let (_, reader) = Link::spawn(tunnel);
+                Self::serve_relay(reader, context, transport).await
+            }
+        }
+    }
+
+    // Forwards the onions of a consumer to the first layered relay of its circuit, and passes
+    // everything that comes back on the circuit to the consumer. The consumer shares no layer
+    // with its entry relay, so nothing is peeled or added here. Once the consumer closes the
+    // circuit or hangs up, the Close is passed on and the circuit ID is freed.
+    // param tunnel: The tunnel to the consumer
+    // param context: Relay node context required for management of links
+    // param transport: The transport to reach the next relay over
+    async fn serve_consumer(
+        tunnel: OnionTunnel,
+        context: Arc<RelayContext>,
+        transport: T,
+    ) -> Result<()> {
+        let (mut reader, writer) = tunnel.into_split();
+
+        // The first onion tells which relay the circuit goes on to.
+        let first = match Self::next_onion(&mut reader).await? {
+            Some(onion) => onion,
+            None => return Ok(()),
+        };
+        let relay_id = match (&first.target, &first.message) {
+            (_, Message::Close(_)) => return Ok(()),
+            (Target::Relay(id), _) => *id,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "must have a relay to send to!",
+                ))
+            }
+        };
+        let link = Self::relay_tunnel(relay_id, &context, &transport).await?;
+        let (circuit_id, queue) = Self::open_circuit(&link)?;
+        let (backward, abort) = abortable(Self::consumer_backward(queue, writer));
+        runtime::spawn(backward);
+
+        let closed = Self::forward_consumer(first, &mut reader, &link, circuit_id).await;
 
-            let secret = context_locked.crypto.gen_secret();
-            let pub_key = secret.public_key();
+        let reason = match &closed {
+            Ok(reason) => reason.clone(),
+            Err(err) => Some(err.to_string()),
+        };
+        abort.abort();
+        let _ = link
+            .send_onion(Onion {
+                target: Target::Current,
+                circuit_id: Some(circuit_id),
+                message: Message::Close(reason),
+            })
+            .await;
+        link.close_circuit(circuit_id);
 
-            let (peel_tunnel, hello_req) =
-                Self::establish_sender_tunnel(stream.clone(), secret).await?;
-            let peel_tunnel_arc = Arc::new(peel_tunnel);
+        closed.map(|_| ())
+    }
 
-            if hello_req.client_type == ClientType::Relay {
-                context_locked
-                    .relay_tunnels
-                    .insert(peel_tunnel_arc.peer_addr(), peel_tunnel_arc.clone());
+    // Forwards the onions of a consumer, starting with the given one, until it closes the
+    // circuit, returning the reason it gave, or None if it hung up
+    // param onion: The first onion of the consumer
+    // param reader: The reader of the tunnel to the consumer
+    // param link: The link to the first layered relay of the circuit
+    // param circuit_id: The ID of the circuit on the link
+    async fn forward_consumer(
+        mut onion: Onion,
+        reader: &mut TunnelOnionReader,
+        link: &Link,
+        circuit_id: u32,
+    ) -> Result<Option<String>> {
+        loop {
+            match (onion.target, onion.message) {
+                (_, Message::Close(reason)) => return Ok(reason),
+                (Target::Relay(_), message) => {
+                    link.send_onion(Onion {
+                        target: Target::Current,
+                        circuit_id: Some(circuit_id),
+                        message,
+                    })
+                    .await?
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "must have a relay to send to!",
+                    ))
+                }
             }
 
-            // TODO: refactor to not use cloned stream
-            RawOnionWriter::new(stream)
-                .write(Onion {
-                    target: Target::Current,
-                    circuit_id: None,
-                    message: Message::HelloResponse(pub_key),
-                })
-                .await?;
+            onion = match Self::next_onion(reader).await? {
+                Some(onion) => onion,
+                None => return Ok(None),
+            };
+        }
+    }
+
+    // Handles the circuits another relay (or the entry relay of a consumer) builds through
+    // this relay. This is the only task reading the link, and it hands the onions of every
+    // circuit to the task running that circuit, so one circuit never holds up another.
+    // Once the previous relay hangs up, its circuits close as their queues end.
+    // param reader: The reader of the link to the previous relay of the circuits
+    // param context: Relay node context required for management of circuits, links and cryptography in a static context
+    // param transport: The transport to reach the next relays over
+    async fn serve_relay(
+        mut reader: LinkReader,
+        context: Arc<RelayContext>,
+        transport: T,
+    ) -> Result<()> {
+        loop {
+            let onion = match reader.recv_unrouted().await {
+                Ok(Some(onion)) => onion,
+                Ok(None) => return Ok(()),
+                Err(err) => return Err(err.into()),
+            };
+            let circuit_id = onion
+                .circuit_id
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "did not receive circuit id"))?;
+
+            match onion.message {
+                Message::HelloRequest(req) => {
+                    let created =
+                        Self::create_circuit(circuit_id, req, reader.link(), &context, &transport)
+                            .await;
+                    // Only the circuit is refused, the link goes on serving the others.
+                    if let Err(err) = created {
+                        println!("Refusing circuit {}: {}", circuit_id, err);
+                        reader.link().queue_onion(Onion {
+                            target: Target::Current,
+                            circuit_id: Some(circuit_id),
+                            message: Message::Close(Some(err.to_string())),
+                        });
+                    }
+                }
+                // Onions can still arrive for a circuit that was just closed.
+                _ => println!("Dropping onion for unknown circuit {}", circuit_id),
+            }
+        }
+    }
 
-            (
-                context_locked.circ_id_generator.get_uid(),
-                peel_tunnel_arc.clone(),
-                hello_req,
+    // Answers the HelloRequest of a consumer that extends its circuit to this relay, and
+    // starts the task running the circuit with its layer ciphers
+    // param circuit_id: The ID the previous relay gave the circuit on the link
+    // param req: The HelloRequest of the consumer
+    // param peel_link: The link to the previous relay
+    // param context: Relay node context to keep the circuit in
+    // param transport: The transport to reach the next relay of the circuit over
+    async fn create_circuit(
+        circuit_id: u32,
+        req: HelloRequest,
+        peel_link: &Arc<Link>,
+        context: &Arc<RelayContext>,
+        transport: &T,
+    ) -> Result<()> {
+        let negotiation = Negotiation::accept(&req, context.cells.load(Ordering::Relaxed))
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+        let queue = peel_link.accept_circuit(circuit_id).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("circuit {} is already open", circuit_id),
             )
+        })?;
+
+        let secret = context.crypto.gen_secret();
+        let pub_key = secret.public_key();
+        let ciphers = secret.symmetric_ciphers(req.public_key, &negotiation);
+
+        let (events, events_queue) = mpsc::channel(CIRCUIT_EVENTS_LEN);
+        let circuit = Circuit {
+            id: circuit_id,
+            peel_cipher: CountedCipher::new(ciphers.recv),
+            layer_cipher: CountedCipher::new(ciphers.send),
+            version: negotiation.version,
+            peel_link: peel_link.clone(),
+            next_hop: None,
+            streams: HashMap::new(),
+            send_window: Arc::new(SendWindow::circuit()),
+            recv_window: RecvWindow::circuit(),
+            events: events.clone(),
         };
+        context
+            .circuits
+            .lock()
+            .unwrap()
+            .insert(circuit.key(), events);
+        // Nothing is sent back on the circuit before the consumer has the response, so the
+        // task can start before it is sent. If the link is down, the task closes the circuit.
+        runtime::spawn(Self::serve_circuit(
+            circuit,
+            queue,
+            events_queue,
+            context.clone(),
+            transport.clone(),
+        ));
+
+        peel_link
+            .send_onion(Onion {
+                target: Target::Current,
+                circuit_id: Some(circuit_id),
+                message: Message::HelloResponse(HelloResponse {
+                    signed_public_key: pub_key,
+                    cipher_suite: negotiation.cipher_suite,
+                    version: negotiation.version,
+                    cells: negotiation.cells,
+                }),
+            })
+            .await
+    }
 
-        // while new onion decoded from tunnel
-        //
+    // Runs a circuit until it is closed. The task owns the circuit and handles what its links
+    // and its streams report to it one at a time, so nothing about the circuit is
+    // locked. A circuit that fails is closed without affecting the other circuits on its links.
+    // param circuit: The circuit to run
+    // param queue: The onions the previous relay sends on the circuit
+    // param events: What the streams of the circuit report to it
+    // param context: Relay node context holding the circuit
+    // param transport: The transport to reach the next relay over
+    async fn serve_circuit(
+        mut circuit: Circuit,
+        queue: CircuitQueue,
+        events: Receiver<CircuitEvent>,
+        context: Arc<RelayContext>,
+        transport: T,
+    ) {
+        let mut inbox = SelectAll::new();
+        inbox.push(events.boxed());
+        inbox.push(Self::queue_events(
+            queue,
+            CircuitEvent::Forward,
+            CircuitEvent::PreviousHopGone,
+        ));
+
+        // The circuit keeps a sender of its own events, so the inbox never runs dry.
+        while let Some(event) = inbox.next().await {
+            let handled =
+                Self::handle_event(event, &mut circuit, &mut inbox, &context, &transport).await;
+            let (reason, origin) = match handled {
+                Ok(None) => continue,
+                Ok(Some(close)) => close,
+                Err(err) => {
+                    println!("Closing circuit {}: {}", circuit.id, err);
+                    (Some(err.to_string()), CloseOrigin::Here)
+                }
+            };
+            Self::close_circuit(circuit, &context, reason, origin).await;
+            return;
+        }
+    }
 
-        // Relay recv loop
-        while let onion = peel_tunnel_arc.recv_onion().await {
-            let mut guard = context.lock().await;
-            let context_locked = &mut *guard;
+    // Turns the queue of a circuit on a link into events of the circuit, ending with the
+    // given event once the link no longer hands onions to the circuit
+    // param queue: The queue of the circuit on the link
+    // param event: Wraps an onion of the queue
+    // param end: The event that ends the queue
+    fn queue_events(
+        queue: CircuitQueue,
+        event: fn(Onion) -> CircuitEvent,
+        end: CircuitEvent,
+    ) -> BoxStream<'static, CircuitEvent> {
+        queue
+            .map(event)
+            .chain(stream::once(async move { end }))
+            .boxed()
+    }
 
-            match hello_req.client_type {
-                ClientType::Consumer => {
-                    //means we are relay_1
-                    let relay_id = match onion.target {
-                        Target::Relay(id) => id,
-                        _ => panic!("must have a relay to send to!"),
-                    };
+    // Handles an event of a circuit, returning how the circuit closes if it does
+    // param event: The event to handle
+    // param circuit: The circuit of the event
+    // param inbox: The events of the circuit, which the link to the next relay is added to
+    // param context: Relay node context required for management of links
+    // param transport: The transport to reach the next relay over
+    async fn handle_event(
+        event: CircuitEvent,
+        circuit: &mut Circuit,
+        inbox: &mut SelectAll<BoxStream<'static, CircuitEvent>>,
+        context: &Arc<RelayContext>,
+        transport: &T,
+    ) -> Result<Option<CircuitClose>> {
+        match event {
+            CircuitEvent::Forward(onion) => match onion.message {
+                Message::Payload(payload) => {
+                    Self::handle_payload(payload, circuit, inbox, context, transport).await?
+                }
+                Message::Close(reason) => return Ok(Some((reason, CloseOrigin::PreviousHop))),
+                _ => {}
+            },
+            CircuitEvent::Backward(onion) => match onion.message {
+                Message::Close(reason) => return Ok(Some((reason, CloseOrigin::NextHop))),
+                message => Self::send_back(circuit, message).await?,
+            },
+            CircuitEvent::PreviousHopGone => return Ok(Some((None, CloseOrigin::PreviousHop))),
+            CircuitEvent::NextHopGone => return Ok(Some((None, CloseOrigin::NextHop))),
+            event => Self::handle_stream_event(event, circuit).await?,
+        }
 
-                    let new_circ_id = context_locked.circ_id_generator.get_uid();
-                    drop(guard);
-                    let layer_tunnel_arc = Self::relay_tunnel(relay_id, context.clone()).await?;
+        Ok(None)
+    }
 
-                    let onion = Onion {
+    // Peels this relay's layer off a Payload of a circuit and passes it on or handles it as the exit.
+    // From CELL_VERSION the layer passed on is padded to the length of this one.
+    // param payload: The payload of the onion
+    // param circuit: The circuit the onion came in on
+    // param inbox: The events of the circuit
+    // param context: Relay node context required for management of links
+    // param transport: The transport to reach the next relay over
+    async fn handle_payload(
+        payload: Vec<u8>,
+        circuit: &mut Circuit,
+        inbox: &mut SelectAll<BoxStream<'static, CircuitEvent>>,
+        context: &Arc<RelayContext>,
+        transport: &T,
+    ) -> Result<()> {
+        let counter = circuit.peel_cipher.counter();
+        let peeled_onion = open_layer(&payload, &mut circuit.peel_cipher, circuit.version).await?;
+
+        match peeled_onion.target {
+            Target::Relay(id) => {
+                let message = match peeled_onion.message {
+                    Message::Payload(layer) if circuit.version >= CELL_VERSION => {
+                        let len = payload.len();
+                        Message::Payload(pad_layer(layer, len, &circuit.peel_cipher, counter)?)
+                    }
+                    message => message,
+                };
+                Self::forward(id, message, circuit, inbox, context, transport).await
+            }
+            target => Self::exit_stream(target, peeled_onion.message, circuit, context).await,
+        }
+    }
+
+    // Tears a circuit down: forgets it, closes the connections of its streams and frees the
+    // IDs it has on its links. The Close is passed on in the directions it didn't come from.
+    // param circuit: The circuit to close
+    // param context: Relay node context holding the circuit
+    // param reason: Why the circuit was closed, if known
+    // param origin: Where the Close came from
+    async fn close_circuit(
+        circuit: Circuit,
+        context: &RelayContext,
+        reason: Option<String>,
+        origin: CloseOrigin,
+    ) {
+        context.circuits.lock().unwrap().remove(&circuit.key());
+        circuit.peel_link.close_circuit(circuit.id);
+
+        if let Some(next_hop) = circuit.next_hop {
+            if origin != CloseOrigin::NextHop {
+                let _ = next_hop
+                    .link
+                    .send_onion(Onion {
                         target: Target::Current,
-                        circuit_id: Some(new_circ_id),
-                        message: onion.message,
-                    };
-                    println!("New Onion: {:?}", onion);
+                        circuit_id: Some(next_hop.circuit_id),
+                        message: Message::Close(reason.clone()),
+                    })
+                    .await;
+            }
+            next_hop.link.close_circuit(next_hop.circuit_id);
+        }
+
+        // The data the consumer sent before closing is still written to the endpoints.
+        for stream in circuit.streams.into_values() {
+            stream.reader.abort();
+        }
+
+        if origin != CloseOrigin::PreviousHop {
+            let _ = circuit
+                .peel_link
+                .send_onion(Onion {
+                    target: Target::Current,
+                    circuit_id: Some(circuit.id),
+                    message: Message::Close(reason),
+                })
+                .await;
+        }
+    }
 
-                    layer_tunnel_arc.send_onion(onion).await?;
+    // Reads the next onion from a tunnel, returning None once the peer closed it
+    // param reader: The reader of the tunnel
+    async fn next_onion(reader: &mut TunnelOnionReader) -> Result<Option<Onion>> {
+        match reader.read().await {
+            Ok(onion) => Ok(Some(onion)),
+            Err(err) if err.is_eof() => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
 
-                    task::spawn(Self::test(new_circ_id, context.clone()));
+    // Opens a circuit on a link to the next relay
+    // param link: The link to open the circuit on
+    fn open_circuit(link: &Link) -> Result<(u32, CircuitQueue)> {
+        link.open_circuit().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotConnected,
+                format!("link to {} is down", link.peer_addr()),
+            )
+        })
+    }
+
+    // Passes a peeled onion on to the next relay of the circuit, extending the circuit to that
+    // relay first if the onion carries the consumer's HelloRequest for it
+    // param relay_id: The relay the peeled onion is for
+    // param message: The message of the peeled onion
+    // param circuit: The circuit the onion came in on
+    // param inbox: The events of the circuit, which the link to the next relay is added to
+    // param context: Relay node context required for management of links
+    // param transport: The transport to reach the next relay over
+    async fn forward(
+        relay_id: u32,
+        message: Message,
+        circuit: &mut Circuit,
+        inbox: &mut SelectAll<BoxStream<'static, CircuitEvent>>,
+        context: &Arc<RelayContext>,
+        transport: &T,
+    ) -> Result<()> {
+        let next_hop = match &mut circuit.next_hop {
+            Some(next_hop) => next_hop,
+            None => {
+                if !matches!(message, Message::HelloRequest(_)) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "circuit does not go past this relay",
+                    ));
                 }
+                let link = Self::relay_tunnel(relay_id, context, transport).await?;
+                let (circuit_id, queue) = Self::open_circuit(&link)?;
+                inbox.push(Self::queue_events(
+                    queue,
+                    CircuitEvent::Backward,
+                    CircuitEvent::NextHopGone,
+                ));
+                circuit.next_hop.insert(NextHop { circuit_id, link })
+            }
+        };
 
-                //relay_1 sends onion {
-                //  target: current,
-                //  circuit_id: Some(325555555),
-                //  message: HelloRequest(data)
-                //} to_relay_2
-                ClientType::Relay => {
-                    let prev_circuit_id = onion.circuit_id.expect("did not receive circuit id");
-
-                    match onion.message {
-                        Message::HelloRequest(req) => {
-                            let new_circuit_id = context_locked.circ_id_generator.get_uid();
-                            match onion.target {
-                                Target::Relay(id) => {
-                                    let layer_tunnel_arc =
-                                        Self::relay_tunnel(id, context.clone()).await?;
-
-                                    let symmetric_cipher = context_locked
-                                        .crypto
-                                        .gen_secret()
-                                        .symmetric_cipher(req.public_key);
-
-                                    context_locked.circuits.insert(
-                                        prev_circuit_id,
-                                        Arc::new(Circuit {
-                                            id: new_circuit_id,
-                                            symmetric_cipher,
-                                            peel_tunnel_addr: peel_tunnel_arc.peer_addr(),
-                                            layer_tunnel_addr: layer_tunnel_arc.peer_addr(),
-                                            endpoint_connection: None,
-                                        }),
-                                    );
-                                }
-                                Target::Current => {
-                                    let pub_key = context_locked.crypto.gen_secret().public_key();
-
-                                    peel_tunnel_arc
-                                        .send_onion(Onion {
-                                            target: Target::Current,
-                                            circuit_id: None,
-                                            message: Message::HelloResponse(pub_key),
-                                        })
-                                        .await?;
-                                }
-                                _ => panic!("must have a relay to send to!"),
-                            };
-                        }
-                        Message::Payload(ref payload) => {
-                            let circuit = context_locked
-                                .circuits
-                                .get(&prev_circuit_id)
-                                .map(|circ_ref| circ_ref.clone())
-                                .expect("failed to get circuit");
-
-                            let peeled_onion = peel_tunnel_arc
-                                .peel_layer(payload.to_vec(), circuit.symmetric_cipher.clone())
-                                .await?;
-
-                            match peeled_onion.target {
-                                Target::Current => {
-                                    panic!("got current")
-                                }
-                                Target::Relay(id) => {
-                                    drop(guard);
-                                    let layer_tunnel_arc =
-                                        Self::relay_tunnel(id, context.clone()).await?;
-
-                                    let onion = Onion {
-                                        target: Target::Current,
-                                        circuit_id: Some(circuit.id),
-                                        message: peeled_onion.message,
-                                    };
-
-                                    layer_tunnel_arc.send_onion(onion).await?;
-                                }
-                                Target::IP(ip) => {
-                                    //check if connected
-                                    //false -> connect
-                                    //true -> send to the conn conn
-                                    //send Message::Payload(data) => data
-
-                                    // let circuit = context_locked.circuits.get(&circuit_id).map(|circ| circ.clone()).expect("failed to get circuit");
-
-                                    // if let Some(connection) = circuit.endpoint_connection {
-                                    //     let (reader, writer) = &mut (&connection, &connection);
-
-                                    //     match onion.message {
-                                    //         Message::Payload(payload) => {
-
-                                    //         },
-                                    //         _ => todo!()
-                                    //     }
-                                    // } else {
-                                    //     // connect
-                                    //     circuit.endpoint_connection = Some(TcpStream::connect(ip).await?);
-                                    // }
-                                    let data = match onion.message {
-                                        Message::Payload(data) => data,
-                                        _ => panic!("Wrong message type"),
-                                    };
-                                }
-                            }
+        next_hop
+            .link
+            .send_onion(Onion {
+                target: Target::Current,
+                circuit_id: Some(next_hop.circuit_id),
+                message,
+            })
+            .await
+    }
+
+    // Handles a stream message that reached the exit of its circuit, keeping the
+    // circuit's stream table and flow control windows up to date.
+    // param target: The target of the peeled onion
+    // param message: The stream message of the peeled onion
+    // param circuit: The circuit the onion came in on
+    // param context: Relay node context holding the exit policy
+    async fn exit_stream(
+        target: Target,
+        message: Message,
+        circuit: &mut Circuit,
+        context: &RelayContext,
+    ) -> Result<()> {
+        match message {
+            Message::BeginStream(stream_id) => {
+                if circuit.streams.contains_key(&stream_id) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("stream {} is already open", stream_id),
+                    ));
+                }
+                let exit_policy = context.exit_policy.lock().unwrap().clone();
+                let (writer, data) = mpsc::channel(STREAM_WINDOW as usize);
+                let send_window = Arc::new(SendWindow::stream());
+                let (reader, registration) = AbortHandle::new_pair();
+                runtime::spawn(Self::exit_endpoint(
+                    stream_id,
+                    target,
+                    exit_policy,
+                    data,
+                    registration,
+                    send_window.clone(),
+                    circuit.send_window.clone(),
+                    circuit.events.clone(),
+                ));
+                circuit.streams.insert(
+                    stream_id,
+                    ExitStream {
+                        writer,
+                        reader,
+                        send_window,
+                        recv_window: RecvWindow::stream(),
+                    },
+                );
+            }
+            Message::Data(StreamData { stream_id, data }) => {
+                let queued = match circuit.streams.get_mut(&stream_id) {
+                    Some(stream) => match stream.writer.try_send(data) {
+                        Ok(()) => true,
+                        // The writer holds no more than the stream's window.
+                        Err(err) if err.is_full() => {
+                            return Err(ProtocolError::FlowControlViolation.into())
                         }
-                        _ => {}
+                        // The connection failed, its end is on the way.
+                        Err(_) => false,
+                    },
+                    // The stream was refused or ended while the data was on its way.
+                    None => {
+                        println!("Dropping data for closed stream {}", stream_id);
+                        false
                     }
+                };
+                // Dropped data still used up the circuit's window.
+                if !queued {
+                    Self::deliver(circuit, None).await?;
+                }
+            }
+            Message::EndStream(stream_id) => {
+                // Dropping the writer lets the endpoint see the end once the data is written.
+                if let Some(stream) = circuit.streams.remove(&stream_id) {
+                    stream.reader.abort();
                 }
             }
+            Message::Sendme(None) => circuit.send_window.credit()?,
+            Message::Sendme(Some(stream_id)) => {
+                // Credit can still arrive for a stream that ended.
+                if let Some(stream) = circuit.streams.get(&stream_id) {
+                    stream.send_window.credit()?;
+                }
+            }
+            message => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unexpected message at the exit: {:?}", message),
+                ))
+            }
         }
 
         Ok(())
     }
 
-    async fn test(circuit_id: u32, context: Arc<Mutex<RelayContext>>) -> Result<()> {
-        let mut guard = context.lock().await;
-        let context_locked = &mut *guard;
+    // Handles what the task of a stream reports about its endpoint
+    // param event: The event of the stream
+    // param circuit: The circuit of the stream
+    async fn handle_stream_event(event: CircuitEvent, circuit: &mut Circuit) -> Result<()> {
+        match event {
+            // Nothing is confirmed for a stream the consumer ended meanwhile, and peers that
+            // predate the confirmation take a stream as open once they begin it.
+            CircuitEvent::StreamOpened(stream_id)
+                if circuit.streams.contains_key(&stream_id)
+                    && circuit.version >= STREAM_OPENED_VERSION =>
+            {
+                Self::send_back(circuit, Message::StreamOpened(stream_id)).await?;
+            }
+            // The consumer may have ended the stream while it was being opened.
+            CircuitEvent::StreamRefused(stream_id, reason) => {
+                if circuit.streams.remove(&stream_id).is_none() {
+                    return Ok(());
+                }
+                println!("Refusing stream {}: {:?}", stream_id, reason);
+                // Peers that predate exit policies only know that the stream ended.
+                let refusal = match circuit.version >= EXIT_POLICY_VERSION {
+                    true => Message::StreamRefused(StreamRefusal { stream_id, reason }),
+                    false => Message::EndStream(stream_id),
+                };
+                Self::send_back(circuit, refusal).await?;
+            }
+            // The consumer may have ended the stream while we waited for the endpoint.
+            CircuitEvent::EndpointData(stream_id, data)
+                if circuit.streams.contains_key(&stream_id) =>
+            {
+                Self::send_back(circuit, Message::Data(StreamData { stream_id, data })).await?;
+            }
+            CircuitEvent::EndpointDelivered(stream_id) => {
+                Self::deliver(circuit, Some(stream_id)).await?
+            }
+            // Tells the consumer, unless the consumer ended the stream first.
+            CircuitEvent::EndpointClosed(stream_id) if circuit.streams.contains_key(&stream_id) => {
+                println!("Stream {} ended", stream_id);
+                if let Some(stream) = circuit.streams.remove(&stream_id) {
+                    stream.reader.abort();
+                }
+                Self::send_back(circuit, Message::EndStream(stream_id)).await?;
+            }
+            _ => {}
+        }
 
-        let circuit = context_locked
-            .circuits
-            .get(&circuit_id)
-            .map(|circ| circ.clone())
-            .expect("Failed to find circuit");
+        Ok(())
+    }
 
-        let layer_tunnel_arc = context_locked
-            .relay_tunnels
-            .get(&circuit.layer_tunnel_addr)
-            .map(|tunnel| tunnel.clone())
-            .expect("Failed to find tunnel");
+    // Counts a Data message from the consumer as delivered, sending the Sendmes that are due.
+    // Data for a stream that ended only counts towards the circuit's window.
+    // param circuit: The circuit the data came in on
+    // param stream_id: The stream the data was delivered on, if it is still open
+    async fn deliver(circuit: &mut Circuit, stream_id: Option<u32>) -> Result<()> {
+        let stream_sendme = stream_id.filter(|stream_id| {
+            circuit
+                .streams
+                .get_mut(stream_id)
+                .is_some_and(|stream| stream.recv_window.deliver())
+        });
+
+        if circuit.recv_window.deliver() {
+            Self::send_back(circuit, Message::Sendme(None)).await?;
+        }
+        if let Some(stream_id) = stream_sendme {
+            Self::send_back(circuit, Message::Sendme(Some(stream_id))).await?;
+        }
+        Ok(())
+    }
 
-        let peel_tunnel_arc = context_locked
-            .relay_tunnels
-            .get(&circuit.peel_tunnel_addr)
-            .map(|tunnel| tunnel.clone())
-            .expect("Failed to find tunnel");
+    // Opens the connection of a stream if the exit policy allows it, then writes what the
+    // consumer sends to the endpoint and reads what the endpoint answers, reporting both to
+    // the circuit until the stream ends
+    // param stream_id: The stream to open
+    // param target: The IP or Domain target of the BeginStream onion
+    // param exit_policy: The exit policy of the relay
+    // param data: The data the consumer sends on the stream
+    // param reader: Stops reading the endpoint once the stream ends
+    // param send_window: The send window of the stream
+    // param circuit_window: The send window of the circuit
+    // param events: Reaches the task of the circuit
+    #[allow(clippy::too_many_arguments)]
+    async fn exit_endpoint(
+        stream_id: u32,
+        target: Target,
+        exit_policy: ExitPolicy,
+        data: Receiver<Vec<u8>>,
+        reader: AbortRegistration,
+        send_window: Arc<SendWindow>,
+        circuit_window: Arc<SendWindow>,
+        mut events: Sender<CircuitEvent>,
+    ) {
+        let connection = match Self::connect_endpoint(target, &exit_policy).await {
+            Ok(opened) => opened,
+            Err(reason) => {
+                let _ = events
+                    .send(CircuitEvent::StreamRefused(stream_id, reason))
+                    .await;
+                return;
+            }
+        };
+        let _ = events.send(CircuitEvent::StreamOpened(stream_id)).await;
+
+        let (read_half, write_half) = connection.split();
+        let endpoint_reader = Self::endpoint_reader(
+            stream_id,
+            read_half,
+            send_window,
+            circuit_window,
+            events.clone(),
+        );
+        // Whether the reader was aborted makes no difference here.
+        let _ = join(
+            Self::endpoint_writer(stream_id, write_half, data, events),
+            Abortable::new(endpoint_reader, reader),
+        )
+        .await;
+    }
 
-        while let onion = layer_tunnel_arc.recv_onion().await {
-            let encrypted_onion = layer_tunnel_arc.add_layer(onion).await?;
+    // Writes the data the consumer sends on a stream to its endpoint, reporting every message
+    // that was delivered so the circuit can give the consumer credit. The connection is closed
+    // for writing once the stream ends.
+    // param stream_id: The stream the endpoint connection belongs to
+    // param writer: The write half of the endpoint connection
+    // param data: The data the consumer sends on the stream
+    // param events: Reaches the task of the circuit
+    async fn endpoint_writer(
+        stream_id: u32,
+        mut writer: WriteHalf<runtime::TcpStream>,
+        mut data: Receiver<Vec<u8>>,
+        mut events: Sender<CircuitEvent>,
+    ) {
+        while let Some(data) = data.next().await {
+            if let Err(err) = writer.write_all(&data).await {
+                println!("Ending stream {}: {}", stream_id, err);
+                let _ = events.send(CircuitEvent::EndpointClosed(stream_id)).await;
+                return;
+            }
+            // The circuit may be closed, the data is written all the same.
+            let _ = events
+                .send(CircuitEvent::EndpointDelivered(stream_id))
+                .await;
+        }
 
-            peel_tunnel_arc
-                .send_onion(Onion {
-                    circuit_id: Some(circuit.id),
-                    message: Message::Payload(encrypted_onion),
-                    target: Target::Current,
-                })
-                .await?;
+        let _ = writer.close().await;
+    }
+
+    // Reads what the endpoint of a stream sends and reports it to the circuit. Stops reading
+    // while the stream or circuit window is exhausted, until the consumer gives credit.
+    // param stream_id: The stream the endpoint connection belongs to
+    // param reader: The read half of the endpoint connection
+    // param send_window: The send window of the stream
+    // param circuit_window: The send window of the circuit
+    // param events: Reaches the task of the circuit
+    async fn endpoint_reader(
+        stream_id: u32,
+        mut reader: ReadHalf<runtime::TcpStream>,
+        send_window: Arc<SendWindow>,
+        circuit_window: Arc<SendWindow>,
+        mut events: Sender<CircuitEvent>,
+    ) {
+        let mut buf = vec![0; ENDPOINT_READ_LEN];
+        loop {
+            send_window.reserve().await;
+            circuit_window.reserve().await;
+
+            let read = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            let data = buf[..read].to_vec();
+            if events
+                .send(CircuitEvent::EndpointData(stream_id, data))
+                .await
+                .is_err()
+            {
+                return;
+            }
         }
 
-        Ok(())
+        let _ = events.send(CircuitEvent::EndpointClosed(stream_id)).await;
+    }
+
+    // Adds the circuit's layer to a message and sends it towards the consumer. Only the task
+    // of the circuit sends back on it, so layers reach the consumer in the order they were added.
+    // param circuit: The circuit to send the message back on
+    // param message: The message for the consumer
+    async fn send_back(circuit: &mut Circuit, message: Message) -> Result<()> {
+        let onion = Onion {
+            circuit_id: None,
+            message,
+            target: Target::Current,
+        };
+        let encrypted_onion = seal_layer(onion, &mut circuit.layer_cipher, circuit.version).await?;
+
+        circuit
+            .peel_link
+            .send_onion(Onion {
+                circuit_id: Some(circuit.id),
+                message: Message::Payload(encrypted_onion),
+                target: Target::Current,
+            })
+            .await
     }
 
-    //
-    async fn relay_ip(stream: TcpStream) {
-        // tentative
+    // Connects to the endpoint of a stream if the exit policy allows it, resolving domain
+    // targets on the exit relay and trying every allowed address they resolve to
+    // param target: The IP or Domain target of the BeginStream onion
+    // param exit_policy: The exit policy of the relay
+    async fn connect_endpoint(
+        target: Target,
+        exit_policy: &ExitPolicy,
+    ) -> std::result::Result<runtime::TcpStream, RefusalReason> {
+        let endpoints = match target {
+            Target::IP(addr) => vec![addr],
+            Target::Domain(host, port) => match runtime::resolve(&host, port).await {
+                Ok(endpoints) if !endpoints.is_empty() => endpoints,
+                _ => return Err(RefusalReason::ResolveFailed),
+            },
+            _ => return Err(RefusalReason::ConnectFailed),
+        };
+
+        let endpoints: Vec<SocketAddr> = endpoints
+            .into_iter()
+            .filter(|&endpoint| exit_policy.allows(endpoint))
+            .collect();
+        if endpoints.is_empty() {
+            return Err(RefusalReason::ExitPolicy);
+        }
+
+        // Where a stream exits to is not logged, that is what its circuit hides.
+        for endpoint in endpoints {
+            if let Ok(connection) = runtime::connect(endpoint).await {
+                return Ok(connection);
+            }
+        }
+        Err(RefusalReason::ConnectFailed)
     }
 
-    // Gets or creates a new secure onion tunnel to another relay based on whether or not there exists a previous connection to said relay
+    // Passes everything the first layered relay of a consumer's circuit sends back on to the
+    // consumer as it is, including the Close that ends the circuit
+    // param queue: The onions the first layered relay sends on the circuit
+    // param writer: The writer of the tunnel to the consumer
+    async fn consumer_backward(
+        mut queue: CircuitQueue,
+        mut writer: TunnelOnionWriter,
+    ) -> Result<()> {
+        loop {
+            let message = match queue.next().await {
+                Some(onion) => onion.message,
+                None => Message::Close(None),
+            };
+            let closed = matches!(message, Message::Close(_));
+
+            writer
+                .write(Onion {
+                    target: Target::Current,
+                    circuit_id: None,
+                    message,
+                })
+                .await?;
+            if closed {
+                return Ok(());
+            }
+        }
+    }
+
+    // Gets or creates a new secure link to another relay based on whether or not there exists a previous connection to said relay.
+    // Every circuit to that relay shares the link.
     // param relay_id: The public id of the relay to connect to, used by the index node
-    // param context: Index node context required for management of circuits, tunnels, id generation and cryptography in a static context
+    // param context: Relay node context holding the known relays and links
+    // param transport: The transport to reach the relay over
     async fn relay_tunnel(
         relay_id: u32,
-        context: Arc<Mutex<RelayContext>>,
-    ) -> Result<Arc<OnionTunnel>> {
-        let mut guard = context.lock().await;
-        let context_locked = &mut *guard;
-
-        let relay = context_locked
-            .indexed_relays
-            .iter()
-            .find(|relay| relay.id == relay_id)
-            .expect("Relay not indexed");
+        context: &Arc<RelayContext>,
+        transport: &T,
+    ) -> Result<Arc<Link>> {
+        let relay = Self::find_relay(relay_id, context, transport).await?;
 
-        let tunnel = if let Some(existing_tunnel) = context_locked
+        let known = context
             .relay_tunnels
-            .contains_key(&relay.addr)
-            .then(|| context_locked.relay_tunnels.get(&relay.addr))
-            .map(|tunnel| tunnel.unwrap())
+            .lock()
+            .unwrap()
+            .get(&relay.addr)
+            .cloned();
+        if let Some(link) = known {
+            return Ok(link);
+        }
+
+        let crypto = ClientCrypto::new(&relay.pub_key)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid signing key"))?;
+        let secret = crypto.gen_secret();
+        let tunnel = OnionTunnel::reach_relay(
+            transport.connect(relay.addr).await?,
+            relay.addr,
+            secret,
+            context.max_frame_len.load(Ordering::Relaxed),
+            context.cells.load(Ordering::Relaxed),
+        )
+        .await?;
+        let (link, reader) = Link::spawn(tunnel);
+
+        // Another circuit may have connected to the relay meanwhile. Its link is used then,
+        // and ours is dropped.
         {
-            existing_tunnel.clone()
-        } else {
-            let crypto = ClientCrypto::new(&relay.pub_key).expect("Failed to generate crypto");
-            let secret = crypto.gen_secret();
+            let mut links = context.relay_tunnels.lock().unwrap();
+            if let Some(known) = links.get(&relay.addr) {
+                return Ok(known.clone());
+            }
+            links.insert(relay.addr, link.clone());
+        }
+        runtime::spawn(Self::serve_link(reader, context.clone()));
 
-            let tunnel = Arc::new(
-                OnionTunnel::reach_relay(TcpStream::connect(relay.addr).await?, secret).await?,
-            );
-            context_locked
-                .relay_tunnels
-                .insert(relay.addr, tunnel.clone());
+        Ok(link)
+    }
 
-            tunnel.clone()
+    // Looks up the address and key of a relay. Relays that registered after us are only known
+    // to the index node, which is asked again if the relay is not known yet.
+    // param relay_id: The public id of the relay, used by the index node
+    // param context: Relay node context holding the known relays
+    // param transport: The transport to reach the index node over
+    async fn find_relay(relay_id: u32, context: &RelayContext, transport: &T) -> Result<Relay> {
+        let find_relay =
+            |relays: &[Relay]| relays.iter().find(|relay| relay.id == relay_id).cloned();
+
+        let known = find_relay(&context.indexed_relays.lock().unwrap());
+        let index = *context.index.lock().unwrap();
+        let relay = match (known, index) {
+            (Some(relay), _) => Some(relay),
+            (None, Some((index_addr, index_signing_pub_key))) => {
+                let relays = Self::index_all_relays(
+                    transport,
+                    index_addr,
+                    index_signing_pub_key,
+                    context.max_frame_len.load(Ordering::Relaxed),
+                    context.cells.load(Ordering::Relaxed),
+                )
+                .await?;
+                let relay = find_relay(&relays);
+                *context.indexed_relays.lock().unwrap() = relays;
+                relay
+            }
+            (None, None) => None,
         };
 
-        Ok(tunnel)
+        relay.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("relay {} is not indexed", relay_id),
+            )
+        })
     }
 
-    // Establishes a secure onion tunnel on the given connection
+    // Reads a link this relay opened to another relay until the other relay hangs up. The
+    // link only carries the circuits opened on it, so nothing is left for this task but
+    // forgetting the link, after which the next circuit to that relay opens a new one.
+    // param reader: The reader of the link
+    // param context: Relay node context holding the link
+    async fn serve_link(mut reader: LinkReader, context: Arc<RelayContext>) {
+        let peer_addr = reader.link().peer_addr();
+        loop {
+            match reader.recv_unrouted().await {
+                Ok(Some(onion)) => println!(
+                    "Dropping onion for unknown circuit {:?} from {}",
+                    onion.circuit_id, peer_addr
+                ),
+                Ok(None) => break,
+                Err(err) => {
+                    println!("Link to {} failed: {}", peer_addr, err);
+                    break;
+                }
+            }
+        }
+
+        let mut links = context.relay_tunnels.lock().unwrap();
+        if links
+            .get(&peer_addr)
+            .is_some_and(|known| Arc::ptr_eq(known, reader.link()))
+        {
+            links.remove(&peer_addr);
+        }
+    }
+
+    // Establishes a secure onion tunnel on the given connection, answering the sender's HelloRequest
     // param stream: Connection to establish an onion tunnel on
+    // param peer_addr: The socket address of the sender
     // param secret: The secret to use with the onion tunnel
+    // param max_frame_len: The largest frame to accept on the onion tunnel
+    // param cells: Whether this relay wants the onion tunnel split into cells
     async fn establish_sender_tunnel(
-        stream: TcpStream,
+        stream: T::Stream,
+        peer_addr: SocketAddr,
         secret: ServerSecret,
+        max_frame_len: u32,
+        cells: bool,
     ) -> Result<(OnionTunnel, HelloRequest)> {
-        let reader = &mut RawOnionReader::new(&stream);
+        let (mut reader, mut writer) = OnionTunnel::split(stream, max_frame_len);
 
         let sender_hello = reader.read().await?;
         if let Message::HelloRequest(req) = sender_hello.message {
-            Ok((
-                OnionTunnel::new(stream, secret.symmetric_cipher(req.public_key)),
-                req,
-            ))
+            let negotiation = match Negotiation::accept(&req, cells) {
+                Ok(negotiation) => negotiation,
+                Err(err) => {
+                    writer
+                        .write(Onion {
+                            target: Target::Current,
+                            circuit_id: None,
+                            message: Message::Close(Some(err.to_string())),
+                        })
+                        .await?;
+                    return Err(Error::new(ErrorKind::InvalidData, err.to_string()));
+                }
+            };
+            writer
+                .write(Onion {
+                    target: Target::Current,
+                    circuit_id: None,
+                    message: Message::HelloResponse(HelloResponse {
+                        signed_public_key: secret.public_key(),
+                        cipher_suite: negotiation.cipher_suite,
+                        version: negotiation.version,
+                        cells: negotiation.cells,
+                    }),
+                })
+                .await?;
+
+            let ciphers = secret.symmetric_ciphers(req.public_key, &negotiation);
+            let tunnel = OnionTunnel::new(reader, writer, peer_addr, ciphers, &negotiation);
+            Ok((tunnel, req))
         } else {
             Err(Error::new(ErrorKind::InvalidData, "Expected Hello request"))
         }
     }
 
-    async fn establish_receiver_channel(stream: TcpStream, secret: ClientSecret) {}
-
     // Contacts the given index node and returns all other registered indexes on the onion network
+    // param transport: The transport to reach the index node over
     // param index_addr: The socket address of the index node
     // param index_signing_pub_key: The signing public key of the index node
+    // param max_frame_len: The largest frame to accept from the index node
+    // param cells: Whether to ask for cells on the link to the index node
     async fn index_all_relays(
+        transport: &T,
         index_addr: SocketAddr,
         index_signing_pub_key: [u8; 32],
+        max_frame_len: u32,
+        cells: bool,
     ) -> Result<Vec<Relay>> {
-        let tunnel = Self::index_tunnel(index_addr, index_signing_pub_key).await?;
+        let tunnel = Self::index_tunnel(
+            transport,
+            index_addr,
+            index_signing_pub_key,
+            max_frame_len,
+            cells,
+        )
+        .await?;
 
         tunnel
             .send_onion(Onion {
@@ -408,50 +1340,640 @@ impl RelayNode {
             })
             .await?;
 
-        let relays_response = tunnel.recv_onion().await;
-        if let Message::GetRelaysResponse(relays) = relays_response.message {
-            Ok(relays)
-        } else {
-            //err?
-            todo!()
+        let relays_response = tunnel.recv_onion().await?;
+        match relays_response.message {
+            Message::Directory(directory) => directory
+                .verify(&index_signing_pub_key, unix_time())
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err)),
+            // Index nodes that predate directories send the relays unsigned.
+            Message::GetRelaysResponse(relays) if tunnel.version() < DIRECTORY_VERSION => {
+                Ok(relays)
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Expected relays from index node",
+            )),
         }
     }
 
     // Establishes a new secure onion tunnel to the given index node
+    // param transport: The transport to reach the index node over
     // param index_addr: The socket address of the index node
     // param index_signing_pub_key: The signing public key of the index node
+    // param max_frame_len: The largest frame to accept from the index node
+    // param cells: Whether to ask for cells on the link to the index node
     async fn index_tunnel(
+        transport: &T,
         addr: SocketAddr,
         index_signing_pub_key: [u8; 32],
+        max_frame_len: u32,
+        cells: bool,
     ) -> Result<OnionTunnel> {
-        let stream = TcpStream::connect(addr).await?;
-        let crypto = ClientCrypto::new(&index_signing_pub_key).expect("Failed to generate crypto");
+        let stream = transport.connect(addr).await?;
+        let crypto = ClientCrypto::new(&index_signing_pub_key)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid signing key"))?;
         let secret = crypto.gen_secret();
 
-        let mut writer = RawOnionWriter::new(&stream);
+        let (mut reader, mut writer) = OnionTunnel::split(stream, max_frame_len);
         writer
             .write(Onion {
                 target: Target::Current,
                 circuit_id: None,
-                message: Message::HelloRequest(HelloRequest {
-                    client_type: ClientType::Relay,
-                    public_key: secret.public_key(),
-                }),
+                message: Message::HelloRequest(
+                    HelloRequest::new(ClientType::Relay, secret.public_key()).with_cells(cells),
+                ),
             })
             .await?;
 
-        let mut reader = RawOnionReader::new(&stream);
         let hello_response = reader.read().await?;
 
-        let symmetric_cipher = if let Message::HelloResponse(peer_key) = hello_response.message {
-            secret
-                .symmetric_cipher(peer_key)
-                .expect("failed to generate symmetric cipher")
-        } else {
-            //err?
-            todo!()
+        let resp = OnionTunnel::hello_response(hello_response.message)?;
+        let negotiation = Negotiation::confirm(&resp, cells)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Unoffered version or suite"))?;
+        let ciphers = secret
+            .symmetric_ciphers(resp.signed_public_key, &negotiation)
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "handshake not signed with index key",
+                )
+            })?;
+
+        Ok(OnionTunnel::new(
+            reader,
+            writer,
+            addr,
+            ciphers,
+            &negotiation,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use futures::io;
+
+    use super::*;
+    use crate::{
+        consumer_node::consumer::{Consumer, StreamEvent},
+        crypto::ServerCrypto,
+        index_node::index_node::IndexNode,
+        protocol::{error::ProtocolError, onion::PROTOCOL_VERSIONS},
+        transport::MemoryTransport,
+    };
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    // Echoes everything sent on every connection to a TCP listener on localhost
+    async fn echo_server() -> SocketAddr {
+        let listener = runtime::TcpListener::bind(SocketAddr::new(LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        runtime::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                runtime::spawn(async move {
+                    let (reader, mut writer) = stream.split();
+                    io::copy(reader, &mut writer).await
+                });
+            }
+        });
+
+        addr
+    }
+
+    // Reads stream events until the given data came back on every stream. The echoes of
+    // different streams may arrive in any order.
+    async fn recv_echoes(consumer: &mut Consumer<MemoryTransport>, expected: &[(u32, &[u8])]) {
+        let mut echoed: HashMap<u32, Vec<u8>> = HashMap::new();
+        while expected
+            .iter()
+            .any(|(id, data)| echoed.get(id).map_or(0, Vec::len) < data.len())
+        {
+            match consumer.recv_message().await.unwrap() {
+                StreamEvent::Data(id, payload) => echoed.entry(id).or_default().extend(payload),
+                StreamEvent::End(id) => panic!("stream {} ended early", id),
+                _ => {}
+            }
+        }
+        for (id, data) in expected {
+            assert_eq!(echoed[id], *data);
+        }
+    }
+
+    // Starts an index node and three relay nodes with the given exit policy in memory,
+    // returning a consumer with a circuit through the relays and the contexts of the relays
+    fn network(exit_policy: ExitPolicy) -> (Consumer<MemoryTransport>, Vec<Arc<RelayContext>>) {
+        let (mut consumers, contexts) = network_with_consumers(exit_policy, 1);
+        (consumers.remove(0), contexts)
+    }
+
+    // Like network, but with the given number of consumers. Every consumer builds its
+    // circuit through the same relays, so the circuits share the links between them.
+    fn network_with_consumers(
+        exit_policy: ExitPolicy,
+        consumers: usize,
+    ) -> (Vec<Consumer<MemoryTransport>>, Vec<Arc<RelayContext>>) {
+        let relays = relays_with_policies(&[exit_policy.clone(), exit_policy.clone(), exit_policy]);
+        let consumers = (0..consumers)
+            .map(|_| runtime::block_on(relays.consumer(None)).unwrap())
+            .collect();
+        (consumers, relays.contexts)
+    }
+
+    // An index and the relays registered at it, all in memory.
+    struct Relays {
+        transport: MemoryTransport,
+        index_addr: SocketAddr,
+        index_key: [u8; 32],
+        contexts: Vec<Arc<RelayContext>>,
+    }
+
+    impl Relays {
+        // Creates a consumer with a circuit through the relays
+        async fn consumer(&self, exit_port: Option<u16>) -> Result<Consumer<MemoryTransport>> {
+            Consumer::with_transport(
+                self.transport.clone(),
+                self.index_addr,
+                self.index_key,
+                RELAY_MAX_FRAME_LEN,
+                exit_port,
+            )
+            .await
+        }
+    }
+
+    // Starts an index node and a relay with each of the given exit policies in memory
+    fn relays_with_policies(exit_policies: &[ExitPolicy]) -> Relays {
+        let transport = MemoryTransport::new();
+        let index_crypto = ServerCrypto::new();
+        let index_addr = SocketAddr::new(LOCALHOST, 9000);
+        let index = IndexNode::with_transport(
+            LOCALHOST,
+            index_addr.port(),
+            index_crypto.to_bytes(),
+            transport.clone(),
+        );
+        runtime::block_on(index.spawn()).unwrap();
+
+        let mut contexts = Vec::new();
+        for (port, exit_policy) in (9001..).zip(exit_policies) {
+            let relay = RelayNode::with_transport(LOCALHOST, port, transport.clone());
+            relay.set_exit_policy(exit_policy.clone());
+            relay.register(index_addr, index_crypto.signing_public());
+            contexts.push(relay.context.clone());
+            runtime::block_on(relay.spawn()).unwrap();
+        }
+
+        Relays {
+            transport,
+            index_addr,
+            index_key: index_crypto.signing_public(),
+            contexts,
+        }
+    }
+
+    // Waits for every relay to forget the circuits through it
+    async fn circuits_closed(contexts: &[Arc<RelayContext>]) {
+        for _ in 0..100 {
+            let mut open = 0;
+            for context in contexts {
+                open += context.circuits.lock().unwrap().len();
+            }
+            if open == 0 {
+                return;
+            }
+            runtime::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("circuits were not closed");
+    }
+
+    #[test]
+    fn circuit_pipes_streams_to_echo_server() {
+        // The echo server is on loopback, which the default policy rejects.
+        let (mut consumer, _) = network("accept *:*".parse().unwrap());
+
+        runtime::block_on(async {
+            let echo_addr = echo_server().await;
+
+            let ip_stream = consumer.begin_stream(Target::IP(echo_addr)).await.unwrap();
+            let domain_stream = consumer
+                .begin_stream(Target::Domain("localhost".to_string(), echo_addr.port()))
+                .await
+                .unwrap();
+
+            consumer
+                .send_data(ip_stream, b"hello".to_vec())
+                .await
+                .unwrap();
+            consumer
+                .send_data(domain_stream, b"world".to_vec())
+                .await
+                .unwrap();
+            recv_echoes(
+                &mut consumer,
+                &[(ip_stream, b"hello"), (domain_stream, b"world")],
+            )
+            .await;
+
+            consumer.end_stream(ip_stream).await.unwrap();
+            consumer.end_stream(domain_stream).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn exit_confirms_opened_stream_before_its_data() {
+        let (mut consumer, _) = network("accept *:*".parse().unwrap());
+
+        runtime::block_on(async {
+            let echo_addr = echo_server().await;
+            let stream_id = consumer.begin_stream(Target::IP(echo_addr)).await.unwrap();
+
+            assert_eq!(
+                consumer.recv_message().await.unwrap(),
+                StreamEvent::Opened(stream_id)
+            );
+            consumer
+                .send_data(stream_id, b"hello".to_vec())
+                .await
+                .unwrap();
+            recv_echoes(&mut consumer, &[(stream_id, b"hello")]).await;
+        });
+    }
+
+    #[test]
+    fn circuits_sharing_links_get_their_own_onions() {
+        let (mut consumers, contexts) = network_with_consumers("accept *:*".parse().unwrap(), 2);
+
+        runtime::block_on(async {
+            // Past the entry relay, both circuits run over the same links, with IDs picked per link.
+            let mut shared = 0;
+            for context in &contexts {
+                let mut keys: Vec<_> = context.circuits.lock().unwrap().keys().copied().collect();
+                keys.sort();
+                if let [first, second] = keys[..] {
+                    assert_eq!(first.0, second.0);
+                    assert_eq!((first.1, second.1), (0, 1));
+                    shared += 1;
+                }
+            }
+            assert_eq!(shared, 2);
+
+            let echo_addr = echo_server().await;
+            let mut streams = Vec::new();
+            for consumer in &mut consumers {
+                streams.push(consumer.begin_stream(Target::IP(echo_addr)).await.unwrap());
+            }
+            for (i, consumer) in consumers.iter_mut().enumerate() {
+                consumer
+                    .send_data(streams[i], format!("consumer {}", i).into_bytes())
+                    .await
+                    .unwrap();
+            }
+            for (i, consumer) in consumers.iter_mut().enumerate() {
+                let data = format!("consumer {}", i);
+                recv_echoes(consumer, &[(streams[i], data.as_bytes())]).await;
+            }
+
+            for consumer in &mut consumers {
+                consumer.close().await.unwrap();
+            }
+            circuits_closed(&contexts).await;
+        });
+    }
+
+    #[test]
+    fn bad_hello_refuses_only_its_circuit() {
+        let relays = relays_with_policies(&["accept *:*".parse().unwrap()]);
+        let relay_addr = SocketAddr::new(LOCALHOST, 9001);
+        let relay_key = relays.contexts[0].crypto.signing_public();
+
+        runtime::block_on(async {
+            // Another relay links to this one and extends two circuits over the link.
+            let crypto = ClientCrypto::new(&relay_key).unwrap();
+            let tunnel = OnionTunnel::reach_relay(
+                relays.transport.connect(relay_addr).await.unwrap(),
+                relay_addr,
+                crypto.gen_secret(),
+                RELAY_MAX_FRAME_LEN,
+                true,
+            )
+            .await
+            .unwrap();
+            let (link, mut reader) = Link::spawn(tunnel);
+            runtime::spawn(async move { while let Ok(Some(_)) = reader.recv_unrouted().await {} });
+            let (bad_id, mut bad) = link.open_circuit().unwrap();
+            let (good_id, mut good) = link.open_circuit().unwrap();
+
+            let hello = |circuit_id, versions| {
+                let mut req =
+                    HelloRequest::new(ClientType::Consumer, crypto.gen_secret().public_key());
+                req.versions = versions;
+                Onion {
+                    target: Target::Current,
+                    circuit_id: Some(circuit_id),
+                    message: Message::HelloRequest(req),
+                }
+            };
+            // No version the relay speaks is offered on the first circuit.
+            link.send_onion(hello(bad_id, 0..=0)).await.unwrap();
+            link.send_onion(hello(good_id, PROTOCOL_VERSIONS))
+                .await
+                .unwrap();
+
+            let refused = bad.next().await.unwrap();
+            assert!(matches!(refused.message, Message::Close(Some(_))));
+            let accepted = good.next().await.unwrap();
+            assert!(matches!(accepted.message, Message::HelloResponse(_)));
+            assert_eq!(relays.contexts[0].circuits.lock().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn default_exit_policy_refuses_loopback() {
+        let (mut consumer, _) = network(ExitPolicy::default());
+
+        runtime::block_on(async {
+            let echo_addr = echo_server().await;
+            let stream_id = consumer.begin_stream(Target::IP(echo_addr)).await.unwrap();
+
+            let event = consumer.recv_message().await.unwrap();
+
+            assert_eq!(
+                event,
+                StreamEvent::Refused(stream_id, RefusalReason::ExitPolicy)
+            );
+            assert!(consumer
+                .send_data(stream_id, b"hello".to_vec())
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn consumer_exits_at_a_relay_that_allows_the_port() {
+        let echo_addr = runtime::block_on(echo_server());
+        let echo_port = echo_addr.port();
+        let other_port = echo_port.wrapping_add(1);
+        let relays = relays_with_policies(&[
+            format!("accept *:{}", echo_port).parse().unwrap(),
+            format!("accept *:{}", other_port).parse().unwrap(),
+            "reject *:*".parse().unwrap(),
+        ]);
+
+        runtime::block_on(async {
+            let mut consumer = relays.consumer(Some(echo_port)).await.unwrap();
+            let stream_id = consumer.begin_stream(Target::IP(echo_addr)).await.unwrap();
+            assert_eq!(
+                consumer.recv_message().await.unwrap(),
+                StreamEvent::Opened(stream_id)
+            );
+            consumer
+                .send_data(stream_id, b"hello".to_vec())
+                .await
+                .unwrap();
+            recv_echoes(&mut consumer, &[(stream_id, b"hello")]).await;
+
+            // The other exit only lets streams out to its own port.
+            let mut consumer = relays.consumer(Some(other_port)).await.unwrap();
+            let stream_id = consumer.begin_stream(Target::IP(echo_addr)).await.unwrap();
+            assert_eq!(
+                consumer.recv_message().await.unwrap(),
+                StreamEvent::Refused(stream_id, RefusalReason::ExitPolicy)
+            );
+
+            let unlisted_port = echo_port.wrapping_add(2);
+            assert!(relays.consumer(Some(unlisted_port)).await.is_err());
+        });
+    }
+
+    #[test]
+    fn consumer_close_tears_down_circuit() {
+        let (mut consumer, contexts) = network("accept *:*".parse().unwrap());
+
+        runtime::block_on(async {
+            let listener = runtime::TcpListener::bind(SocketAddr::new(LOCALHOST, 0))
+                .await
+                .unwrap();
+            let endpoint_addr = listener.local_addr().unwrap();
+            let (closed_sender, closed) = futures::channel::oneshot::channel();
+            runtime::spawn(async move {
+                let (mut endpoint, _) = listener.accept().await.unwrap();
+                let mut buf = Vec::new();
+                endpoint.read_to_end(&mut buf).await.unwrap();
+                closed_sender.send(()).unwrap();
+            });
+
+            let stream_id = consumer
+                .begin_stream(Target::IP(endpoint_addr))
+                .await
+                .unwrap();
+            consumer
+                .send_data(stream_id, b"hello".to_vec())
+                .await
+                .unwrap();
+            consumer.close().await.unwrap();
+
+            closed.await.unwrap();
+            circuits_closed(&contexts).await;
+        });
+    }
+
+    #[test]
+    fn relay_close_reaches_consumer() {
+        let (mut consumer, contexts) = network("accept *:*".parse().unwrap());
+
+        runtime::block_on(async {
+            // The exit relay fails the circuit, which does not go on to another relay.
+            consumer.begin_stream(Target::Relay(0)).await.unwrap();
+
+            let err = consumer.recv_message().await.unwrap_err();
+            assert!(matches!(
+                err,
+                ProtocolError::CircuitClosed(Some(reason))
+                    if reason == "circuit does not go past this relay"
+            ));
+            circuits_closed(&contexts).await;
+        });
+    }
+
+    #[test]
+    fn heartbeat_keeps_relay_listed_and_learns_new_relays() {
+        let transport = MemoryTransport::new();
+        let index_crypto = ServerCrypto::new();
+        let index_addr = SocketAddr::new(LOCALHOST, 9000);
+        let index = IndexNode::with_transport(
+            LOCALHOST,
+            index_addr.port(),
+            index_crypto.to_bytes(),
+            transport.clone(),
+        );
+        // The TTL is far longer than the heartbeat, so only the silent relay expires.
+        index.set_relay_ttl(Duration::from_millis(500));
+        runtime::block_on(index.spawn()).unwrap();
+
+        let relay = RelayNode::with_transport(LOCALHOST, 9001, transport.clone());
+        relay.set_heartbeat_interval(Duration::from_millis(20));
+        relay.register(index_addr, index_crypto.signing_public());
+        let context = relay.context.clone();
+        runtime::block_on(relay.spawn()).unwrap();
+
+        // The second relay only pings when it starts.
+        let silent = RelayNode::with_transport(LOCALHOST, 9002, transport.clone());
+        silent.set_heartbeat_interval(Duration::from_secs(3600));
+        silent.register(index_addr, index_crypto.signing_public());
+        runtime::block_on(silent.spawn()).unwrap();
+
+        let known_ports = |relays: &[Relay]| -> Vec<u16> {
+            relays.iter().map(|relay| relay.addr.port()).collect()
         };
 
-        Ok(OnionTunnel::new(stream, symmetric_cipher))
+        runtime::block_on(async {
+            let mut learned = false;
+            for _ in 0..200 {
+                learned = known_ports(&context.indexed_relays.lock().unwrap()) == [9001, 9002];
+                if learned {
+                    break;
+                }
+                runtime::sleep(Duration::from_millis(10)).await;
+            }
+            assert!(learned, "relay did not learn the silent relay");
+
+            let mut expired = false;
+            for _ in 0..200 {
+                let listed = RelayNode::index_all_relays(
+                    &transport,
+                    index_addr,
+                    index_crypto.signing_public(),
+                    RELAY_MAX_FRAME_LEN,
+                    true,
+                )
+                .await
+                .unwrap();
+                assert!(known_ports(&listed).contains(&9001));
+                expired = known_ports(&context.indexed_relays.lock().unwrap()) == [9001]
+                    && known_ports(&listed) == [9001];
+                if expired {
+                    break;
+                }
+                runtime::sleep(Duration::from_millis(20)).await;
+            }
+            assert!(expired, "silent relay is still listed");
+        });
+    }
+
+    // Starts an index node in memory, returning its address and signing public key
+    fn index(transport: &MemoryTransport) -> (SocketAddr, [u8; 32]) {
+        let index_crypto = ServerCrypto::new();
+        let index_addr = SocketAddr::new(LOCALHOST, 9000);
+        let index = IndexNode::with_transport(
+            LOCALHOST,
+            index_addr.port(),
+            index_crypto.to_bytes(),
+            transport.clone(),
+        );
+        runtime::block_on(index.spawn()).unwrap();
+        (index_addr, index_crypto.signing_public())
+    }
+
+    #[test]
+    fn listening_relay_registers_once_index_comes_up() {
+        let transport = MemoryTransport::new();
+        let index_crypto = ServerCrypto::new();
+        let index_addr = SocketAddr::new(LOCALHOST, 9000);
+
+        let relay = RelayNode::with_transport(LOCALHOST, 9001, transport.clone());
+        relay.register(index_addr, index_crypto.signing_public());
+        let context = relay.context.clone();
+        runtime::spawn(async move { relay.listen(SocketAddr::new(LOCALHOST, 9001)).await });
+
+        runtime::block_on(async {
+            // The first ping fails while the index node is down.
+            runtime::sleep(Duration::from_millis(100)).await;
+            let index = IndexNode::with_transport(
+                LOCALHOST,
+                index_addr.port(),
+                index_crypto.to_bytes(),
+                transport.clone(),
+            );
+            index.spawn().await.unwrap();
+
+            for _ in 0..100 {
+                if !context.indexed_relays.lock().unwrap().is_empty() {
+                    return;
+                }
+                runtime::sleep(Duration::from_millis(50)).await;
+            }
+            panic!("relay did not register");
+        });
+    }
+
+    #[test]
+    fn index_refuses_relays_it_cannot_reach() {
+        let transport = MemoryTransport::new();
+        let (index_addr, index_key) = index(&transport);
+
+        // The relay never listens on the port it advertises.
+        let relay = RelayNode::with_transport(LOCALHOST, 9001, transport.clone());
+        relay.register(index_addr, index_key);
+
+        runtime::block_on(async {
+            let err = RelayNode::heartbeat(&relay.context, &transport, 9001)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+            let listed = RelayNode::index_all_relays(
+                &transport,
+                index_addr,
+                index_key,
+                RELAY_MAX_FRAME_LEN,
+                true,
+            )
+            .await
+            .unwrap();
+            assert!(listed.is_empty());
+        });
+    }
+
+    #[test]
+    fn index_refuses_relay_advertising_port_of_another() {
+        let transport = MemoryTransport::new();
+        let (index_addr, index_key) = index(&transport);
+
+        let relay = RelayNode::with_transport(LOCALHOST, 9001, transport.clone());
+        relay.register(index_addr, index_key);
+        let relay_key = relay.context.crypto.signing_public();
+        runtime::block_on(relay.spawn()).unwrap();
+
+        // The impostor signs the challenge with its own key, but the relay that answers on
+        // the port does not.
+        let impostor = RelayNode::with_transport(LOCALHOST, 9002, transport.clone());
+        impostor.register(index_addr, index_key);
+
+        runtime::block_on(async {
+            let err = RelayNode::heartbeat(&impostor.context, &transport, 9001)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+            let listed = RelayNode::index_all_relays(
+                &transport,
+                index_addr,
+                index_key,
+                RELAY_MAX_FRAME_LEN,
+                true,
+            )
+            .await
+            .unwrap();
+            let listed: Vec<_> = listed
+                .iter()
+                .map(|relay| (relay.addr.port(), relay.pub_key))
+                .collect();
+            assert_eq!(listed, vec![(9001, relay_key)]);
+        });
     }
 }
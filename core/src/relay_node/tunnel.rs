@@ -1,55 +1,99 @@
-use std::{cell::RefCell, net::SocketAddr, sync::Arc};
+use std::net::SocketAddr;
 
-use crate::crypto::Aes256;
-use async_std::{
-    io::{Cursor, Read, Result, Write},
-    net::TcpStream,
-    sync::Mutex,
+use crate::crypto::{LinkCiphers, Negotiation, SuiteCipher};
+use futures::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    lock::Mutex,
 };
+use std::io::{Error, ErrorKind, Result};
 
 use crate::{
     crypto::ClientSecret,
     protocol::{
+        error::ProtocolError,
         io::{OnionReader, OnionWriter, RawOnionReader, RawOnionWriter},
-        onion::{ClientType, HelloRequest, Message, Onion, Target},
+        onion::{ClientType, HelloRequest, HelloResponse, Message, Onion, Target},
     },
 };
 
+/// The read half of the connection a tunnel runs on.
+pub type TunnelReader = Box<dyn AsyncRead + Send + Unpin>;
+/// The write half of the connection a tunnel runs on.
+pub type TunnelWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Reads the onions of a tunnel once the handshake is made.
+pub type TunnelOnionReader = OnionReader<TunnelReader, SuiteCipher>;
+/// Writes the onions of a tunnel once the handshake is made.
+pub type TunnelOnionWriter = OnionWriter<TunnelWriter, SuiteCipher>;
+
 pub struct OnionTunnel {
-    symmetric_cipher: Aes256,
-    reader: Mutex<OnionReader<TcpStream, Aes256>>,
-    writer: Mutex<OnionWriter<TcpStream, Aes256>>,
+    reader: Mutex<TunnelOnionReader>,
+    writer: Mutex<TunnelOnionWriter>,
     peer_addr: SocketAddr,
+    version: u8,
 }
 
 impl OnionTunnel {
-    // Returns a new OnionTunnel on which to read and write onions
-    // param stream: The connection to establish the tunnel on
-    // param symmetric_cipher: The symmetric cipher between the sender and receiver used in securing the tunnel
-    pub fn new(stream: TcpStream, symmetric_cipher: Aes256) -> Self {
+    // Returns a new OnionTunnel on which to read and write onions, continuing on the
+    // reader and writer the handshake was made on
+    // param reader: The raw reader of the connection, as returned by OnionTunnel::split
+    // param writer: The raw writer of the connection, as returned by OnionTunnel::split
+    // param peer_addr: The socket address of the other side of the connection
+    // param ciphers: The symmetric ciphers between the sender and receiver used in securing the tunnel
+    // param negotiation: The protocol version and cells negotiated between the sender and receiver
+    pub fn new(
+        reader: RawOnionReader<TunnelReader>,
+        writer: RawOnionWriter<TunnelWriter>,
+        peer_addr: SocketAddr,
+        ciphers: LinkCiphers,
+        negotiation: &Negotiation,
+    ) -> Self {
+        let (version, cells) = (negotiation.version, negotiation.cells);
         Self {
-            symmetric_cipher: symmetric_cipher.clone(),
-            peer_addr: stream.peer_addr().expect("Failed to retrieve peer address"),
+            peer_addr,
+            version,
             reader: Mutex::new(
-                RawOnionReader::new(stream.clone()).with_cipher(symmetric_cipher.clone()),
+                reader
+                    .with_version(version)
+                    .with_cells(cells)
+                    .with_cipher(ciphers.recv),
+            ),
+            writer: Mutex::new(
+                writer
+                    .with_version(version)
+                    .with_cells(cells)
+                    .with_cipher(ciphers.send),
             ),
-            writer: Mutex::new(RawOnionWriter::new(stream).with_cipher(symmetric_cipher)),
         }
     }
 
+    // Splits a connection into the raw reader and writer to make the handshake on
+    // param stream: The connection to split
+    // param max_frame_len: The largest frame to accept on the connection
+    pub fn split<S: AsyncRead + AsyncWrite + Send + 'static>(
+        stream: S,
+        max_frame_len: u32,
+    ) -> (RawOnionReader<TunnelReader>, RawOnionWriter<TunnelWriter>) {
+        let (reader, writer) = stream.split();
+        (
+            RawOnionReader::new(Box::new(reader) as TunnelReader).with_max_frame_len(max_frame_len),
+            RawOnionWriter::new(Box::new(writer) as TunnelWriter),
+        )
+    }
+
     // Returns the socket address of the other side of the tunnel
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer_addr
     }
 
+    // Returns the protocol version negotiated on the tunnel
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
     // Reads the connection for onions and returns the read onion
-    pub async fn recv_onion(&self) -> Onion {
-        self.reader
-            .lock()
-            .await
-            .read()
-            .await
-            .expect("Failed to read onion")
+    pub async fn recv_onion(&self) -> std::result::Result<Onion, ProtocolError> {
+        self.reader.lock().await.read().await
     }
 
     // Writes an onion on the connection
@@ -57,35 +101,25 @@ impl OnionTunnel {
         self.writer.lock().await.write(onion).await
     }
 
-    // Peels a layer of encryption from the given payload, returning a peeled onion
-    // param payload: The payload buffer to peel
-    // param symmetric_cipher: The symmetric cipher used to peel away the layer of encryption
-    pub async fn peel_layer(&self, payload: Vec<u8>, symmetric_cipher: Aes256) -> Result<Onion> {
-        let cursor = Cursor::new(payload);
-
-        Ok(RawOnionReader::new(cursor)
-            .with_cipher(symmetric_cipher)
-            .read()
-            .await?)
-    }
-
-    // Adds a layer of encryption on an onion, returning a byte buffer containing the encrypted onion
-    // param onion: The onion to add a layer of encryption on
-    pub async fn add_layer(&self, onion: Onion) -> Result<Vec<u8>> {
-        let mut payload_buf_cursor = Cursor::new(Vec::new());
-        RawOnionWriter::new(payload_buf_cursor.get_mut())
-            .with_cipher(self.symmetric_cipher.clone())
-            .write(onion)
-            .await?;
-
-        Ok(payload_buf_cursor.into_inner())
+    // Splits the tunnel into its reader and writer, so that separate tasks can own them
+    pub fn into_split(self) -> (TunnelOnionReader, TunnelOnionWriter) {
+        (self.reader.into_inner(), self.writer.into_inner())
     }
 
     // A static implementation used to directly create a secure onion tunnel between two relays
     // param stream: The connection to create the onion tunnel on
+    // param peer_addr: The socket address of the relay the connection goes to
     // param secret: The secret to use in the establishment of the tunnel
-    pub async fn reach_relay(stream: TcpStream, secret: ClientSecret) -> Result<OnionTunnel> {
-        let (reader, writer) = &mut (RawOnionReader::new(&stream), RawOnionWriter::new(&stream));
+    // param max_frame_len: The largest frame to accept on the tunnel
+    // param cells: Whether to ask for cells on the tunnel
+    pub async fn reach_relay<S: AsyncRead + AsyncWrite + Send + 'static>(
+        stream: S,
+        peer_addr: SocketAddr,
+        secret: ClientSecret,
+        max_frame_len: u32,
+        cells: bool,
+    ) -> Result<OnionTunnel> {
+        let (mut reader, mut writer) = Self::split(stream, max_frame_len);
 
         let pub_key = secret.public_key();
 
@@ -93,24 +127,43 @@ impl OnionTunnel {
             .write(Onion {
                 target: Target::Current,
                 circuit_id: None,
-                message: Message::HelloRequest(HelloRequest {
-                    client_type: ClientType::Relay,
-                    public_key: pub_key,
-                }),
+                message: Message::HelloRequest(
+                    HelloRequest::new(ClientType::Relay, pub_key).with_cells(cells),
+                ),
             })
             .await?;
 
         let hello_response = reader.read().await?;
 
-        let symmetric_cipher = if let Message::HelloResponse(peer_key) = hello_response.message {
-            secret
-                .symmetric_cipher(peer_key)
-                .expect("Failed to create symmetric cipher")
-        } else {
-            //err?
-            todo!()
-        };
+        let resp = Self::hello_response(hello_response.message)?;
+        let negotiation = Negotiation::confirm(&resp, cells)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Unoffered version or suite"))?;
+        let ciphers = secret
+            .symmetric_ciphers(resp.signed_public_key, &negotiation)
+            .expect("Failed to create symmetric cipher");
+
+        Ok(OnionTunnel::new(
+            reader,
+            writer,
+            peer_addr,
+            ciphers,
+            &negotiation,
+        ))
+    }
 
-        Ok(OnionTunnel::new(stream, symmetric_cipher))
+    // Unpacks the answer to a HelloRequest, turning a Close into an error with the peer's reason
+    // param message: The message received after sending the HelloRequest
+    pub fn hello_response(message: Message) -> Result<HelloResponse> {
+        match message {
+            Message::HelloResponse(resp) => Ok(resp),
+            Message::Close(reason) => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                reason.unwrap_or_else(|| "Handshake closed".to_string()),
+            )),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Expected Hello response",
+            )),
+        }
     }
 }
@@ -0,0 +1,256 @@
+use std::{
+    future::Future,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    path::Path,
+    pin::pin,
+    time::Duration,
+};
+
+use futures::future::{self, Either};
+
+#[cfg(not(any(feature = "async-std-runtime", feature = "tokio-runtime")))]
+compile_error!("core needs a runtime, enable the `async-std-runtime` or `tokio-runtime` feature");
+
+pub use backend::TcpStream;
+#[cfg(unix)]
+pub use backend::UnixStream;
+
+/// Listens for TCP connections on the selected runtime.
+pub struct TcpListener(backend::TcpListener);
+
+impl TcpListener {
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        Ok(Self(backend::TcpListener::bind(addr).await?))
+    }
+
+    /// Waits for the next connection, returning it with the address of its peer.
+    pub async fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
+        backend::accept(&self.0).await
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.0.local_addr()
+    }
+}
+
+/// Opens a TCP connection on the selected runtime.
+pub async fn connect(addr: SocketAddr) -> Result<TcpStream> {
+    backend::connect(addr).await
+}
+
+/// Looks up the addresses of a host name on the selected runtime.
+pub async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    backend::resolve(host, port).await
+}
+
+/// Listens for Unix domain socket connections on the selected runtime.
+#[cfg(unix)]
+pub struct UnixListener(backend::UnixListener);
+
+#[cfg(unix)]
+impl UnixListener {
+    pub async fn bind(path: &Path) -> Result<Self> {
+        Ok(Self(backend::bind_unix(path).await?))
+    }
+
+    pub async fn accept(&self) -> Result<UnixStream> {
+        backend::accept_unix(&self.0).await
+    }
+}
+
+/// Opens a Unix domain socket connection on the selected runtime.
+#[cfg(unix)]
+pub async fn connect_unix(path: &Path) -> Result<UnixStream> {
+    backend::connect_unix(path).await
+}
+
+/// Runs a future in the background on the selected runtime.
+pub fn spawn<F>(future: F)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    backend::spawn(future)
+}
+
+/// Runs a future to completion on the selected runtime, blocking the current thread.
+/// Must not be called from within the runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    backend::block_on(future)
+}
+
+/// Waits for the duration to pass without blocking the runtime.
+pub async fn sleep(duration: Duration) {
+    backend::sleep(duration).await
+}
+
+/// Runs blocking work, such as file I/O, on a thread where it doesn't hold up the runtime.
+pub async fn spawn_blocking<F, R>(work: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    backend::spawn_blocking(work).await
+}
+
+/// Waits for the future to complete, failing with TimedOut once the duration passed.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output> {
+    match future::select(pin!(future), pin!(sleep(duration))).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Error::from(ErrorKind::TimedOut)),
+    }
+}
+
+#[cfg(all(feature = "async-std-runtime", not(feature = "tokio-runtime")))]
+mod backend {
+    use std::{future::Future, io::Result, net::SocketAddr, path::Path, time::Duration};
+
+    pub use async_std::net::{TcpListener, TcpStream};
+    #[cfg(unix)]
+    pub use async_std::os::unix::net::{UnixListener, UnixStream};
+
+    pub async fn accept(listener: &TcpListener) -> Result<(TcpStream, SocketAddr)> {
+        listener.accept().await
+    }
+
+    pub async fn connect(addr: SocketAddr) -> Result<TcpStream> {
+        TcpStream::connect(addr).await
+    }
+
+    pub async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok(
+            async_std::net::ToSocketAddrs::to_socket_addrs(&(host, port))
+                .await?
+                .collect(),
+        )
+    }
+
+    #[cfg(unix)]
+    pub async fn bind_unix(path: &Path) -> Result<UnixListener> {
+        UnixListener::bind(path).await
+    }
+
+    #[cfg(unix)]
+    pub async fn accept_unix(listener: &UnixListener) -> Result<UnixStream> {
+        Ok(listener.accept().await?.0)
+    }
+
+    #[cfg(unix)]
+    pub async fn connect_unix(path: &Path) -> Result<UnixStream> {
+        UnixStream::connect(path).await
+    }
+
+    pub fn spawn<F>(future: F)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        async_std::task::spawn(future);
+    }
+
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        async_std::task::block_on(future)
+    }
+
+    pub async fn spawn_blocking<F, R>(work: F) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        // async-std only offers spawn_blocking as an unstable API, so the work gets a thread of its own.
+        let (done, result) = futures::channel::oneshot::channel();
+        std::thread::spawn(move || {
+            let _ = done.send(work());
+        });
+        result.await.expect("blocking task panicked")
+    }
+
+    pub async fn sleep(duration: Duration) {
+        async_std::task::sleep(duration).await
+    }
+}
+
+#[cfg(feature = "tokio-runtime")]
+mod backend {
+    use std::{
+        future::Future, io::Result, net::SocketAddr, path::Path, sync::OnceLock, time::Duration,
+    };
+
+    use tokio::runtime::{Handle, Runtime};
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    pub use tokio::net::TcpListener;
+
+    #[cfg(unix)]
+    pub use tokio::net::UnixListener;
+
+    /// A tokio TcpStream behind the futures I/O traits.
+    pub type TcpStream = Compat<tokio::net::TcpStream>;
+    /// A tokio UnixStream behind the futures I/O traits.
+    #[cfg(unix)]
+    pub type UnixStream = Compat<tokio::net::UnixStream>;
+
+    // The runtime used when the nodes are started from outside of tokio.
+    fn runtime() -> &'static Runtime {
+        static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+        RUNTIME.get_or_init(|| Runtime::new().expect("Failed to start tokio runtime"))
+    }
+
+    pub async fn accept(listener: &TcpListener) -> Result<(TcpStream, SocketAddr)> {
+        let (stream, peer_addr) = listener.accept().await?;
+        Ok((stream.compat(), peer_addr))
+    }
+
+    pub async fn connect(addr: SocketAddr) -> Result<TcpStream> {
+        Ok(tokio::net::TcpStream::connect(addr).await?.compat())
+    }
+
+    pub async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+
+    #[cfg(unix)]
+    pub async fn bind_unix(path: &Path) -> Result<UnixListener> {
+        UnixListener::bind(path)
+    }
+
+    #[cfg(unix)]
+    pub async fn accept_unix(listener: &UnixListener) -> Result<UnixStream> {
+        Ok(listener.accept().await?.0.compat())
+    }
+
+    #[cfg(unix)]
+    pub async fn connect_unix(path: &Path) -> Result<UnixStream> {
+        Ok(tokio::net::UnixStream::connect(path).await?.compat())
+    }
+
+    pub fn spawn<F>(future: F)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match Handle::try_current() {
+            Ok(handle) => handle.spawn(future),
+            Err(_) => runtime().spawn(future),
+        };
+    }
+
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        runtime().block_on(future)
+    }
+
+    pub async fn spawn_blocking<F, R>(work: F) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        tokio::task::spawn_blocking(work)
+            .await
+            .expect("blocking task panicked")
+    }
+
+    pub async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{atomic::AtomicU32, Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    io::{AsyncRead, AsyncWrite},
+    lock, StreamExt,
+};
+
+use super::{ephemeral_port, Listener, Transport};
+
+/// The most bytes a connection buffers in each direction before writes wait for reads.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Runs nodes in memory, so a whole network can run in one process without sockets.
+/// Clones of a transport share the same network.
+///
+/// A connection is identified by a loopback address of the IP version of the address
+/// it was made to, so nodes on the network should listen on loopback addresses.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    network: Arc<Network>,
+}
+
+#[derive(Default)]
+struct Network {
+    listeners: Mutex<HashMap<SocketAddr, UnboundedSender<(MemoryStream, SocketAddr)>>>,
+    ports: AtomicU32,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Stream = MemoryStream;
+    type Listener = MemoryListener;
+
+    async fn listen(&self, mut addr: SocketAddr) -> Result<MemoryListener> {
+        let mut listeners = self.network.listeners.lock().unwrap();
+        if addr.port() == 0 {
+            addr.set_port(ephemeral_port(&self.network.ports));
+            while listeners.contains_key(&addr) {
+                addr.set_port(ephemeral_port(&self.network.ports));
+            }
+        } else if listeners.contains_key(&addr) {
+            return Err(Error::from(ErrorKind::AddrInUse));
+        }
+
+        let (sender, receiver) = mpsc::unbounded();
+        listeners.insert(addr, sender);
+
+        Ok(MemoryListener {
+            addr,
+            incoming: lock::Mutex::new(receiver),
+            network: self.network.clone(),
+        })
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> Result<MemoryStream> {
+        let listener = self.network.listeners.lock().unwrap().get(&addr).cloned();
+        let listener = listener.ok_or_else(|| Error::from(ErrorKind::ConnectionRefused))?;
+
+        let loopback = match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        };
+        let peer_addr = SocketAddr::new(loopback, ephemeral_port(&self.network.ports));
+
+        let (stream, peer_stream) = MemoryStream::pair();
+        listener
+            .unbounded_send((peer_stream, peer_addr))
+            .map_err(|_| Error::from(ErrorKind::ConnectionRefused))?;
+
+        Ok(stream)
+    }
+}
+
+pub struct MemoryListener {
+    addr: SocketAddr,
+    incoming: lock::Mutex<UnboundedReceiver<(MemoryStream, SocketAddr)>>,
+    network: Arc<Network>,
+}
+
+impl Listener for MemoryListener {
+    type Stream = MemoryStream;
+
+    async fn accept(&self) -> Result<(MemoryStream, SocketAddr)> {
+        let mut incoming = self.incoming.lock().await;
+        incoming
+            .next()
+            .await
+            .ok_or_else(|| Error::from(ErrorKind::NotConnected))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.network.listeners.lock().unwrap().remove(&self.addr);
+    }
+}
+
+// One direction of a connection.
+#[derive(Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    closed: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl Pipe {
+    fn close(&mut self) {
+        self.closed = true;
+        wake(&mut self.reader);
+        wake(&mut self.writer);
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+/// One end of an in-memory duplex connection. Dropping or closing an end ends the
+/// stream of the other end.
+pub struct MemoryStream {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+impl MemoryStream {
+    /// Returns both ends of a new connection.
+    pub fn pair() -> (Self, Self) {
+        let (a, b) = (Arc::<Mutex<Pipe>>::default(), Arc::<Mutex<Pipe>>::default());
+        (
+            Self {
+                read: a.clone(),
+                write: b.clone(),
+            },
+            Self { read: b, write: a },
+        )
+    }
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        let mut pipe = self.read.lock().unwrap();
+        if pipe.buf.is_empty() {
+            if pipe.closed {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = buf.len().min(pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..len)) {
+            *dst = src;
+        }
+        wake(&mut pipe.writer);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let mut pipe = self.write.lock().unwrap();
+        if pipe.closed {
+            return Poll::Ready(Err(Error::from(ErrorKind::BrokenPipe)));
+        }
+        let free = PIPE_CAPACITY - pipe.buf.len();
+        if free == 0 {
+            pipe.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = buf.len().min(free);
+        pipe.buf.extend(&buf[..len]);
+        wake(&mut pipe.reader);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.write.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.write.lock().unwrap().close();
+        self.read.lock().unwrap().close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn localhost(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+    }
+
+    #[async_std::test]
+    async fn memory_connection_roundtrip() {
+        let transport = MemoryTransport::new();
+        let listener = transport.listen(localhost(9001)).await.unwrap();
+
+        let mut client = transport.connect(localhost(9001)).await.unwrap();
+        let (mut server, peer_addr) = listener.accept().await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        server.write_all(b"world").await.unwrap();
+
+        let (mut from_client, mut from_server) = ([0u8; 5], [0u8; 5]);
+        server.read_exact(&mut from_client).await.unwrap();
+        client.read_exact(&mut from_server).await.unwrap();
+        assert_eq!(&from_client, b"hello");
+        assert_eq!(&from_server, b"world");
+        assert_eq!(peer_addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    #[async_std::test]
+    async fn memory_connect_without_listener_is_refused() {
+        let transport = MemoryTransport::new();
+        drop(transport.listen(localhost(9001)).await.unwrap());
+
+        let err = transport.connect(localhost(9001)).await.err().unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[async_std::test]
+    async fn memory_listen_twice_is_refused() {
+        let transport = MemoryTransport::new();
+        let _listener = transport.listen(localhost(9001)).await.unwrap();
+
+        let err = transport
+            .clone()
+            .listen(localhost(9001))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+    }
+
+    #[async_std::test]
+    async fn memory_listen_on_port_zero_picks_port() {
+        let transport = MemoryTransport::new();
+        let listener = transport.listen(localhost(0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        assert_ne!(addr.port(), 0);
+        transport.connect(addr).await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn memory_dropped_stream_ends_peer() {
+        let (mut stream, peer_stream) = MemoryStream::pair();
+        drop(peer_stream);
+
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        let err = stream.write_all(&buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[async_std::test]
+    async fn memory_write_larger_than_pipe() {
+        let (mut stream, mut peer_stream) = MemoryStream::pair();
+        let data: Vec<u8> = (0..PIPE_CAPACITY * 3).map(|i| i as u8).collect();
+
+        let (written, read) = futures::join!(stream.write_all(&data), async {
+            let mut read = vec![0u8; data.len()];
+            peer_stream.read_exact(&mut read).await.map(|_| read)
+        });
+
+        written.unwrap();
+        assert_eq!(read.unwrap(), data);
+    }
+}
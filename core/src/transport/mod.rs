@@ -0,0 +1,52 @@
+use futures::io::{AsyncRead, AsyncWrite};
+use std::{
+    future::Future,
+    io::Result,
+    net::SocketAddr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+mod memory;
+mod tcp;
+#[cfg(unix)]
+mod unix;
+
+pub use memory::{MemoryListener, MemoryStream, MemoryTransport};
+pub use tcp::TcpTransport;
+#[cfg(unix)]
+pub use unix::{UnixListener, UnixTransport};
+
+/// The way nodes reach each other. Nodes are addressed by socket addresses on every
+/// transport, since that is how the index node lists relays; transports that don't run
+/// over IP map the addresses onto their own endpoints.
+pub trait Transport: Clone + Send + Sync + 'static {
+    /// A duplex connection between two nodes.
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+    type Listener: Listener<Stream = Self::Stream>;
+
+    /// Starts listening for connections to the address.
+    fn listen(&self, addr: SocketAddr) -> impl Future<Output = Result<Self::Listener>> + Send;
+
+    /// Opens a connection to the node listening on the address.
+    fn connect(&self, addr: SocketAddr) -> impl Future<Output = Result<Self::Stream>> + Send;
+}
+
+pub trait Listener: Send + Sync + 'static {
+    type Stream;
+
+    /// Waits for the next connection, returning it with the address identifying its peer.
+    fn accept(&self) -> impl Future<Output = Result<(Self::Stream, SocketAddr)>> + Send;
+
+    /// Returns the address the listener listens on, with the port it was given if it
+    /// was asked to listen on port 0.
+    fn local_addr(&self) -> Result<SocketAddr>;
+}
+
+const EPHEMERAL_PORTS_START: u16 = 49152;
+
+// Hands out ports from the ephemeral range in turn, for identifying connections on
+// transports without ports of their own.
+fn ephemeral_port(counter: &AtomicU32) -> u16 {
+    let ephemeral_ports = (u16::MAX - EPHEMERAL_PORTS_START) as u32 + 1;
+    EPHEMERAL_PORTS_START + (counter.fetch_add(1, Ordering::Relaxed) % ephemeral_ports) as u16
+}
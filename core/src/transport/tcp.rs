@@ -0,0 +1,33 @@
+use std::{io::Result, net::SocketAddr};
+
+use super::{Listener, Transport};
+use crate::runtime::{self, TcpListener, TcpStream};
+
+/// Runs nodes over TCP on the selected runtime.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    type Stream = TcpStream;
+    type Listener = TcpListener;
+
+    async fn listen(&self, addr: SocketAddr) -> Result<TcpListener> {
+        TcpListener::bind(addr).await
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> Result<TcpStream> {
+        runtime::connect(addr).await
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
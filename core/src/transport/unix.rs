@@ -0,0 +1,125 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::atomic::AtomicU32,
+};
+
+use super::{ephemeral_port, Listener, Transport};
+use crate::runtime::{self, UnixStream};
+
+/// Runs nodes over Unix domain sockets in a directory, so nodes on one host can reach
+/// each other without taking up ports. The socket of a node is named after the address
+/// it listens on.
+///
+/// Connections over Unix domain sockets have no address of the connecting node, so an
+/// accepted connection is identified by the IP of the listener and a port from the
+/// ephemeral range. Nodes sharing a directory should therefore listen on the same IP.
+#[derive(Clone, Debug)]
+pub struct UnixTransport {
+    dir: PathBuf,
+}
+
+impl UnixTransport {
+    /// Creates a transport with the sockets of its nodes in the directory.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn socket_path(&self, addr: SocketAddr) -> PathBuf {
+        self.dir.join(format!("{}_{}.sock", addr.ip(), addr.port()))
+    }
+}
+
+impl Transport for UnixTransport {
+    type Stream = UnixStream;
+    type Listener = UnixListener;
+
+    async fn listen(&self, addr: SocketAddr) -> Result<UnixListener> {
+        if addr.port() == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Unix transport can't pick a port",
+            ));
+        }
+
+        let path = self.socket_path(addr);
+        // A socket left behind by a node that didn't shut down keeps the path taken.
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+        let listener = runtime::UnixListener::bind(&path).await?;
+
+        Ok(UnixListener {
+            listener,
+            addr,
+            path,
+            accepted: AtomicU32::new(0),
+        })
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> Result<UnixStream> {
+        runtime::connect_unix(&self.socket_path(addr)).await
+    }
+}
+
+pub struct UnixListener {
+    listener: runtime::UnixListener,
+    addr: SocketAddr,
+    path: PathBuf,
+    accepted: AtomicU32,
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept(&self) -> Result<(UnixStream, SocketAddr)> {
+        let stream = self.listener.accept().await?;
+        let peer_addr = SocketAddr::new(self.addr.ip(), ephemeral_port(&self.accepted));
+        Ok((stream, peer_addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    // Unix sockets need the reactor of the selected runtime.
+    #[test]
+    fn unix_connection_roundtrip() {
+        runtime::block_on(async {
+            let dir = std::env::temp_dir().join(format!("ronion-unix-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let transport = UnixTransport::new(&dir);
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9001);
+            let listener = transport.listen(addr).await.unwrap();
+
+            let mut client = transport.connect(addr).await.unwrap();
+            let (mut server, peer_addr) = listener.accept().await.unwrap();
+            client.write_all(b"hello").await.unwrap();
+
+            let mut from_client = [0u8; 5];
+            server.read_exact(&mut from_client).await.unwrap();
+            assert_eq!(&from_client, b"hello");
+            assert_eq!(peer_addr.ip(), addr.ip());
+
+            drop(listener);
+            assert!(!transport.socket_path(addr).exists());
+            fs::remove_dir(&dir).unwrap();
+        })
+    }
+}
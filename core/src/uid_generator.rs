@@ -30,10 +30,49 @@ impl UIDGenerator {
         }
     }
 
+    // Marks the given identifier as taken, so it is not generated until it is cleared
+    // param index: The index to take (ID to reserve)
+    pub fn take_uid(&mut self, index: u32) {
+        let index = index as usize;
+        if index >= self.ids.len() {
+            self.ids.resize(index + GROWTH, false);
+        }
+        self.ids[index] = true;
+    }
+
     // Clears the given unique identified from the generator, freeing it up for future use
     // param index: The index to clear (ID to free up)
     pub fn clear_uid(&mut self, index: u32) {
-        let mut val = self.ids.get_mut(index as usize);
-        val = Some(&mut false);
+        if let Some(val) = self.ids.get_mut(index as usize) {
+            *val = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cleared_uid_is_reused() {
+        let mut generator = UIDGenerator::new(2);
+        let first = generator.get_uid();
+        let second = generator.get_uid();
+
+        generator.clear_uid(first);
+
+        assert_eq!(generator.get_uid(), first);
+        assert_ne!(generator.get_uid(), second);
+    }
+
+    #[test]
+    fn taken_uid_is_skipped() {
+        let mut generator = UIDGenerator::new(2);
+        generator.take_uid(0);
+        generator.take_uid(7);
+
+        let generated: Vec<u32> = (0..7).map(|_| generator.get_uid()).collect();
+
+        assert_eq!(generated, vec![1, 2, 3, 4, 5, 6, 8]);
     }
 }